@@ -0,0 +1,226 @@
+use crate::rng::XorShift32;
+use crate::sample_rate;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+pub const MAX_ARP_OCTAVES: u8 = 4;
+// fraction of a step each note sounds for
+const ARP_GATE: f32 = 0.5;
+
+// Order the held notes are played in
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ArpMode {
+    Up,
+    Down,
+    // up and back down, the top and bottom notes played once
+    UpDown,
+    Random,
+}
+
+impl FromStr for ArpMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "up" => Ok(ArpMode::Up),
+            "down" => Ok(ArpMode::Down),
+            "updown" => Ok(ArpMode::UpDown),
+            "random" => Ok(ArpMode::Random),
+            _ => Err(format!("unknown arpeggiator mode {:?}", s)),
+        }
+    }
+}
+
+// Length of an arpeggiator step
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ArpRate {
+    Quarter,
+    Eighth,
+    Sixteenth,
+}
+
+impl ArpRate {
+    // length of a step in quarter-note beats
+    pub fn beats(self) -> f32 {
+        match self {
+            ArpRate::Quarter => 1.0,
+            ArpRate::Eighth => 0.5,
+            ArpRate::Sixteenth => 0.25,
+        }
+    }
+}
+
+impl FromStr for ArpRate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "1/4" => Ok(ArpRate::Quarter),
+            "1/8" => Ok(ArpRate::Eighth),
+            "1/16" => Ok(ArpRate::Sixteenth),
+            _ => Err(format!("unknown arpeggiator rate {:?}", s)),
+        }
+    }
+}
+
+// A (channel, note) the arpeggiator lets go of, and a (channel, note, velocity) it starts
+pub(crate) type ArpEvents = (Option<(u8, u8)>, Option<(u8, u8, u8)>);
+
+// Plays the keys held down one at a time, in a pattern, at a rate locked to the tempo. It
+// only keeps track of the keys and time, the synth plays the notes it hands out, advancing it
+// at control rate with the tempo of its clock.
+#[derive(Debug, Clone)]
+pub struct Arpeggiator {
+    pub mode: ArpMode,
+    pub rate: ArpRate,
+    // octaves the pattern spans, the held notes repeated an octave up for each one past 1
+    pub octaves: u8,
+    // keys physically down, in the order they were pressed, as (channel, note, velocity)
+    held: Vec<(u8, u8, u8)>,
+    step: usize,
+    // samples until the next step, and until the sounding note is let go
+    to_next: f32,
+    to_release: Option<f32>,
+    sounding: Option<(u8, u8)>,
+}
+
+impl Default for Arpeggiator {
+    fn default() -> Self {
+        Self::new(ArpMode::Up, ArpRate::Eighth, 1)
+    }
+}
+
+// Parses `<mode>,<rate>[,<octaves>]`, e.g. `up,1/16` or `updown,1/8,2`
+impl FromStr for Arpeggiator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let bad = || format!("bad arpeggiator {:?}", s);
+        let fields: Vec<&str> = s.split(',').map(str::trim).collect();
+        let (mode, rate, octaves) = match fields[..] {
+            [mode, rate] => (mode, rate, None),
+            [mode, rate, octaves] => (mode, rate, Some(octaves)),
+            _ => return Err(bad()),
+        };
+        let octaves = match octaves {
+            Some(octaves) => octaves.parse().map_err(|_| bad())?,
+            None => 1,
+        };
+        if !(1..=MAX_ARP_OCTAVES).contains(&octaves) {
+            return Err(format!(
+                "arpeggiator spans 1 to {} octaves, not {}",
+                MAX_ARP_OCTAVES, octaves
+            ));
+        }
+        Ok(Arpeggiator::new(mode.parse()?, rate.parse()?, octaves))
+    }
+}
+
+impl Arpeggiator {
+    pub fn new(mode: ArpMode, rate: ArpRate, octaves: u8) -> Self {
+        Self {
+            mode,
+            rate,
+            octaves: octaves.clamp(1, MAX_ARP_OCTAVES),
+            held: Vec::new(),
+            step: 0,
+            to_next: 0.0,
+            to_release: None,
+            sounding: None,
+        }
+    }
+
+    // A key went down. The first key of a new chord starts the pattern at once.
+    pub fn press(&mut self, channel: u8, note: u8, velocity: u8) {
+        if self.held.is_empty() {
+            self.restart();
+        }
+        self.held.retain(|held| (held.0, held.1) != (channel, note));
+        self.held.push((channel, note, velocity));
+    }
+
+    // A key came up, true if it was one of the arpeggiator's
+    pub fn release(&mut self, channel: u8, note: u8) -> bool {
+        let before = self.held.len();
+        self.held.retain(|held| (held.0, held.1) != (channel, note));
+        self.held.len() != before
+    }
+
+    // Forget the held keys, returns the note still sounding for the synth to let go of
+    pub(crate) fn clear(&mut self) -> Option<(u8, u8)> {
+        self.held.clear();
+        self.to_release = None;
+        self.sounding.take()
+    }
+
+    // Back to the start of the pattern, the next step plays straight away (MIDI start)
+    pub fn restart(&mut self) {
+        self.step = 0;
+        self.to_next = 0.0;
+    }
+
+    // Move on by `samples` at `bpm`, returning the note to let go of and the note to start, if
+    // any. Random mode draws from `rng`.
+    pub(crate) fn advance(&mut self, samples: usize, bpm: f32, rng: &mut XorShift32) -> ArpEvents {
+        let mut off = None;
+        if let Some(to_release) = self.to_release.as_mut() {
+            *to_release -= samples as f32;
+            if *to_release <= 0.0 {
+                self.to_release = None;
+                off = self.sounding.take();
+            }
+        }
+        if self.held.is_empty() {
+            return (off.or(self.sounding.take()), None);
+        }
+
+        self.to_next -= samples as f32;
+        if self.to_next > 0.0 {
+            return (off, None);
+        }
+        let step_samples = self.rate.beats() * 60.0 / bpm.max(1.0) * sample_rate() as f32;
+        // a late tick doesn't push the following steps back
+        self.to_next = (self.to_next + step_samples).max(0.0);
+
+        let (channel, note, velocity) = self.next_note(rng);
+        // a gate longer than the tick can leave the last note sounding
+        if let Some(sounding) = self.sounding.take() {
+            off = Some(sounding);
+        }
+        self.sounding = Some((channel, note));
+        self.to_release = Some(step_samples * ARP_GATE);
+        (off, Some((channel, note, velocity)))
+    }
+
+    // The note at the current step of the pattern, moving on a step
+    fn next_note(&mut self, rng: &mut XorShift32) -> (u8, u8, u8) {
+        let mut chord = self.held.clone();
+        chord.sort_by_key(|held| held.1);
+        let pattern: Vec<(u8, u8, u8)> = (0..self.octaves)
+            .flat_map(|octave| {
+                chord.iter().filter_map(move |&(channel, note, velocity)| {
+                    let note = note.checked_add(12 * octave).filter(|&note| note <= 127)?;
+                    Some((channel, note, velocity))
+                })
+            })
+            .collect();
+        let len = pattern.len();
+        let index = match self.mode {
+            ArpMode::Up => self.step % len,
+            ArpMode::Down => len - 1 - self.step % len,
+            ArpMode::UpDown if len > 1 => {
+                let cycle = 2 * len - 2;
+                let position = self.step % cycle;
+                if position < len {
+                    position
+                } else {
+                    cycle - position
+                }
+            }
+            ArpMode::UpDown => 0,
+            ArpMode::Random => rng.next_u32() as usize % len,
+        };
+        self.step += 1;
+        pattern[index]
+    }
+}
@@ -0,0 +1,137 @@
+use crate::sample_rate;
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+pub const DEFAULT_CHORUS_RATE_HZ: f32 = 0.8;
+pub const DEFAULT_CHORUS_DEPTH_MS: f32 = 4.0;
+pub const DEFAULT_CHORUS_VOICES: usize = 2;
+pub const MAX_CHORUS_VOICES: usize = 3;
+// mix when only rate and depth are given, and when the panel switches the chorus on
+pub const DEFAULT_CHORUS_MIX: f32 = 0.5;
+// the delay lines swing around this, so the depth reaches 5 to 25 ms
+const CHORUS_CENTER_MS: f32 = 15.0;
+pub const MAX_CHORUS_DEPTH_MS: f32 = 10.0;
+
+// Stereo chorus for the master mix: up to three short delay lines whose times are swept by a
+// slow sine, mixed back with the dry signal. The lines are spread evenly around the sweep and
+// the right side runs a quarter cycle behind the left, so even one line widens the sound.
+#[derive(Debug, Clone)]
+pub struct Chorus {
+    left: Vec<f32>,
+    right: Vec<f32>,
+    pos: usize,
+    phase: f32,
+    pub rate_hz: f32,
+    // how far the delay times swing either side of the centre, in ms
+    pub depth_ms: f32,
+    voices: usize,
+    // 0 is dry only, 1 is wet only
+    pub mix: f32,
+}
+
+// Off (all dry) until the mix is turned up
+impl Default for Chorus {
+    fn default() -> Self {
+        Self::new(
+            DEFAULT_CHORUS_RATE_HZ,
+            DEFAULT_CHORUS_DEPTH_MS,
+            DEFAULT_CHORUS_VOICES,
+            0.0,
+        )
+    }
+}
+
+impl Chorus {
+    pub fn new(rate_hz: f32, depth_ms: f32, voices: usize, mix: f32) -> Self {
+        // room for the longest sweep and the interpolation's extra sample
+        let ms = CHORUS_CENTER_MS + MAX_CHORUS_DEPTH_MS;
+        let len = (ms * sample_rate() as f32 / 1000.0) as usize + 2;
+        let mut chorus = Self {
+            left: vec![0.0; len],
+            right: vec![0.0; len],
+            pos: 0,
+            phase: 0.0,
+            rate_hz,
+            depth_ms,
+            voices: 1,
+            mix,
+        };
+        chorus.set_voices(voices);
+        chorus
+    }
+
+    // Number of delay lines, clamped to 1..=MAX_CHORUS_VOICES
+    pub fn set_voices(&mut self, voices: usize) {
+        self.voices = voices.clamp(1, MAX_CHORUS_VOICES);
+    }
+
+    pub fn voices(&self) -> usize {
+        self.voices
+    }
+
+    pub fn process_stereo(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let len = self.left.len();
+        self.left[self.pos] = left;
+        self.right[self.pos] = right;
+
+        let ms_to_samples = sample_rate() as f32 / 1000.0;
+        let depth = self.depth_ms.clamp(0.0, MAX_CHORUS_DEPTH_MS);
+        let (mut left_wet, mut right_wet) = (0.0, 0.0);
+        for voice in 0..self.voices {
+            let phase = self.phase + voice as f32 / self.voices as f32;
+            for (line, offset, wet) in [
+                (&self.left, 0.0, &mut left_wet),
+                (&self.right, 0.25, &mut right_wet),
+            ] {
+                let sweep = (2.0 * PI * (phase + offset)).sin();
+                let delay = (CHORUS_CENTER_MS + depth * sweep) * ms_to_samples;
+                // linear interpolation, a swept delay read at whole samples crackles
+                let back = delay.floor();
+                let frac = delay - back;
+                let newer = (self.pos + len - back as usize) % len;
+                let older = (newer + len - 1) % len;
+                *wet += line[newer] + (line[older] - line[newer]) * frac;
+            }
+        }
+        let scale = 1.0 / self.voices as f32;
+
+        self.pos = (self.pos + 1) % len;
+        self.phase = (self.phase + self.rate_hz / sample_rate() as f32).fract();
+
+        let dry = 1.0 - self.mix;
+        (
+            left * dry + left_wet * scale * self.mix,
+            right * dry + right_wet * scale * self.mix,
+        )
+    }
+}
+
+// Parses `rate_hz,depth_ms[,voices[,mix]]`, e.g. `0.8,4` or `0.5,6,3,0.4`
+impl FromStr for Chorus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let bad = || format!("bad chorus settings {:?}", s);
+        let fields: Vec<&str> = s.split(',').map(str::trim).collect();
+        let (rate, depth, voices, mix) = match fields[..] {
+            [rate, depth] => (rate, depth, None, None),
+            [rate, depth, voices] => (rate, depth, Some(voices), None),
+            [rate, depth, voices, mix] => (rate, depth, Some(voices), Some(mix)),
+            _ => return Err(bad()),
+        };
+        let voices = match voices {
+            Some(voices) => voices.parse().map_err(|_| bad())?,
+            None => DEFAULT_CHORUS_VOICES,
+        };
+        let mix = match mix {
+            Some(mix) => mix.parse().map_err(|_| bad())?,
+            None => DEFAULT_CHORUS_MIX,
+        };
+        Ok(Self::new(
+            rate.parse().map_err(|_| bad())?,
+            depth.parse().map_err(|_| bad())?,
+            voices,
+            mix,
+        ))
+    }
+}
@@ -0,0 +1,85 @@
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_TEMPO_BPM: f32 = 120.0;
+// MIDI clock ticks per quarter note
+const CLOCK_PPQ: f32 = 24.0;
+// no clock for this long and the internal tempo takes over again
+const CLOCK_TIMEOUT: Duration = Duration::from_millis(500);
+// fraction of the way each clock tick moves the measured tick length, evens out MIDI jitter
+const CLOCK_SMOOTHING: f32 = 0.1;
+
+// Tempo the arpeggiator and synced LFOs run at: the incoming MIDI clock's while it is
+// running, the internal `bpm` otherwise. A clock that stops (MIDI stop, or no ticks for a
+// while) hands back to the internal tempo.
+#[derive(Debug, Clone, Copy)]
+pub struct MidiClock {
+    pub bpm: f32,
+    last_tick: Option<Instant>,
+    // smoothed time between ticks, in seconds
+    tick_secs: Option<f32>,
+}
+
+impl Default for MidiClock {
+    fn default() -> Self {
+        Self::new(DEFAULT_TEMPO_BPM)
+    }
+}
+
+impl MidiClock {
+    pub fn new(bpm: f32) -> Self {
+        Self {
+            bpm,
+            last_tick: None,
+            tick_secs: None,
+        }
+    }
+
+    // One clock tick (0xF8)
+    pub fn tick(&mut self) {
+        let now = Instant::now();
+        if let Some(last) = self.last_tick {
+            let tick = now.duration_since(last);
+            if tick < CLOCK_TIMEOUT {
+                let tick = tick.as_secs_f32();
+                self.tick_secs = Some(match self.tick_secs {
+                    Some(smoothed) => smoothed + (tick - smoothed) * CLOCK_SMOOTHING,
+                    None => tick,
+                });
+            }
+        }
+        self.last_tick = Some(now);
+    }
+
+    // Transport stopped (0xFC): back to the internal tempo straight away
+    pub fn stop(&mut self) {
+        self.last_tick = None;
+        self.tick_secs = None;
+    }
+
+    // Whether MIDI clock is coming in
+    pub fn running(&self) -> bool {
+        self.last_tick
+            .is_some_and(|last| last.elapsed() < CLOCK_TIMEOUT)
+    }
+
+    // Beats per minute to run at now
+    pub fn tempo(&self) -> f32 {
+        match self.tick_secs {
+            Some(tick) if self.running() && tick > 0.0 => 60.0 / (tick * CLOCK_PPQ),
+            _ => self.bpm,
+        }
+    }
+}
+
+// Length in quarter-note beats of a note value written as a fraction of a whole note, e.g.
+// `1/4`, `1/16` or `2/1` (two bars)
+pub fn parse_note_value(s: &str) -> Result<f32, String> {
+    let bad = || format!("bad note value {:?}", s);
+    let (num, den) = s.split_once('/').ok_or_else(bad)?;
+    let num: f32 = num.trim().parse().map_err(|_| bad())?;
+    let den: f32 = den.trim().parse().map_err(|_| bad())?;
+    if num <= 0.0 || den <= 0.0 {
+        return Err(bad());
+    }
+    Ok(4.0 * num / den)
+}
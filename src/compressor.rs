@@ -0,0 +1,139 @@
+use crate::follower::EnvelopeFollower;
+use std::str::FromStr;
+
+pub const DEFAULT_COMPRESSOR_ATTACK_MS: f32 = 5.0;
+pub const DEFAULT_COMPRESSOR_RELEASE_MS: f32 = 100.0;
+
+// Feed-forward compressor: the level of the input is tracked with attack/release smoothing and
+// everything above the threshold is scaled down by the ratio. A ratio of 1 leaves the signal
+// untouched, which is the default.
+#[derive(Debug, Clone, Copy)]
+pub struct Compressor {
+    pub threshold_db: f32,
+    pub ratio: f32,
+    attack_ms: f32,
+    release_ms: f32,
+    detector: EnvelopeFollower,
+    gain_reduction_db: f32,
+}
+
+impl Default for Compressor {
+    fn default() -> Self {
+        Self::new(
+            0.0,
+            1.0,
+            DEFAULT_COMPRESSOR_ATTACK_MS,
+            DEFAULT_COMPRESSOR_RELEASE_MS,
+        )
+    }
+}
+
+impl Compressor {
+    pub fn new(threshold_db: f32, ratio: f32, attack_ms: f32, release_ms: f32) -> Self {
+        Self {
+            threshold_db,
+            ratio,
+            attack_ms,
+            release_ms,
+            detector: EnvelopeFollower::new(attack_ms, release_ms),
+            gain_reduction_db: 0.0,
+        }
+    }
+
+    pub fn set_times(&mut self, attack_ms: f32, release_ms: f32) {
+        self.attack_ms = attack_ms;
+        self.release_ms = release_ms;
+        self.detector.set_times(attack_ms, release_ms);
+    }
+
+    // (attack, release) in ms
+    pub fn times(&self) -> (f32, f32) {
+        (self.attack_ms, self.release_ms)
+    }
+
+    // how far the last sample was turned down, in dB (0 or positive)
+    pub fn gain_reduction_db(&self) -> f32 {
+        self.gain_reduction_db
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        sample * self.gain(sample)
+    }
+
+    // Both channels are turned down together, by the louder of the two, so the stereo image
+    // doesn't shift
+    pub fn process_stereo(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let gain = self.gain(left.abs().max(right.abs()));
+        (left * gain, right * gain)
+    }
+
+    // gain for the next sample given its (peak) level
+    fn gain(&mut self, peak: f32) -> f32 {
+        let level = self.detector.process(peak);
+        if self.ratio <= 1.0 || level <= 0.0 {
+            self.gain_reduction_db = 0.0;
+            return 1.0;
+        }
+        let over_db = 20.0 * level.log10() - self.threshold_db;
+        self.gain_reduction_db = over_db.max(0.0) * (1.0 - 1.0 / self.ratio);
+        10f32.powf(-self.gain_reduction_db / 20.0)
+    }
+}
+
+// Parses `<threshold_db>,<ratio>[,<attack_ms>,<release_ms>]`, e.g. `-18,4` or `-18,4,5,100`
+impl FromStr for Compressor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let values = s
+            .split(',')
+            .map(|value| value.trim().parse::<f32>())
+            .collect::<Result<Vec<f32>, _>>()
+            .map_err(|_| format!("bad compressor settings {:?}", s))?;
+        match values[..] {
+            [threshold_db, ratio] => Ok(Self {
+                threshold_db,
+                ratio,
+                ..Self::default()
+            }),
+            [threshold_db, ratio, attack_ms, release_ms] => {
+                Ok(Self::new(threshold_db, ratio, attack_ms, release_ms))
+            }
+            _ => Err(format!("bad compressor settings {:?}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Output level in dB of a steady input at `input_db`, once the detector has settled
+    fn output_db(compressor: &mut Compressor, input_db: f32) -> f32 {
+        let input = 10f32.powf(input_db / 20.0);
+        let mut output = 0.0;
+        for _ in 0..crate::sample_rate() {
+            output = compressor.process(input);
+        }
+        20.0 * output.log10()
+    }
+
+    #[test]
+    fn turns_down_by_the_ratio_above_the_threshold() {
+        let mut compressor = Compressor::new(-20.0, 4.0, 1.0, 10.0);
+        // under the threshold nothing happens
+        assert!((output_db(&mut compressor, -30.0) + 30.0).abs() < 0.01);
+        assert_eq!(compressor.gain_reduction_db(), 0.0);
+        // over it, every 4 dB in comes out as 1 dB
+        for input_db in [-16.0, -8.0, 0.0] {
+            let expected = -20.0 + (input_db + 20.0) / 4.0;
+            let output = output_db(&mut compressor, input_db);
+            assert!(
+                (output - expected).abs() < 0.05,
+                "{} dB in came out at {} dB",
+                input_db,
+                output
+            );
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use std::str::FromStr;
+
+pub const MAX_CRUSHER_BITS: u8 = 16;
+pub const DEFAULT_CRUSHER_BITS: u8 = 8;
+pub const DEFAULT_CRUSHER_DOWNSAMPLE: usize = 4;
+
+// Lo-fi bitcrusher for the master mix: every `downsample` samples one is taken and held, as if
+// the mix were sampled at a fraction of the rate without a filter in front, and each is
+// quantized to `bits` bits across -1..1. Off by default, keeping its settings for when it is
+// switched on.
+#[derive(Debug, Clone, Copy)]
+pub struct Crusher {
+    pub on: bool,
+    bits: u8,
+    // samples each taken one is held for, 1 keeps the full rate
+    pub downsample: usize,
+    held: (f32, f32),
+    // samples the held one has left
+    hold: usize,
+}
+
+impl Default for Crusher {
+    fn default() -> Self {
+        let mut crusher = Self::new(DEFAULT_CRUSHER_BITS, DEFAULT_CRUSHER_DOWNSAMPLE);
+        crusher.on = false;
+        crusher
+    }
+}
+
+impl Crusher {
+    pub fn new(bits: u8, downsample: usize) -> Self {
+        let mut crusher = Self {
+            on: true,
+            bits: MAX_CRUSHER_BITS,
+            downsample,
+            held: (0.0, 0.0),
+            hold: 0,
+        };
+        crusher.set_bits(bits);
+        crusher
+    }
+
+    // Resolution of the output, clamped to 1..=MAX_CRUSHER_BITS
+    pub fn set_bits(&mut self, bits: u8) {
+        self.bits = bits.clamp(1, MAX_CRUSHER_BITS);
+    }
+
+    pub fn bits(&self) -> u8 {
+        self.bits
+    }
+
+    pub fn process_stereo(&mut self, left: f32, right: f32) -> (f32, f32) {
+        if !self.on {
+            return (left, right);
+        }
+        if self.hold == 0 {
+            self.held = (self.quantize(left), self.quantize(right));
+            self.hold = self.downsample.max(1);
+        }
+        self.hold -= 1;
+        self.held
+    }
+
+    // Nearest of the steps `bits` bits give between -1 and 1. A single bit is just -1, 0 and 1.
+    fn quantize(&self, sample: f32) -> f32 {
+        let steps = (1u32 << (self.bits - 1)) as f32;
+        ((sample * steps).round() / steps).clamp(-1.0, 1.0)
+    }
+}
+
+// Parses `bits[,downsample]`, e.g. `6` or `8,4`, the downsample defaulting to 1 (full rate)
+impl FromStr for Crusher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let bad = || format!("bad bitcrusher {:?}", s);
+        let fields: Vec<&str> = s.split(',').map(str::trim).collect();
+        let (bits, downsample) = match fields[..] {
+            [bits] => (bits, None),
+            [bits, downsample] => (bits, Some(downsample)),
+            _ => return Err(bad()),
+        };
+        let bits: u8 = bits.parse().map_err(|_| bad())?;
+        if !(1..=MAX_CRUSHER_BITS).contains(&bits) {
+            return Err(format!(
+                "bitcrusher takes 1 to {} bits, not {}",
+                MAX_CRUSHER_BITS, bits
+            ));
+        }
+        let downsample = match downsample {
+            Some(downsample) => downsample.parse().map_err(|_| bad())?,
+            None => 1,
+        };
+        if downsample == 0 {
+            return Err(bad());
+        }
+        Ok(Self::new(bits, downsample))
+    }
+}
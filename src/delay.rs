@@ -0,0 +1,282 @@
+use crate::sample_rate;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+// Musical divisions the (master) delay time can lock to when a tempo is known
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum DelaySync {
+    #[default]
+    Off,
+    Quarter,
+    Eighth,
+    Sixteenth,
+    DottedQuarter,
+    DottedEighth,
+    QuarterTriplet,
+    EighthTriplet,
+}
+
+impl DelaySync {
+    // length of the division in quarter-note beats
+    pub fn beats(self) -> Option<f32> {
+        match self {
+            DelaySync::Off => None,
+            DelaySync::Quarter => Some(1.0),
+            DelaySync::Eighth => Some(0.5),
+            DelaySync::Sixteenth => Some(0.25),
+            DelaySync::DottedQuarter => Some(1.5),
+            DelaySync::DottedEighth => Some(0.75),
+            DelaySync::QuarterTriplet => Some(2.0 / 3.0),
+            DelaySync::EighthTriplet => Some(1.0 / 3.0),
+        }
+    }
+
+    // delay length in samples, falls back to the ms setting when unsynced or no clock is present
+    pub fn delay_samples(self, bpm: Option<f32>, delay_ms: f32) -> usize {
+        let ms = match (self.beats(), bpm) {
+            (Some(beats), Some(bpm)) if bpm > 0.0 => beats * 60_000.0 / bpm,
+            _ => delay_ms,
+        };
+        (ms * sample_rate() as f32 / 1000.0).round() as usize
+    }
+}
+
+// Parses a note value: `1/4`, `1/8` or `1/16`, dotted with a trailing `.`, triplets with a
+// trailing `t`, or `off`
+impl FromStr for DelaySync {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "off" => Ok(DelaySync::Off),
+            "1/4" => Ok(DelaySync::Quarter),
+            "1/8" => Ok(DelaySync::Eighth),
+            "1/16" => Ok(DelaySync::Sixteenth),
+            "1/4." => Ok(DelaySync::DottedQuarter),
+            "1/8." => Ok(DelaySync::DottedEighth),
+            "1/4t" => Ok(DelaySync::QuarterTriplet),
+            "1/8t" => Ok(DelaySync::EighthTriplet),
+            _ => Err(format!("unknown delay division {:?}", s)),
+        }
+    }
+}
+
+// longest delay time the delay lines have room for
+pub const MAX_DELAY_MS: f32 = 2000.0;
+// more feedback than this and the echoes stop dying away
+pub const MAX_DELAY_FEEDBACK: f32 = 0.95;
+pub const DEFAULT_DELAY_MS: f32 = 350.0;
+pub const DEFAULT_DELAY_FEEDBACK: f32 = 0.4;
+// Time constant of the glide to a new delay time, it bends the echoes' pitch like a tape delay
+// rather than jumping and clicking
+const DELAY_GLIDE_MS: f32 = 50.0;
+// Slowest the glide moves, in samples per sample. Near a long time the one-pole steps get lost
+// in the f32 rounding and would stall short of the target.
+const DELAY_GLIDE_MIN_STEP: f32 = 0.01;
+
+// Stereo echo for the master mix, each side repeating into itself, or in ping-pong mode
+// bouncing between left and right. The lines are sized for MAX_DELAY_MS up front so the time can
+// change while playing. Synced to a note value, the time follows the tempo given to
+// follow_tempo instead of the ms setting.
+#[derive(Debug, Clone)]
+pub struct Delay {
+    left: Vec<f32>,
+    right: Vec<f32>,
+    pos: usize,
+    delay_ms: f32,
+    // note value the time locks to, taking over from the ms setting
+    pub sync: DelaySync,
+    tempo_bpm: Option<f32>,
+    // the delay time being glided to, and the one the lines are read at now, in samples
+    target_samples: f32,
+    delay_samples: f32,
+    glide: f32,
+    // 0..MAX_DELAY_FEEDBACK, how much of each echo is repeated
+    pub feedback: f32,
+    // 0 is dry only, 1 is wet only
+    pub mix: f32,
+    // the mix goes into the left line only and each line feeds the other, so even a centred
+    // hit echoes left, right, left, right...
+    pub ping_pong: bool,
+}
+
+// Off (all dry) until the mix is turned up
+impl Default for Delay {
+    fn default() -> Self {
+        Self::new(DEFAULT_DELAY_MS, DEFAULT_DELAY_FEEDBACK, 0.0)
+    }
+}
+
+impl Delay {
+    pub fn new(delay_ms: f32, feedback: f32, mix: f32) -> Self {
+        let len = (MAX_DELAY_MS * sample_rate() as f32 / 1000.0) as usize;
+        let glide_samples = DELAY_GLIDE_MS * sample_rate() as f32 / 1000.0;
+        let mut delay = Self {
+            left: vec![0.0; len],
+            right: vec![0.0; len],
+            pos: 0,
+            delay_ms: 0.0,
+            sync: DelaySync::Off,
+            tempo_bpm: None,
+            target_samples: 1.0,
+            delay_samples: 1.0,
+            glide: 1.0 - (-1.0 / glide_samples).exp(),
+            feedback,
+            mix,
+            ping_pong: false,
+        };
+        delay.set_time(delay_ms);
+        // a new delay starts out at its time
+        delay.delay_samples = delay.target_samples;
+        delay
+    }
+
+    // Set the time between echoes, clamped to MAX_DELAY_MS. Used while unsynced, the echoes
+    // glide over to it.
+    pub fn set_time(&mut self, delay_ms: f32) {
+        self.delay_ms = delay_ms.clamp(0.0, MAX_DELAY_MS);
+        self.retarget();
+    }
+
+    // the ms setting, whether or not it is synced
+    pub fn time(&self) -> f32 {
+        self.delay_ms
+    }
+
+    // Tempo for the sync division; the synth passes on the clock's every control tick
+    pub fn follow_tempo(&mut self, bpm: f32) {
+        self.tempo_bpm = Some(bpm);
+        self.retarget();
+    }
+
+    fn retarget(&mut self) {
+        let samples = self.sync.delay_samples(self.tempo_bpm, self.delay_ms);
+        self.target_samples = samples.clamp(1, self.left.len() - 1) as f32;
+    }
+
+    pub fn process_stereo(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let len = self.left.len();
+        let distance = self.target_samples - self.delay_samples;
+        let step = (distance.abs() * self.glide)
+            .max(DELAY_GLIDE_MIN_STEP)
+            .min(distance.abs());
+        self.delay_samples += step.copysign(distance);
+        let left_echo = read_line(&self.left, self.pos, self.delay_samples);
+        let right_echo = read_line(&self.right, self.pos, self.delay_samples);
+
+        let feedback = self.feedback.clamp(0.0, MAX_DELAY_FEEDBACK);
+        if self.ping_pong {
+            self.left[self.pos] = (left + right) * 0.5 + right_echo * feedback;
+            self.right[self.pos] = left_echo * feedback;
+        } else {
+            self.left[self.pos] = left + left_echo * feedback;
+            self.right[self.pos] = right + right_echo * feedback;
+        }
+        self.pos = (self.pos + 1) % len;
+
+        let dry = 1.0 - self.mix;
+        (
+            left * dry + left_echo * self.mix,
+            right * dry + right_echo * self.mix,
+        )
+    }
+}
+
+// Sample `delay` samples back from the write position `pos`, between samples by linear
+// interpolation so a gliding time doesn't crackle
+fn read_line(line: &[f32], pos: usize, delay: f32) -> f32 {
+    let len = line.len();
+    let back = delay.floor();
+    let frac = delay - back;
+    let newer = (pos + len - back as usize) % len;
+    let older = (newer + len - 1) % len;
+    line[newer] + (line[older] - line[newer]) * frac
+}
+
+// Parses `time,feedback[,mix[,pingpong]]`, the mix defaulting to half wet. The time is in ms or
+// a note value to sync to, e.g. `350,0.4`, `1/8.,0.4,0.3` or `1/4,0.5,0.4,pingpong`.
+impl FromStr for Delay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let bad = || format!("bad delay settings {:?}", s);
+        let fields: Vec<&str> = s.split(',').map(str::trim).collect();
+        let (time, feedback, mix, ping_pong) = match fields[..] {
+            [time, feedback] => (time, feedback, None, false),
+            [time, feedback, mix] => (time, feedback, Some(mix), false),
+            [time, feedback, mix, "pingpong"] => (time, feedback, Some(mix), true),
+            _ => return Err(bad()),
+        };
+        let (delay_ms, sync) = if time.contains('/') {
+            (DEFAULT_DELAY_MS, time.parse()?)
+        } else {
+            (time.parse().map_err(|_| bad())?, DelaySync::Off)
+        };
+        let mix = match mix {
+            Some(mix) => mix.parse().map_err(|_| bad())?,
+            None => 0.5,
+        };
+        let mut delay = Self::new(delay_ms, feedback.parse().map_err(|_| bad())?, mix);
+        delay.sync = sync;
+        delay.ping_pong = ping_pong;
+        Ok(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Samples until the first echo of an impulse, once the delay has glided to its time
+    fn echo_after(delay: &mut Delay) -> usize {
+        for _ in 0..sample_rate() {
+            delay.process_stereo(0.0, 0.0);
+        }
+        delay.process_stereo(1.0, 1.0);
+        (1..MAX_DELAY_MS as usize * sample_rate() as usize / 1000)
+            .find(|_| delay.process_stereo(0.0, 0.0).0 > 0.5)
+            .unwrap()
+    }
+
+    #[test]
+    fn synced_delay_follows_the_tempo() {
+        let mut delay = Delay::new(100.0, 0.0, 1.0);
+        delay.sync = DelaySync::Quarter;
+        delay.follow_tempo(120.0);
+        // a quarter note at 120 bpm is half a second
+        assert_eq!(echo_after(&mut delay), sample_rate() as usize / 2);
+
+        delay.follow_tempo(240.0);
+        assert_eq!(echo_after(&mut delay), sample_rate() as usize / 4);
+    }
+
+    #[test]
+    fn ping_pong_alternates_sides() {
+        let mut delay = Delay::new(10.0, 0.5, 1.0);
+        delay.ping_pong = true;
+        let tap = sample_rate() as usize / 100;
+        // a centred (mono) impulse
+        let mut out = vec![delay.process_stereo(1.0, 1.0)];
+        out.extend((1..5 * tap).map(|_| delay.process_stereo(0.0, 0.0)));
+
+        for (n, side) in [(1, 0), (2, 1), (3, 0), (4, 1)] {
+            let (left, right) = out[n * tap];
+            let (hit, other) = if side == 0 {
+                (left, right)
+            } else {
+                (right, left)
+            };
+            assert!(hit > 0.0, "echo {} missing", n);
+            assert_eq!(other, 0.0, "echo {} on both sides", n);
+        }
+        // each bounce is the last one scaled by the feedback
+        assert!((out[2 * tap].1 - out[tap].0 * 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn unsynced_delay_keeps_its_time() {
+        let mut delay = Delay::new(100.0, 0.0, 1.0);
+        delay.follow_tempo(120.0);
+        assert_eq!(echo_after(&mut delay), sample_rate() as usize / 10);
+    }
+}
@@ -0,0 +1,219 @@
+use crate::filter::{Filter, FilterMode, DEFAULT_RESONANCE};
+use crate::rng::XorShift32;
+use crate::{sample_rate, DrumMap};
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// General MIDI's percussion channel, 10 counting from 1
+pub const GM_DRUM_CHANNEL: u8 = 9;
+
+// fixed so every hit of a kit sounds the same from run to run
+const DRUM_NOISE_SEED: u32 = 0x5EED_D2C5;
+
+// A built-in percussion sound: a short fixed hit made from noise and decaying sines, played
+// at its own pitch whatever key it sits on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DrumVoice {
+    // sine sweeping down from a click into a low thump
+    Kick,
+    // tone body under a burst of high-passed noise
+    Snare,
+    // very short knock
+    Rim,
+    // a few quick noise bursts, then a tail
+    Clap,
+    // tick of bright noise
+    ClosedHat,
+    // bright noise left to ring
+    OpenHat,
+    // pitch-dropping sines, a fifth or so apart
+    LowTom,
+    MidTom,
+    HighTom,
+    // long wash of noise
+    Crash,
+    // two clashing squares through a resonant low-pass
+    Cowbell,
+}
+
+impl DrumVoice {
+    // The hit as mono PCM at the engine's sample rate
+    pub fn render(self) -> Vec<f32> {
+        let rate = sample_rate() as f32;
+        let mut noise = XorShift32::new(DRUM_NOISE_SEED);
+        let high_pass = |cutoff: f32| {
+            let mut filter = Filter::new(cutoff, DEFAULT_RESONANCE);
+            filter.set_mode(FilterMode::HighPass);
+            filter
+        };
+        let samples = |ms: f32| (ms * rate / 1000.0) as usize;
+        // exponential decay to about -60 dB over `ms`
+        let decay = |t: f32, ms: f32| (-6.9 * t * 1000.0 / ms).exp();
+
+        let (len_ms, mut sample): (f32, Box<dyn FnMut(f32) -> f32>) = match self {
+            DrumVoice::Kick => {
+                let mut phase = 0.0;
+                (
+                    400.0,
+                    Box::new(move |t| {
+                        let freq = 50.0 + 100.0 * (-t / 0.03).exp();
+                        phase += freq / rate;
+                        (2.0 * PI * phase).sin() * decay(t, 400.0)
+                    }),
+                )
+            }
+            DrumVoice::Snare => {
+                let mut filter = high_pass(1_500.0);
+                (
+                    250.0,
+                    Box::new(move |t| {
+                        let tone = (2.0 * PI * 185.0 * t).sin() * decay(t, 100.0);
+                        let hiss = filter.process(noise.next_bipolar()) * decay(t, 250.0);
+                        0.5 * tone + 0.6 * hiss
+                    }),
+                )
+            }
+            DrumVoice::Rim => (
+                40.0,
+                Box::new(move |t| (2.0 * PI * 820.0 * t).sin() * decay(t, 40.0)),
+            ),
+            DrumVoice::Clap => {
+                let mut filter = high_pass(1_000.0);
+                (
+                    300.0,
+                    Box::new(move |t| {
+                        // three 10 ms slaps, the last one trailing off
+                        let level = if t < 0.03 {
+                            decay(t % 0.01, 10.0)
+                        } else {
+                            decay(t - 0.03, 270.0)
+                        };
+                        filter.process(noise.next_bipolar()) * level * 1.2
+                    }),
+                )
+            }
+            DrumVoice::ClosedHat | DrumVoice::OpenHat => {
+                let ring_ms = if self == DrumVoice::ClosedHat {
+                    50.0
+                } else {
+                    400.0
+                };
+                let mut filter = high_pass(7_000.0);
+                (
+                    ring_ms,
+                    Box::new(move |t| {
+                        filter.process(noise.next_bipolar()) * decay(t, ring_ms) * 0.6
+                    }),
+                )
+            }
+            DrumVoice::LowTom | DrumVoice::MidTom | DrumVoice::HighTom => {
+                let pitch = match self {
+                    DrumVoice::LowTom => 90.0,
+                    DrumVoice::MidTom => 130.0,
+                    _ => 190.0,
+                };
+                let mut phase = 0.0;
+                (
+                    350.0,
+                    Box::new(move |t| {
+                        let freq = pitch * (0.7 + 0.3 * (-t / 0.08).exp());
+                        phase += freq / rate;
+                        (2.0 * PI * phase).sin() * decay(t, 350.0)
+                    }),
+                )
+            }
+            DrumVoice::Crash => {
+                let mut filter = high_pass(4_000.0);
+                (
+                    1_500.0,
+                    Box::new(move |t| {
+                        filter.process(noise.next_bipolar()) * decay(t, 1_500.0) * 0.5
+                    }),
+                )
+            }
+            DrumVoice::Cowbell => {
+                // resonant low-pass, rounds the squares into a clank
+                let mut filter = Filter::new(2_500.0, 2.0);
+                (
+                    200.0,
+                    Box::new(move |t| {
+                        let square = |freq: f32| if (t * freq).fract() < 0.5 { 1.0 } else { -1.0 };
+                        let body = (square(540.0) + square(800.0)) * 0.5;
+                        filter.process(body) * decay(t, 200.0) * 0.6
+                    }),
+                )
+            }
+        };
+        (0..samples(len_ms))
+            .map(|n| sample(n as f32 / rate).clamp(-1.0, 1.0))
+            .collect()
+    }
+}
+
+impl FromStr for DrumVoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "kick" | "bd" => Ok(DrumVoice::Kick),
+            "snare" | "sd" => Ok(DrumVoice::Snare),
+            "rim" => Ok(DrumVoice::Rim),
+            "clap" => Ok(DrumVoice::Clap),
+            "hat" | "closed_hat" | "ch" => Ok(DrumVoice::ClosedHat),
+            "open_hat" | "oh" => Ok(DrumVoice::OpenHat),
+            "low_tom" => Ok(DrumVoice::LowTom),
+            "mid_tom" => Ok(DrumVoice::MidTom),
+            "high_tom" => Ok(DrumVoice::HighTom),
+            "crash" => Ok(DrumVoice::Crash),
+            "cowbell" => Ok(DrumVoice::Cowbell),
+            _ => Err(format!("unknown drum sound {:?}", s)),
+        }
+    }
+}
+
+// Which sound each key of the drum channel plays
+pub type DrumKit = HashMap<u8, DrumVoice>;
+
+// The built-in sounds on their General MIDI keys
+pub fn gm_drum_kit() -> DrumKit {
+    HashMap::from([
+        (35, DrumVoice::Kick),
+        (36, DrumVoice::Kick),
+        (37, DrumVoice::Rim),
+        (38, DrumVoice::Snare),
+        (39, DrumVoice::Clap),
+        (40, DrumVoice::Snare),
+        (41, DrumVoice::LowTom),
+        (42, DrumVoice::ClosedHat),
+        (43, DrumVoice::LowTom),
+        (44, DrumVoice::ClosedHat),
+        (45, DrumVoice::MidTom),
+        (46, DrumVoice::OpenHat),
+        (47, DrumVoice::MidTom),
+        (48, DrumVoice::HighTom),
+        (49, DrumVoice::Crash),
+        (50, DrumVoice::HighTom),
+        (56, DrumVoice::Cowbell),
+        (57, DrumVoice::Crash),
+    ])
+}
+
+// Render every sound of a kit once, keys sharing a sound sharing its PCM
+pub fn render_drum_kit(kit: &DrumKit) -> DrumMap {
+    let mut rendered: Vec<(DrumVoice, Arc<Vec<f32>>)> = Vec::new();
+    kit.iter()
+        .map(|(&note, &drum)| {
+            let pcm = match rendered.iter().find(|(done, _)| *done == drum) {
+                Some((_, pcm)) => pcm.clone(),
+                None => {
+                    let pcm = Arc::new(drum.render());
+                    rendered.push((drum, pcm.clone()));
+                    pcm
+                }
+            };
+            (note, pcm)
+        })
+        .collect()
+}
@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum EnvMode {
+    // regular attack, decay, sustain until note-off, release
+    Adsr,
+    // attack then decay to silence, ignoring sustain/release and note-off
+    OneShot,
+    // no shaping at all, full level until the source itself ends (drum samples)
+    Bypass,
+}
+
+// Shape of the envelope segments
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum EnvCurve {
+    // a fixed step per tick, straight lines
+    #[default]
+    Linear,
+    // a fixed fraction of the way to the target per tick, like an analog envelope: a snappy
+    // attack and decays that tail off naturally
+    Exponential,
+}
+
+impl FromStr for EnvCurve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "linear" | "lin" => Ok(EnvCurve::Linear),
+            "exponential" | "exp" => Ok(EnvCurve::Exponential),
+            _ => Err(format!("unknown envelope curve {:?}", s)),
+        }
+    }
+}
+
+// Where a note is in its envelope, in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EnvStage {
+    // silence before the attack
+    Delay,
+    Attack,
+    // full level before the decay
+    Hold,
+    Decay,
+    Sustain,
+    Release,
+    // finished, the voice can be freed
+    Off,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Adsr {
+    // silence before the attack and time at full level after it, in ms like the other
+    // stages. Defaulted, like the curve, so envelopes saved before they existed still load.
+    #[serde(default)]
+    pub delay: usize,
+    pub attack: usize,
+    #[serde(default)]
+    pub hold: usize,
+    pub decay: usize,
+    pub sustain: f32,
+    pub release: usize,
+    // loop attack/decay until note-off instead of holding sustain
+    pub loop_ad: bool,
+    pub mode: EnvMode,
+    #[serde(default)]
+    pub curve: EnvCurve,
+}
+
+impl Default for Adsr {
+    fn default() -> Self {
+        Adsr {
+            delay: 0,
+            attack: 10,
+            hold: 0,
+            decay: 10,
+            sustain: 1.0,
+            release: 10,
+            loop_ad: false,
+            mode: EnvMode::Adsr,
+            curve: EnvCurve::Linear,
+        }
+    }
+}
+
+// Envelope that sweeps a voice's filter cutoff, times in ms like the amp envelope. It rises
+// linearly from the patch's cutoff to `amount` octaves away over the attack, falls to the
+// sustain fraction of that over the decay, and goes back to the cutoff over the release.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FilterEnv {
+    pub attack: usize,
+    pub decay: usize,
+    pub sustain: f32,
+    pub release: usize,
+    // octaves at the peak, negative sweeps the cutoff down, 0 leaves it alone
+    pub amount: f32,
+}
+
+impl Default for FilterEnv {
+    fn default() -> Self {
+        FilterEnv {
+            attack: 0,
+            decay: 200,
+            sustain: 0.0,
+            release: 200,
+            amount: 0.0,
+        }
+    }
+}
+
+// Parses `<attack ms>,<decay ms>,<sustain>,<release ms>,<octaves>`, e.g. `0,250,0.2,300,4`
+impl FromStr for FilterEnv {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let bad = || format!("bad filter envelope {:?}", s);
+        let fields: Vec<&str> = s.split(',').map(str::trim).collect();
+        let [attack, decay, sustain, release, amount] = fields[..] else {
+            return Err(bad());
+        };
+        let sustain: f32 = sustain.parse().map_err(|_| bad())?;
+        if !(0.0..=1.0).contains(&sustain) {
+            return Err(bad());
+        }
+        Ok(FilterEnv {
+            attack: attack.parse().map_err(|_| bad())?,
+            decay: decay.parse().map_err(|_| bad())?,
+            sustain,
+            release: release.parse().map_err(|_| bad())?,
+            amount: amount.parse().map_err(|_| bad())?,
+        })
+    }
+}
+
+impl Adsr {
+    // Envelope for drum hits: the sample plays untouched and note-off is ignored
+    pub fn bypass() -> Self {
+        Adsr {
+            mode: EnvMode::Bypass,
+            ..Adsr::default()
+        }
+    }
+}
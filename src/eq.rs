@@ -0,0 +1,238 @@
+use crate::sample_rate;
+use std::f32::consts::PI;
+
+pub const DEFAULT_EQ_LOW_HZ: f32 = 200.0;
+pub const DEFAULT_EQ_MID_HZ: f32 = 1000.0;
+pub const DEFAULT_EQ_HIGH_HZ: f32 = 4000.0;
+pub const DEFAULT_EQ_MID_Q: f32 = 1.0;
+// shelf slope, 1/sqrt(2) is the steepest without overshoot
+const SHELF_Q: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+// Second-order IIR section (transposed direct form II) with RBJ cookbook designs
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    z1: f32,
+    z2: f32,
+}
+
+impl Default for Biquad {
+    // passes the signal through untouched
+    fn default() -> Self {
+        Self {
+            b0: 1.0,
+            b1: 0.0,
+            b2: 0.0,
+            a1: 0.0,
+            a2: 0.0,
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+}
+
+impl Biquad {
+    // set the coefficients, normalised by a0, keeping the filter state
+    fn set(&mut self, b: [f32; 3], a: [f32; 3]) {
+        self.b0 = b[0] / a[0];
+        self.b1 = b[1] / a[0];
+        self.b2 = b[2] / a[0];
+        self.a1 = a[1] / a[0];
+        self.a2 = a[2] / a[0];
+    }
+
+    // w0 and alpha of the cookbook formulas
+    fn omega(freq: f32, q: f32) -> (f32, f32, f32) {
+        let w0 = 2.0 * PI * freq.clamp(1.0, sample_rate() as f32 * 0.49) / sample_rate() as f32;
+        let (sin, cos) = w0.sin_cos();
+        (cos, sin, sin / (2.0 * q.max(0.01)))
+    }
+
+    pub(crate) fn set_low_shelf(&mut self, freq: f32, gain_db: f32) {
+        let a = 10f32.powf(gain_db / 40.0);
+        let (cos, _, alpha) = Self::omega(freq, SHELF_Q);
+        let k = 2.0 * a.sqrt() * alpha;
+        self.set(
+            [
+                a * ((a + 1.0) - (a - 1.0) * cos + k),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - k),
+            ],
+            [
+                (a + 1.0) + (a - 1.0) * cos + k,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - k,
+            ],
+        );
+    }
+
+    pub(crate) fn set_high_shelf(&mut self, freq: f32, gain_db: f32) {
+        let a = 10f32.powf(gain_db / 40.0);
+        let (cos, _, alpha) = Self::omega(freq, SHELF_Q);
+        let k = 2.0 * a.sqrt() * alpha;
+        self.set(
+            [
+                a * ((a + 1.0) + (a - 1.0) * cos + k),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - k),
+            ],
+            [
+                (a + 1.0) - (a - 1.0) * cos + k,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - k,
+            ],
+        );
+    }
+
+    pub(crate) fn set_peak(&mut self, freq: f32, q: f32, gain_db: f32) {
+        let a = 10f32.powf(gain_db / 40.0);
+        let (cos, _, alpha) = Self::omega(freq, q);
+        self.set(
+            [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+            [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+        );
+    }
+
+    // band-pass with 0 dB gain at the centre
+    pub(crate) fn set_band_pass(&mut self, freq: f32, q: f32) {
+        let (cos, _, alpha) = Self::omega(freq, q);
+        self.set([alpha, 0.0, -alpha], [1.0 + alpha, -2.0 * cos, 1.0 - alpha]);
+    }
+
+    pub(crate) fn process(&mut self, input: f32) -> f32 {
+        let out = self.b0 * input + self.z1;
+        self.z1 = self.b1 * input - self.a1 * out + self.z2;
+        self.z2 = self.b2 * input - self.a2 * out;
+        out
+    }
+}
+
+// Low shelf, mid peak and high shelf in series on the master mix. Flat (all gains 0 dB) by
+// default.
+#[derive(Debug, Clone, Copy)]
+pub struct ThreeBandEq {
+    low: Biquad,
+    mid: Biquad,
+    high: Biquad,
+    // the same bands for the right channel of a stereo signal
+    right: [Biquad; 3],
+    low_hz: f32,
+    mid_hz: f32,
+    high_hz: f32,
+    mid_q: f32,
+    low_db: f32,
+    mid_db: f32,
+    high_db: f32,
+}
+
+impl Default for ThreeBandEq {
+    fn default() -> Self {
+        let mut eq = Self {
+            low: Biquad::default(),
+            mid: Biquad::default(),
+            high: Biquad::default(),
+            right: [Biquad::default(); 3],
+            low_hz: DEFAULT_EQ_LOW_HZ,
+            mid_hz: DEFAULT_EQ_MID_HZ,
+            high_hz: DEFAULT_EQ_HIGH_HZ,
+            mid_q: DEFAULT_EQ_MID_Q,
+            low_db: 0.0,
+            mid_db: 0.0,
+            high_db: 0.0,
+        };
+        eq.update();
+        eq
+    }
+}
+
+impl ThreeBandEq {
+    fn update(&mut self) {
+        self.low.set_low_shelf(self.low_hz, self.low_db);
+        self.mid.set_peak(self.mid_hz, self.mid_q, self.mid_db);
+        self.high.set_high_shelf(self.high_hz, self.high_db);
+        let [low, mid, high] = &mut self.right;
+        low.set_low_shelf(self.low_hz, self.low_db);
+        mid.set_peak(self.mid_hz, self.mid_q, self.mid_db);
+        high.set_high_shelf(self.high_hz, self.high_db);
+    }
+
+    // band gains in dB, as (low, mid, high)
+    pub fn gains(&self) -> (f32, f32, f32) {
+        (self.low_db, self.mid_db, self.high_db)
+    }
+
+    pub fn set_low(&mut self, gain_db: f32) {
+        self.low_db = gain_db;
+        self.update();
+    }
+
+    pub fn set_mid(&mut self, gain_db: f32) {
+        self.mid_db = gain_db;
+        self.update();
+    }
+
+    pub fn set_high(&mut self, gain_db: f32) {
+        self.high_db = gain_db;
+        self.update();
+    }
+
+    // shelf corners and the centre of the mid band, in Hz
+    pub fn set_frequencies(&mut self, low_hz: f32, mid_hz: f32, high_hz: f32) {
+        self.low_hz = low_hz;
+        self.mid_hz = mid_hz;
+        self.high_hz = high_hz;
+        self.update();
+    }
+
+    pub fn set_mid_q(&mut self, q: f32) {
+        self.mid_q = q;
+        self.update();
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        self.high
+            .process(self.mid.process(self.low.process(sample)))
+    }
+
+    pub fn process_stereo(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let [low, mid, high] = &mut self.right;
+        let right = high.process(mid.process(low.process(right)));
+        (self.process(left), right)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Gain of the EQ on a sine at `freq`, measured over a second once the filters have settled
+    fn gain_at(eq: &mut ThreeBandEq, freq: f32) -> f32 {
+        let rate = sample_rate() as f32;
+        let sine = |n: usize| (2.0 * PI * freq * n as f32 / rate).sin();
+        let rms = |samples: &[f32]| {
+            (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+        };
+        let len = rate as usize;
+        let output: Vec<f32> = (0..2 * len).map(|n| eq.process(sine(n))).collect();
+        let input: Vec<f32> = (len..2 * len).map(sine).collect();
+        rms(&output[len..]) / rms(&input)
+    }
+
+    #[test]
+    fn low_shelf_boost_raises_the_lows() {
+        let mut eq = ThreeBandEq::default();
+        assert!((gain_at(&mut eq, 50.0) - 1.0).abs() < 0.01);
+
+        eq.set_low(6.0);
+        // well below the corner the shelf gives its full boost, 6 dB is about double
+        let low = gain_at(&mut eq, 50.0);
+        assert!((low - 10f32.powf(6.0 / 20.0)).abs() < 0.05, "gain {}", low);
+        // and leaves the top end alone
+        let high = gain_at(&mut eq, 5_000.0);
+        assert!((high - 1.0).abs() < 0.02, "gain {}", high);
+    }
+}
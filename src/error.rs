@@ -0,0 +1,42 @@
+use std::{
+    error::Error,
+    fmt, io,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+// Failures while bringing the instrument up, each with what was being attempted
+#[derive(Debug)]
+pub enum SynthError {
+    // audio output stream or sink
+    Audio(String),
+    // MIDI ports and connections
+    Midi(String),
+    // front-panel GPIO
+    Gpio(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for SynthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SynthError::Audio(msg) => write!(f, "audio: {}", msg),
+            SynthError::Midi(msg) => write!(f, "MIDI: {}", msg),
+            SynthError::Gpio(msg) => write!(f, "GPIO: {}", msg),
+            SynthError::Io(err) => write!(f, "I/O: {}", err),
+        }
+    }
+}
+
+impl Error for SynthError {}
+
+impl From<io::Error> for SynthError {
+    fn from(err: io::Error) -> Self {
+        SynthError::Io(err)
+    }
+}
+
+// Lock a mutex, carrying on with the data if another thread panicked while holding it. The
+// synth state stays usable, and one bad callback shouldn't silence the whole instrument.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
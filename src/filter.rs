@@ -0,0 +1,124 @@
+use crate::sample_rate;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::{FRAC_1_SQRT_2, PI};
+use std::str::FromStr;
+
+// Fully open by default, the filter only colours the sound once it is turned down
+pub const DEFAULT_CUTOFF_HZ: f32 = 20_000.0;
+// no resonant peak
+pub const DEFAULT_RESONANCE: f32 = FRAC_1_SQRT_2;
+pub const MIN_CUTOFF_HZ: f32 = 20.0;
+pub const MAX_RESONANCE: f32 = 40.0;
+
+// Which side of the cutoff the filter lets through
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum FilterMode {
+    LowPass,
+    // thins the sound out from below, fully open at the lowest cutoff
+    HighPass,
+}
+
+impl FromStr for FilterMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "lowpass" | "lp" => Ok(FilterMode::LowPass),
+            "highpass" | "hp" => Ok(FilterMode::HighPass),
+            _ => Err(format!("unknown filter mode {:?}", s)),
+        }
+    }
+}
+
+// Resonant 12 dB/octave low-pass or high-pass (trapezoidal state-variable filter). It stays
+// stable at any cutoff and resonance, high Q rings and self-oscillates without running away.
+#[derive(Debug, Clone, Copy)]
+pub struct Filter {
+    mode: FilterMode,
+    cutoff: f32,
+    resonance: f32,
+    g: f32,
+    k: f32,
+    a1: f32,
+    a2: f32,
+    a3: f32,
+    ic1eq: f32,
+    ic2eq: f32,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self::new(DEFAULT_CUTOFF_HZ, DEFAULT_RESONANCE)
+    }
+}
+
+impl Filter {
+    pub fn new(cutoff: f32, resonance: f32) -> Self {
+        let mut filter = Self {
+            mode: FilterMode::LowPass,
+            cutoff: -1.0,
+            resonance: -1.0,
+            g: 0.0,
+            k: 0.0,
+            a1: 0.0,
+            a2: 0.0,
+            a3: 0.0,
+            ic1eq: 0.0,
+            ic2eq: 0.0,
+        };
+        filter.set(cutoff, resonance);
+        filter
+    }
+
+    pub fn cutoff(&self) -> f32 {
+        self.cutoff
+    }
+
+    pub fn resonance(&self) -> f32 {
+        self.resonance
+    }
+
+    pub fn mode(&self) -> FilterMode {
+        self.mode
+    }
+
+    // Both modes come out of the same state, switching mid-note doesn't click
+    pub fn set_mode(&mut self, mode: FilterMode) {
+        self.mode = mode;
+    }
+
+    // Cutoff in Hz and resonance as Q, the coefficients are only recomputed when they change
+    pub fn set(&mut self, cutoff: f32, resonance: f32) {
+        let cutoff = cutoff.clamp(MIN_CUTOFF_HZ, sample_rate() as f32 * 0.49);
+        let resonance = resonance.clamp(0.5, MAX_RESONANCE);
+        if cutoff == self.cutoff && resonance == self.resonance {
+            return;
+        }
+        self.cutoff = cutoff;
+        self.resonance = resonance;
+
+        self.g = (PI * cutoff / sample_rate() as f32).tan();
+        self.k = 1.0 / resonance;
+        self.a1 = 1.0 / (1.0 + self.g * (self.g + self.k));
+        self.a2 = self.g * self.a1;
+        self.a3 = self.g * self.a2;
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        let v3 = input - self.ic2eq;
+        let v1 = self.a1 * self.ic1eq + self.a2 * v3;
+        let v2 = self.ic2eq + self.a2 * self.ic1eq + self.a3 * v3;
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+        // a bad input sample must not leave the filter stuck on NaN for the rest of the note
+        if !self.ic1eq.is_finite() || !self.ic2eq.is_finite() {
+            self.ic1eq = 0.0;
+            self.ic2eq = 0.0;
+            return 0.0;
+        }
+        match self.mode {
+            FilterMode::LowPass => v2,
+            FilterMode::HighPass => input - self.k * v1 - v2,
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use crate::sample_rate;
+
+pub const DEFAULT_FOLLOWER_ATTACK_MS: f32 = 10.0;
+pub const DEFAULT_FOLLOWER_RELEASE_MS: f32 = 100.0;
+
+// Coefficient of a one-pole smoother that covers ~63% of a step in `ms`
+fn smoothing_coeff(ms: f32) -> f32 {
+    if ms <= 0.0 {
+        return 0.0;
+    }
+    (-1.0 / (ms * 0.001 * sample_rate() as f32)).exp()
+}
+
+// Tracks the amplitude of a signal as a 0..1 control value, rising with the attack time and
+// falling with the release time
+#[derive(Debug, Clone, Copy)]
+pub struct EnvelopeFollower {
+    attack_coeff: f32,
+    release_coeff: f32,
+    value: f32,
+}
+
+impl Default for EnvelopeFollower {
+    fn default() -> Self {
+        Self::new(DEFAULT_FOLLOWER_ATTACK_MS, DEFAULT_FOLLOWER_RELEASE_MS)
+    }
+}
+
+impl EnvelopeFollower {
+    pub fn new(attack_ms: f32, release_ms: f32) -> Self {
+        Self {
+            attack_coeff: smoothing_coeff(attack_ms),
+            release_coeff: smoothing_coeff(release_ms),
+            value: 0.0,
+        }
+    }
+
+    pub fn set_times(&mut self, attack_ms: f32, release_ms: f32) {
+        self.attack_coeff = smoothing_coeff(attack_ms);
+        self.release_coeff = smoothing_coeff(release_ms);
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        let level = sample.abs();
+        let coeff = if level > self.value {
+            self.attack_coeff
+        } else {
+            self.release_coeff
+        };
+        self.value = level + (self.value - level) * coeff;
+        self.value
+    }
+
+    pub fn value(&self) -> f32 {
+        self.value
+    }
+}
@@ -0,0 +1,63 @@
+use crate::eq::Biquad;
+
+// First three formants (Hz) of the vowels A, E, I, O, U (adult male voice)
+pub const VOWEL_FORMANTS: [[f32; 3]; 5] = [
+    [800.0, 1150.0, 2900.0],
+    [400.0, 1600.0, 2700.0],
+    [350.0, 1700.0, 2700.0],
+    [450.0, 800.0, 2830.0],
+    [325.0, 700.0, 2530.0],
+];
+// bandwidth (Hz) and level of each formant band
+const FORMANT_BANDWIDTHS: [f32; 3] = [80.0, 90.0, 120.0];
+const FORMANT_GAINS: [f32; 3] = [1.0, 0.5, 0.25];
+// the narrow band-passes throw away most of the level, bring it back up
+const FORMANT_MAKEUP_GAIN: f32 = 2.0;
+
+// Three parallel band-passes tuned to the formants of a vowel. The vowel position runs 0..4
+// (A, E, I, O, U) and fractional positions interpolate between neighbouring vowels.
+#[derive(Debug, Clone, Copy)]
+pub struct FormantFilter {
+    bands: [Biquad; 3],
+    vowel: f32,
+}
+
+impl FormantFilter {
+    pub fn new(vowel: f32) -> Self {
+        let mut filter = Self {
+            bands: [Biquad::default(); 3],
+            vowel: -1.0,
+        };
+        filter.set_vowel(vowel);
+        filter
+    }
+
+    pub fn vowel(&self) -> f32 {
+        self.vowel
+    }
+
+    pub fn set_vowel(&mut self, vowel: f32) {
+        let vowel = vowel.clamp(0.0, (VOWEL_FORMANTS.len() - 1) as f32);
+        if vowel == self.vowel {
+            return;
+        }
+        self.vowel = vowel;
+
+        let from = vowel.floor() as usize;
+        let to = (from + 1).min(VOWEL_FORMANTS.len() - 1);
+        let frac = vowel - from as f32;
+        for (i, band) in self.bands.iter_mut().enumerate() {
+            let freq =
+                VOWEL_FORMANTS[from][i] + (VOWEL_FORMANTS[to][i] - VOWEL_FORMANTS[from][i]) * frac;
+            band.set_band_pass(freq, freq / FORMANT_BANDWIDTHS[i]);
+        }
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        let mut out = 0.0;
+        for (band, gain) in self.bands.iter_mut().zip(FORMANT_GAINS) {
+            out += band.process(sample) * gain;
+        }
+        out * FORMANT_MAKEUP_GAIN
+    }
+}
@@ -0,0 +1,141 @@
+use crate::clock::parse_note_value;
+use crate::sample_rate;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+pub const DEFAULT_LFO_RATE_HZ: f32 = 5.0;
+// range of the rate CC
+pub const MIN_LFO_RATE_HZ: f32 = 0.1;
+pub const MAX_LFO_RATE_HZ: f32 = 20.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LfoShape {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+}
+
+impl FromStr for LfoShape {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "sine" => Ok(LfoShape::Sine),
+            "triangle" => Ok(LfoShape::Triangle),
+            "saw" => Ok(LfoShape::Saw),
+            "square" => Ok(LfoShape::Square),
+            _ => Err(format!("unknown LFO shape {:?}", s)),
+        }
+    }
+}
+
+// Low-frequency oscillator feeding the mod matrix, bipolar (-1..1) times the depth. It is
+// advanced at control rate and shared by all voices, so they all move together. Where it goes
+// and how far is set by the mod matrix routes.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Lfo {
+    pub shape: LfoShape,
+    pub rate_hz: f32,
+    // length of a cycle in quarter-note beats when locked to the tempo, rate_hz is ignored.
+    // Free running for LFOs saved before it existed.
+    #[serde(default)]
+    pub sync_beats: Option<f32>,
+    pub depth: f32,
+    // scale the depth by the mod wheel of each voice's channel, so vibrato (say) comes in as
+    // the wheel is pushed
+    pub wheel: bool,
+    #[serde(skip)]
+    phase: f32,
+}
+
+impl Default for Lfo {
+    fn default() -> Self {
+        Self::new(LfoShape::Sine, DEFAULT_LFO_RATE_HZ, 1.0, false)
+    }
+}
+
+impl Lfo {
+    pub fn new(shape: LfoShape, rate_hz: f32, depth: f32, wheel: bool) -> Self {
+        Self {
+            shape,
+            rate_hz,
+            sync_beats: None,
+            depth,
+            wheel,
+            phase: 0.0,
+        }
+    }
+
+    // Move on by `samples`, a synced LFO at `bpm`
+    pub fn advance(&mut self, samples: usize, bpm: f32) {
+        let rate_hz = match self.sync_beats {
+            Some(beats) => bpm / 60.0 / beats,
+            None => self.rate_hz,
+        };
+        self.phase = (self.phase + rate_hz * samples as f32 / sample_rate() as f32).fract();
+    }
+
+    // Back to the start of the cycle, so a synced LFO lines up with the downbeat
+    pub fn restart(&mut self) {
+        self.phase = 0.0;
+    }
+
+    pub fn value(&self) -> f32 {
+        self.wave() * self.depth
+    }
+
+    // Where the cycle is, -1..1 before the depth
+    pub fn wave(&self) -> f32 {
+        let phase = self.phase;
+        match self.shape {
+            LfoShape::Sine => (2.0 * PI * phase).sin(),
+            // starts at 0 going up, like the sine
+            LfoShape::Triangle => 1.0 - 4.0 * (phase - 0.25 - (phase - 0.25).round()).abs(),
+            LfoShape::Saw => 2.0 * phase - 1.0,
+            LfoShape::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+        }
+    }
+}
+
+// Parses `<shape>,<rate>[,<depth>][,wheel]`, the rate in Hz or a note value locking it to the
+// tempo, e.g. `sine,5`, `triangle,0.5,0.8,wheel` or `saw,1/8`
+impl FromStr for Lfo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let bad = || format!("bad LFO settings {:?}", s);
+        let mut fields: Vec<&str> = s.split(',').map(str::trim).collect();
+        let wheel = fields.last() == Some(&"wheel");
+        if wheel {
+            fields.pop();
+        }
+        let (shape, rate, depth) = match fields[..] {
+            [shape, rate] => (shape, rate, None),
+            [shape, rate, depth] => (shape, rate, Some(depth)),
+            _ => return Err(bad()),
+        };
+        let depth = match depth {
+            Some(depth) => depth.parse().map_err(|_| bad())?,
+            None => 1.0,
+        };
+        if rate.contains('/') {
+            let mut lfo = Lfo::new(shape.parse()?, DEFAULT_LFO_RATE_HZ, depth, wheel);
+            lfo.sync_beats = Some(parse_note_value(rate)?);
+            return Ok(lfo);
+        }
+        Ok(Lfo::new(
+            shape.parse()?,
+            rate.parse().map_err(|_| bad())?,
+            depth,
+            wheel,
+        ))
+    }
+}
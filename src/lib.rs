@@ -0,0 +1,98 @@
+mod arp;
+mod chorus;
+mod clock;
+mod compressor;
+mod crusher;
+mod delay;
+mod drums;
+mod envelope;
+mod eq;
+mod error;
+mod filter;
+mod follower;
+mod formant;
+mod lfo;
+mod limiter;
+mod meter;
+mod midi_file;
+mod modmatrix;
+mod offline;
+mod patch;
+mod recorder;
+mod rng;
+mod sample;
+mod shaper;
+mod smooth;
+mod spectrum;
+mod state;
+mod synth;
+mod voice;
+mod wave;
+
+pub use arp::{ArpMode, ArpRate, Arpeggiator, MAX_ARP_OCTAVES};
+pub use chorus::{
+    Chorus, DEFAULT_CHORUS_DEPTH_MS, DEFAULT_CHORUS_MIX, DEFAULT_CHORUS_RATE_HZ,
+    DEFAULT_CHORUS_VOICES, MAX_CHORUS_DEPTH_MS, MAX_CHORUS_VOICES,
+};
+pub use clock::{parse_note_value, MidiClock, DEFAULT_TEMPO_BPM};
+pub use compressor::{Compressor, DEFAULT_COMPRESSOR_ATTACK_MS, DEFAULT_COMPRESSOR_RELEASE_MS};
+pub use crusher::{Crusher, DEFAULT_CRUSHER_BITS, DEFAULT_CRUSHER_DOWNSAMPLE, MAX_CRUSHER_BITS};
+pub use delay::{
+    Delay, DelaySync, DEFAULT_DELAY_FEEDBACK, DEFAULT_DELAY_MS, MAX_DELAY_FEEDBACK, MAX_DELAY_MS,
+};
+pub use drums::{gm_drum_kit, render_drum_kit, DrumKit, DrumVoice, GM_DRUM_CHANNEL};
+pub use envelope::{Adsr, EnvCurve, EnvMode, EnvStage, FilterEnv};
+pub use eq::{
+    ThreeBandEq, DEFAULT_EQ_HIGH_HZ, DEFAULT_EQ_LOW_HZ, DEFAULT_EQ_MID_HZ, DEFAULT_EQ_MID_Q,
+};
+pub use error::{lock, SynthError};
+pub use filter::{
+    Filter, FilterMode, DEFAULT_CUTOFF_HZ, DEFAULT_RESONANCE, MAX_RESONANCE, MIN_CUTOFF_HZ,
+};
+pub use follower::{EnvelopeFollower, DEFAULT_FOLLOWER_ATTACK_MS, DEFAULT_FOLLOWER_RELEASE_MS};
+pub use formant::{FormantFilter, VOWEL_FORMANTS};
+pub use lfo::{Lfo, LfoShape, DEFAULT_LFO_RATE_HZ, MAX_LFO_RATE_HZ, MIN_LFO_RATE_HZ};
+pub use limiter::{Limiter, DEFAULT_LIMITER_CEILING_DB, DEFAULT_LIMITER_RELEASE_MS};
+pub use meter::{Level, LevelMeter};
+pub use midi_file::play_midi_file;
+pub use modmatrix::{ModDest, ModMatrix, ModOutputs, ModRoute, ModSource, ModSources};
+pub use offline::{render_events, Event, OFFLINE_SEED};
+pub use patch::{Osc2, Patch, VelocityLayer, DEFAULT_LAYER_WIDTH};
+pub use recorder::MidiRecorder;
+pub use sample::{load_drum_map, load_sample, load_wavetables, DrumMap, WavetablePair};
+pub use shaper::{ShapeCurve, Shaper, DEFAULT_DRIVE, MAX_DRIVE};
+pub use smooth::{SmoothedParam, DEFAULT_SMOOTHING_MS};
+pub use spectrum::{SpectrumAnalyzer, SpectrumTap, SPECTRUM_SIZE};
+pub use state::{PatchState, SavedOsc2, SavedPatch, SavedWave, SynthState, STATE_VERSION};
+pub use synth::{
+    NotePriority, PanMode, Synth, TriggerMode, VelocityCurve, VelocityDest, CC_CHORUS_DEPTH,
+    CC_CHORUS_MIX, CC_CHORUS_RATE, CC_CUTOFF, CC_DELAY_FEEDBACK, CC_DELAY_MIX, CC_DELAY_TIME,
+    CC_EQ_HIGH, CC_EQ_LOW, CC_EQ_MID, CC_FILTER_ENV_AMOUNT, CC_LFO_DEPTH, CC_LFO_RATE,
+    CC_NOISE_COLOR, CC_PULSE_WIDTH, CC_RESONANCE, CC_VOWEL, DEFAULT_BEND_RANGE,
+    DEFAULT_BEND_SMOOTHING_MS, DEFAULT_CONTROL_RATE, DEFAULT_GLIDE_MS, DEFAULT_POLYPHONY,
+    DEFAULT_UNISON_DETUNE, DEFAULT_VELOCITY_CUTOFF_OCTAVES, MAX_FILTER_ENV_OCTAVES, MAX_GLIDE_MS,
+    MAX_POLYPHONY, MAX_TRANSPOSE, MIDI_CHANNELS,
+};
+pub use voice::{Voice, MAX_UNISON_VOICES};
+pub use wave::{Wave, WaveTrims, WaveType, DEFAULT_PULSE_WIDTH, MAX_PULSE_WIDTH, MIN_PULSE_WIDTH};
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+static SAMPLE_RATE: AtomicU32 = AtomicU32::new(DEFAULT_SAMPLE_RATE);
+
+// Rate the engine renders at, in Hz
+pub fn sample_rate() -> u32 {
+    SAMPLE_RATE.load(Ordering::Relaxed)
+}
+
+// Change the engine's sample rate. Set it at startup, before the synth is created and samples
+// are loaded: filters, envelopes and samples are set up for the rate of their creation.
+pub fn set_sample_rate(hz: u32) {
+    SAMPLE_RATE.store(hz, Ordering::Relaxed);
+}
+
+pub fn midi_note_to_freq(midi_note: u8) -> f32 {
+    2f32.powf((midi_note as f32 - 69.0) / 12.0) * 440.0
+}
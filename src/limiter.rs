@@ -0,0 +1,144 @@
+use crate::sample_rate;
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+pub const DEFAULT_LIMITER_CEILING_DB: f32 = -0.3;
+pub const DEFAULT_LIMITER_RELEASE_MS: f32 = 50.0;
+// how far ahead the limiter sees peaks coming, the output is late by this much
+const LIMITER_LOOKAHEAD_MS: f32 = 2.0;
+// time constants of the gain's fall per look-ahead window, so it is (nearly) down by the time
+// the peak comes out
+const LIMITER_ATTACK_TIME_CONSTANTS: f32 = 5.0;
+
+// Look-ahead peak limiter for the end of the chain. The signal is delayed by the look-ahead
+// time while the gain it is going to need is worked out, so the gain can come down smoothly
+// before a peak instead of clipping it, and it recovers with the release time. Whatever is
+// still over the ceiling after that is clipped, so the output never goes past it.
+#[derive(Debug, Clone)]
+pub struct Limiter {
+    ceiling_db: f32,
+    ceiling: f32,
+    release_ms: f32,
+    release_coeff: f32,
+    attack_coeff: f32,
+    // the last look-ahead's worth of input
+    delay: Vec<(f32, f32)>,
+    pos: usize,
+    // (sample index, gain it needs) over the look-ahead window, gains rising front to back so
+    // the front is the lowest
+    needed: VecDeque<(usize, f32)>,
+    count: usize,
+    gain: f32,
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self::new(DEFAULT_LIMITER_CEILING_DB, DEFAULT_LIMITER_RELEASE_MS)
+    }
+}
+
+impl Limiter {
+    pub fn new(ceiling_db: f32, release_ms: f32) -> Self {
+        let lookahead = ((LIMITER_LOOKAHEAD_MS * sample_rate() as f32 / 1000.0) as usize).max(1);
+        let mut limiter = Self {
+            ceiling_db: 0.0,
+            ceiling: 1.0,
+            release_ms: 0.0,
+            release_coeff: 0.0,
+            attack_coeff: (-LIMITER_ATTACK_TIME_CONSTANTS / lookahead as f32).exp(),
+            delay: vec![(0.0, 0.0); lookahead],
+            pos: 0,
+            needed: VecDeque::with_capacity(lookahead + 1),
+            count: 0,
+            gain: 1.0,
+        };
+        limiter.set_ceiling_db(ceiling_db);
+        limiter.set_release(release_ms);
+        limiter
+    }
+
+    // Highest level let out, in dB below full scale (0 or negative)
+    pub fn set_ceiling_db(&mut self, ceiling_db: f32) {
+        self.ceiling_db = ceiling_db.min(0.0);
+        self.ceiling = 10f32.powf(self.ceiling_db / 20.0);
+    }
+
+    pub fn ceiling_db(&self) -> f32 {
+        self.ceiling_db
+    }
+
+    // How quickly the gain comes back up after a peak
+    pub fn set_release(&mut self, release_ms: f32) {
+        self.release_ms = release_ms.max(0.0);
+        let samples = self.release_ms * sample_rate() as f32 / 1000.0;
+        self.release_coeff = if samples > 0.0 {
+            (-1.0 / samples).exp()
+        } else {
+            0.0
+        };
+    }
+
+    pub fn release(&self) -> f32 {
+        self.release_ms
+    }
+
+    // how far the last sample was turned down, in dB (0 or positive)
+    pub fn gain_reduction_db(&self) -> f32 {
+        -20.0 * self.gain.log10()
+    }
+
+    // Both channels are turned down together so the stereo image doesn't shift
+    pub fn process_stereo(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let peak = left.abs().max(right.abs());
+        let needed = if peak > self.ceiling {
+            self.ceiling / peak
+        } else {
+            1.0
+        };
+        // keep the lowest gain needed over the window at the front
+        while self.needed.back().is_some_and(|&(_, gain)| gain >= needed) {
+            self.needed.pop_back();
+        }
+        self.needed.push_back((self.count, needed));
+        let window = self.delay.len();
+        while self
+            .needed
+            .front()
+            .is_some_and(|&(index, _)| index + window < self.count)
+        {
+            self.needed.pop_front();
+        }
+        self.count += 1;
+
+        let target = self.needed.front().map_or(1.0, |&(_, gain)| gain);
+        let coeff = if target < self.gain {
+            self.attack_coeff
+        } else {
+            self.release_coeff
+        };
+        self.gain = target + (self.gain - target) * coeff;
+
+        let (left, right) = std::mem::replace(&mut self.delay[self.pos], (left, right));
+        self.pos = (self.pos + 1) % window;
+        let clip = |sample: f32| (sample * self.gain).clamp(-self.ceiling, self.ceiling);
+        (clip(left), clip(right))
+    }
+}
+
+// Parses `<ceiling_db>[,<release_ms>]`, e.g. `-1` or `-1,100`
+impl FromStr for Limiter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let values = s
+            .split(',')
+            .map(|value| value.trim().parse::<f32>())
+            .collect::<Result<Vec<f32>, _>>()
+            .map_err(|_| format!("bad limiter settings {:?}", s))?;
+        match values[..] {
+            [ceiling_db] => Ok(Self::new(ceiling_db, DEFAULT_LIMITER_RELEASE_MS)),
+            [ceiling_db, release_ms] => Ok(Self::new(ceiling_db, release_ms)),
+            _ => Err(format!("bad limiter settings {:?}", s)),
+        }
+    }
+}
@@ -1,469 +1,1854 @@
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    terminal::{disable_raw_mode, enable_raw_mode},
+};
 use lazy_static::lazy_static;
-use midir::{Ignore, MidiInput};
+use midir::{Ignore, MidiInput, MidiInputPort, MidiOutput, MidiOutputConnection};
+use rodio::cpal::{
+    self,
+    traits::{DeviceTrait, HostTrait},
+};
 use rodio::Source;
-use rodio::{OutputStream, OutputStreamHandle, Sink};
-use rppal::gpio::{Gpio, Level};
-use std::f32::consts::PI;
+use rodio::{buffer::SamplesBuffer, OutputStream, OutputStreamHandle, Sink};
+#[cfg(feature = "gpio")]
+use rppal::{
+    gpio::{Gpio, InputPin, Trigger},
+    spi::{Bus, Mode, SlaveSelect, Spi},
+};
+#[cfg(feature = "gpio")]
+use std::{collections::HashMap, fs, time::Instant};
 use std::{
-    collections::{HashMap, HashSet},
     error::Error,
     io::{stdin, stdout, Write},
-    sync::{Arc, Mutex},
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
     thread,
     time::Duration,
 };
-const SAMPLE_RATE: usize = 44_000;
+use synth::{
+    load_drum_map, load_sample, load_wavetables, lock, play_midi_file, sample_rate,
+    set_sample_rate, Arpeggiator, Chorus, Compressor, Crusher, Delay, EnvCurve, FilterEnv,
+    FilterMode, LevelMeter, Lfo, Limiter, MidiRecorder, ModMatrix, Osc2, PanMode, Shaper,
+    SpectrumAnalyzer, SpectrumTap, Synth, SynthError, TriggerMode, VelocityCurve, VelocityDest,
+    VelocityLayer, Wave, WaveTrims, WaveType, DEFAULT_CHORUS_MIX, DEFAULT_POLYPHONY,
+    DEFAULT_PULSE_WIDTH, DEFAULT_SAMPLE_RATE, MAX_UNISON_VOICES,
+};
 
-#[allow(unused)]
+// How far one press of the front-panel up/down buttons moves the selected ADSR parameter, and
+// the range the times are kept in (--env-step, --env-range, --sustain-step)
 #[derive(Debug, Clone, Copy)]
-enum WaveType {
-    Sine,
-    Square,
-    Saw,
-    Triangle,
+struct EnvAdjust {
+    step_ms: usize,
+    min_ms: usize,
+    max_ms: usize,
+    sustain_step: f32,
+}
+
+impl Default for EnvAdjust {
+    fn default() -> Self {
+        EnvAdjust {
+            step_ms: 10,
+            min_ms: 10,
+            max_ms: 1000,
+            sustain_step: 0.05,
+        }
+    }
 }
 
-#[derive(Clone, Debug)]
-struct Wave {
-    freq: f32,
-    num_sample: usize,
-    typ: WaveType,
-    state: f32,
+// What the panel needs besides the synth: how the envelope buttons step and where the save
+// button writes to
+#[derive(Debug, Clone)]
+struct PanelSetup {
+    env_adjust: EnvAdjust,
+    state_path: Option<String>,
+    patch_path: Option<String>,
 }
 
-impl Wave {
-    fn new(freq: f32, typ: WaveType) -> Wave {
-        Wave {
-            freq,
-            typ,
-            num_sample: 0,
-            state: 0.0,
+// Presses of a panel button closer together than this are contact bounce
+#[cfg(feature = "gpio")]
+const BUTTON_DEBOUNCE_MS: u64 = 50;
+
+// Highest BCM pin number on the Pi's GPIO header
+#[cfg(feature = "gpio")]
+const MAX_GPIO_PIN: u8 = 27;
+// Chip enable 0, MISO, MOSI and clock of the SPI bus the pots' ADC is on
+#[cfg(feature = "gpio")]
+const SPI0_PINS: [u8; 4] = [8, 9, 10, 11];
+
+// How often the keyboard thread checks whether it has been stopped
+const KEY_POLL_MS: u64 = 50;
+
+lazy_static! {
+    static ref ENV_TYPE: Mutex<u8> = Mutex::new(0);
+}
+
+// Command line options
+#[derive(Debug)]
+struct Args {
+    // Standard MIDI File to play through the synth
+    play: Option<String>,
+    // keep repeating the MIDI file
+    loop_play: bool,
+    // record the incoming MIDI to this file, written on exit
+    record: Option<String>,
+    // forward incoming MIDI to the output port whose name contains this
+    thru: Option<String>,
+    // only listen to the input ports picked by these, each a number from --list-midi or part of
+    // a name, all of them when there are none
+    ports: Vec<String>,
+    // print the MIDI input ports and exit
+    list_midi: bool,
+    thru_filter: ThruFilter,
+    // WAV file to play instead of an oscillator
+    sample: Option<String>,
+    // two single-cycle WAV files to morph between
+    wavetables: Option<(String, String)>,
+    // second wave to crossfade into by velocity
+    velocity_layer: Option<VelocityLayer>,
+    // how hard a key has to be struck to play loud
+    velocity_curve: Option<VelocityCurve>,
+    // what a repeated note-on does to a key still sounding
+    trigger_mode: Option<TriggerMode>,
+    // what velocity changes and optionally how many octaves it moves the cutoff
+    velocity_dest: Option<(VelocityDest, Option<f32>)>,
+    // how new notes are placed in the stereo field, and how far either side
+    pan_spread: Option<(PanMode, f32)>,
+    // overrides of the per-waveform loudness trims
+    wave_trims: Option<WaveTrims>,
+    // note to sample map for playing drums
+    drums: Option<String>,
+    // MIDI channel (0-based) playing the built-in drum kit, or none
+    drum_channel: Option<Option<u8>>,
+    // one legato voice per channel
+    mono: bool,
+    // mono mode with overlapping notes only changing the pitch, the envelope carries on
+    legato: bool,
+    // arpeggiate held keys with these settings
+    arp: Option<Arpeggiator>,
+    // internal tempo, for when no MIDI clock comes in
+    tempo_bpm: Option<f32>,
+    // portamento on, taking this long per glide
+    glide_ms: Option<f32>,
+    // semitones of a full pitch bend
+    bend_range: Option<f32>,
+    // ms a bend takes to catch up with the wheel
+    bend_smoothing_ms: Option<f32>,
+    // seconds after which a held note is released as stuck
+    note_timeout_secs: Option<f32>,
+    // semitones every key is shifted by
+    transpose: Option<i8>,
+    // oscillators per note and optionally their detune in cents
+    unison: Option<(usize, Option<f32>)>,
+    // shape of every channel's envelope
+    env_curve: Option<EnvCurve>,
+    // every channel's envelope delay and hold times in ms
+    env_delay_hold: Option<(usize, usize)>,
+    // what the panel's envelope up/down buttons do
+    env_adjust: EnvAdjust,
+    // every channel's second oscillator, switching it on
+    osc2: Option<Osc2>,
+    // every channel's ring modulator ratio
+    ring_ratio: Option<f32>,
+    // every channel's filter mode and filter envelope
+    filter_mode: Option<FilterMode>,
+    filter_env: Option<FilterEnv>,
+    // number of simultaneous voices
+    polyphony: usize,
+    // rate the synth renders and the output runs at, in Hz
+    sample_rate: u32,
+    // LFO settings, replacing the defaults
+    lfos: [Option<Lfo>; 2],
+    // modulation routes to load
+    mod_routes: Option<String>,
+    // waveshaper settings, switching it on
+    shaper: Option<Shaper>,
+    // bitcrusher settings, switching it on
+    crusher: Option<Crusher>,
+    // master chorus settings
+    chorus: Option<Chorus>,
+    // master delay settings
+    delay: Option<Delay>,
+    // master compressor settings
+    compressor: Option<Compressor>,
+    // output level in dB
+    master_gain_db: Option<f32>,
+    // output limiter settings
+    limiter: Option<Limiter>,
+    // draw a live spectrum of the output in the terminal
+    spectrum: bool,
+    // show a live output level meter in the terminal
+    meter: bool,
+    // set the envelope from pots on an MCP3008 ADC instead of the up/down buttons
+    pots: bool,
+    // set the master gain from a pot on the same ADC
+    gain_pot: bool,
+    // drive the panel controls from the computer keyboard instead of the GPIO buttons
+    keys: bool,
+    // which panel button is on which pin
+    #[cfg(feature = "gpio")]
+    pins: Option<String>,
+    // play a reference tone and exit
+    selftest: bool,
+    // fixed seed for all random features, random when not given
+    seed: Option<u32>,
+    // where the synth state is restored from at startup and saved on exit
+    state: Option<String>,
+    // TOML patch file the sound is loaded from at startup and the save button writes to
+    patch: Option<String>,
+}
+
+// How long the sound is given to fade out on exit
+const SHUTDOWN_FADE_MS: u64 = 50;
+
+// State file used unless --state or --no-state says otherwise
+const DEFAULT_STATE_FILE: &str = "synth-state.json";
+// Patch file used unless --patch or --no-patch says otherwise
+const DEFAULT_PATCH_FILE: &str = "default.toml";
+// Panel layout used unless --pins says otherwise, the built-in one when it doesn't exist
+#[cfg(feature = "gpio")]
+const DEFAULT_PINS_FILE: &str = "pins.conf";
+
+// Which incoming messages are passed on to the MIDI thru port
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum ThruFilter {
+    #[default]
+    All,
+    Notes,
+}
+
+impl ThruFilter {
+    fn passes(self, message: &[u8]) -> bool {
+        match self {
+            ThruFilter::All => true,
+            // note on/off on any channel
+            ThruFilter::Notes => matches!(message.first(), Some(128..=159)),
         }
     }
 }
 
-impl Iterator for Wave {
-    type Item = f32;
-
-    fn next(&mut self) -> Option<f32> {
-        self.num_sample = self.num_sample.wrapping_add(1);
-        let period = 1.0 / self.freq * SAMPLE_RATE as f32;
+impl Default for Args {
+    fn default() -> Self {
+        Args {
+            play: None,
+            loop_play: false,
+            record: None,
+            thru: None,
+            ports: Vec::new(),
+            list_midi: false,
+            thru_filter: ThruFilter::All,
+            sample: None,
+            wavetables: None,
+            velocity_layer: None,
+            velocity_curve: None,
+            trigger_mode: None,
+            velocity_dest: None,
+            pan_spread: None,
+            wave_trims: None,
+            drums: None,
+            drum_channel: None,
+            mono: false,
+            legato: false,
+            arp: None,
+            tempo_bpm: None,
+            glide_ms: None,
+            bend_range: None,
+            bend_smoothing_ms: None,
+            note_timeout_secs: None,
+            transpose: None,
+            unison: None,
+            env_curve: None,
+            env_delay_hold: None,
+            env_adjust: EnvAdjust::default(),
+            osc2: None,
+            ring_ratio: None,
+            filter_mode: None,
+            filter_env: None,
+            polyphony: DEFAULT_POLYPHONY,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            lfos: [None, None],
+            mod_routes: None,
+            shaper: None,
+            crusher: None,
+            chorus: None,
+            delay: None,
+            compressor: None,
+            master_gain_db: None,
+            limiter: None,
+            spectrum: false,
+            meter: false,
+            pots: false,
+            gain_pot: false,
+            keys: false,
+            #[cfg(feature = "gpio")]
+            pins: Some(DEFAULT_PINS_FILE.to_string()),
+            selftest: false,
+            seed: None,
+            state: Some(DEFAULT_STATE_FILE.to_string()),
+            patch: Some(DEFAULT_PATCH_FILE.to_string()),
+        }
+    }
+}
 
-        Some(match self.typ {
-            WaveType::Sine => {
-                (2.0 * PI * self.freq * self.num_sample as f32 / (SAMPLE_RATE as f32)).sin()
-            }
-            WaveType::Saw => {
-                self.state = 2.0
-                    * ((self.num_sample as f32 / period)
-                        - (0.5 + (self.num_sample as f32 / period)).floor());
-                self.state
-            }
-            WaveType::Square => {
-                if self.num_sample % (period as usize) <= (period / 2.0) as usize {
-                    1f32
-                } else {
-                    -1f32
+impl Args {
+    fn parse() -> Result<Self, String> {
+        let mut args = Args::default();
+        let mut iter = std::env::args().skip(1);
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--play" => args.play = Some(iter.next().ok_or("--play needs a file")?),
+                "--loop" => args.loop_play = true,
+                "--record" => args.record = Some(iter.next().ok_or("--record needs a file")?),
+                "--thru" => args.thru = Some(iter.next().ok_or("--thru needs a port name")?),
+                "--midi" | "--port" => args
+                    .ports
+                    .push(iter.next().ok_or("--midi needs a port name or number")?),
+                "--list-midi" => args.list_midi = true,
+                "--thru-notes" => args.thru_filter = ThruFilter::Notes,
+                "--sample" => args.sample = Some(iter.next().ok_or("--sample needs a file")?),
+                "--wavetables" => {
+                    let a = iter.next().ok_or("--wavetables needs two files")?;
+                    let b = iter.next().ok_or("--wavetables needs two files")?;
+                    args.wavetables = Some((a, b));
                 }
+                "--velocity-layer" => {
+                    let value = iter.next().ok_or("--velocity-layer needs wave,split")?;
+                    args.velocity_layer = Some(value.parse()?);
+                }
+                "--velocity-curve" => {
+                    let value = iter.next().ok_or("--velocity-curve needs a curve")?;
+                    args.velocity_curve = Some(value.parse()?);
+                }
+                "--trigger-mode" => {
+                    let value = iter.next().ok_or("--trigger-mode needs a mode")?;
+                    args.trigger_mode = Some(value.parse()?);
+                }
+                "--velocity-dest" => {
+                    let value = iter
+                        .next()
+                        .ok_or("--velocity-dest needs amp, cutoff or both")?;
+                    let (dest, octaves) = match value.split_once(',') {
+                        Some((dest, octaves)) => (dest, Some(octaves)),
+                        None => (value.as_str(), None),
+                    };
+                    let octaves = octaves
+                        .map(|octaves| {
+                            octaves
+                                .trim()
+                                .parse()
+                                .map_err(|_| format!("bad velocity cutoff {:?}", octaves))
+                        })
+                        .transpose()?;
+                    args.velocity_dest = Some((dest.trim().parse()?, octaves));
+                }
+                "--pan-spread" => {
+                    let value = iter
+                        .next()
+                        .ok_or("--pan-spread needs fixed, random, key or roundrobin")?;
+                    let (mode, spread) = match value.split_once(',') {
+                        Some((mode, spread)) => (mode, Some(spread)),
+                        None => (value.as_str(), None),
+                    };
+                    let spread = match spread {
+                        Some(spread) => spread
+                            .trim()
+                            .parse()
+                            .map_err(|_| format!("bad pan spread {:?}", spread))?,
+                        None => 1.0,
+                    };
+                    args.pan_spread = Some((mode.trim().parse()?, spread));
+                }
+                "--wave-trims" => {
+                    let value = iter.next().ok_or("--wave-trims needs wave=gain pairs")?;
+                    args.wave_trims = Some(value.parse()?);
+                }
+                "--drums" => args.drums = Some(iter.next().ok_or("--drums needs a file")?),
+                "--drum-channel" => {
+                    let value = iter.next().ok_or("--drum-channel needs a channel or off")?;
+                    args.drum_channel = Some(match value.as_str() {
+                        "off" => None,
+                        // counted from 1 like on the MIDI gear
+                        _ => Some(
+                            value
+                                .parse::<u8>()
+                                .ok()
+                                .filter(|channel| (1..=16).contains(channel))
+                                .ok_or_else(|| format!("bad drum channel {:?}", value))?
+                                - 1,
+                        ),
+                    });
+                }
+                "--mod-routes" => {
+                    args.mod_routes = Some(iter.next().ok_or("--mod-routes needs a file")?)
+                }
+                "--lfo1" | "--lfo2" => {
+                    let value = iter.next().ok_or("--lfo needs shape,rate")?;
+                    args.lfos[usize::from(arg == "--lfo2")] = Some(value.parse()?);
+                }
+                "--drive" => {
+                    let value = iter.next().ok_or("--drive needs curve[,drive]")?;
+                    args.shaper = Some(value.parse()?);
+                }
+                "--crush" => {
+                    let value = iter.next().ok_or("--crush needs bits[,downsample]")?;
+                    args.crusher = Some(value.parse()?);
+                }
+                "--chorus" => {
+                    let value = iter.next().ok_or("--chorus needs rate_hz,depth_ms")?;
+                    args.chorus = Some(value.parse()?);
+                }
+                "--delay" => {
+                    let value = iter
+                        .next()
+                        .ok_or("--delay needs time_ms,feedback or division,feedback")?;
+                    args.delay = Some(value.parse()?);
+                }
+                "--gain" => {
+                    let value = iter.next().ok_or("--gain needs a level in dB")?;
+                    args.master_gain_db =
+                        Some(value.parse().map_err(|_| format!("bad gain {:?}", value))?);
+                }
+                "--limit" => {
+                    let value = iter.next().ok_or("--limit needs a ceiling in dB")?;
+                    args.limiter = Some(value.parse()?);
+                }
+                "--compress" => {
+                    let value = iter.next().ok_or("--compress needs threshold,ratio")?;
+                    args.compressor = Some(value.parse()?);
+                }
+                "--spectrum" => args.spectrum = true,
+                "--meter" => args.meter = true,
+                "--pots" => args.pots = true,
+                "--gain-pot" => args.gain_pot = true,
+                "--keys" => args.keys = true,
+                #[cfg(feature = "gpio")]
+                "--pins" => args.pins = Some(iter.next().ok_or("--pins needs a file")?),
+                #[cfg(not(feature = "gpio"))]
+                "--pins" => return Err("--pins needs a build with the gpio feature".into()),
+                "--selftest" => args.selftest = true,
+                "--seed" => {
+                    let value = iter.next().ok_or("--seed needs a number")?;
+                    args.seed = Some(value.parse().map_err(|_| format!("bad seed {:?}", value))?);
+                }
+                "--state" => args.state = Some(iter.next().ok_or("--state needs a file")?),
+                "--no-state" => args.state = None,
+                "--patch" => args.patch = Some(iter.next().ok_or("--patch needs a file")?),
+                "--no-patch" => args.patch = None,
+                "--mono" => args.mono = true,
+                "--legato" => {
+                    args.mono = true;
+                    args.legato = true;
+                }
+                "--tempo" => {
+                    let value = iter.next().ok_or("--tempo needs beats per minute")?;
+                    args.tempo_bpm = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|&bpm: &f32| bpm > 0.0)
+                            .ok_or_else(|| format!("bad tempo {:?}", value))?,
+                    );
+                }
+                "--arp" => {
+                    let value = iter.next().ok_or("--arp needs mode,rate")?;
+                    args.arp = Some(value.parse()?);
+                }
+                "--glide" => {
+                    let value = iter.next().ok_or("--glide needs a time in ms")?;
+                    args.glide_ms = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("bad glide {:?}", value))?,
+                    );
+                }
+                "--unison" => {
+                    let value = iter.next().ok_or("--unison needs voices[,detune]")?;
+                    let bad = || format!("bad unison {:?}", value);
+                    let (voices, detune) = match value.split_once(',') {
+                        Some((voices, detune)) => (voices, Some(detune)),
+                        None => (value.as_str(), None),
+                    };
+                    let voices: usize = voices.trim().parse().map_err(|_| bad())?;
+                    if !(1..=MAX_UNISON_VOICES).contains(&voices) {
+                        return Err(format!(
+                            "unison needs 1 to {} voices, not {}",
+                            MAX_UNISON_VOICES, voices
+                        ));
+                    }
+                    let detune = detune
+                        .map(|detune| detune.trim().parse().map_err(|_| bad()))
+                        .transpose()?;
+                    args.unison = Some((voices, detune));
+                }
+                "--transpose" => {
+                    let value = iter.next().ok_or("--transpose needs semitones")?;
+                    args.transpose = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("bad transpose {:?}", value))?,
+                    );
+                }
+                "--bend-range" => {
+                    let value = iter.next().ok_or("--bend-range needs semitones")?;
+                    args.bend_range = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("bad bend range {:?}", value))?,
+                    );
+                }
+                "--note-timeout" => {
+                    let value = iter.next().ok_or("--note-timeout needs seconds")?;
+                    args.note_timeout_secs = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|secs: &f32| *secs > 0.0)
+                            .ok_or_else(|| format!("bad note timeout {:?}", value))?,
+                    );
+                }
+                "--bend-smoothing" => {
+                    let value = iter.next().ok_or("--bend-smoothing needs ms")?;
+                    args.bend_smoothing_ms = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|ms: &f32| *ms >= 0.0)
+                            .ok_or_else(|| format!("bad bend smoothing {:?}", value))?,
+                    );
+                }
+                "--env-curve" => {
+                    let value = iter
+                        .next()
+                        .ok_or("--env-curve needs linear or exponential")?;
+                    args.env_curve = Some(value.parse()?);
+                }
+                "--env-delay-hold" => {
+                    let value = iter
+                        .next()
+                        .ok_or("--env-delay-hold needs delay_ms,hold_ms")?;
+                    let bad = || format!("bad envelope delay and hold {:?}", value);
+                    let (delay, hold) = value.split_once(',').ok_or_else(bad)?;
+                    args.env_delay_hold = Some((
+                        delay.trim().parse().map_err(|_| bad())?,
+                        hold.trim().parse().map_err(|_| bad())?,
+                    ));
+                }
+                "--env-step" => {
+                    let value = iter.next().ok_or("--env-step needs a time in ms")?;
+                    args.env_adjust.step_ms = value
+                        .parse()
+                        .ok()
+                        .filter(|&ms: &usize| ms > 0)
+                        .ok_or_else(|| format!("bad envelope step {:?}", value))?;
+                }
+                "--env-range" => {
+                    let value = iter.next().ok_or("--env-range needs min_ms,max_ms")?;
+                    let bad = || format!("bad envelope range {:?}", value);
+                    let (min, max) = value.split_once(',').ok_or_else(bad)?;
+                    let min: usize = min.trim().parse().map_err(|_| bad())?;
+                    let max: usize = max.trim().parse().map_err(|_| bad())?;
+                    if min > max {
+                        return Err(format!("envelope range {} to {} is backwards", min, max));
+                    }
+                    args.env_adjust.min_ms = min;
+                    args.env_adjust.max_ms = max;
+                }
+                "--sustain-step" => {
+                    let value = iter.next().ok_or("--sustain-step needs a level")?;
+                    args.env_adjust.sustain_step = value
+                        .parse()
+                        .ok()
+                        .filter(|step: &f32| *step > 0.0 && *step <= 1.0)
+                        .ok_or_else(|| format!("bad sustain step {:?}", value))?;
+                }
+                "--osc2" => {
+                    let value = iter
+                        .next()
+                        .ok_or("--osc2 needs wave[,semitones[,detune[,mix]]]")?;
+                    args.osc2 = Some(value.parse()?);
+                }
+                "--ring" => {
+                    let value = iter.next().ok_or("--ring needs a frequency ratio")?;
+                    let ratio = value
+                        .parse()
+                        .ok()
+                        .filter(|&ratio: &f32| ratio > 0.0)
+                        .ok_or_else(|| format!("bad ring modulator ratio {:?}", value))?;
+                    args.ring_ratio = Some(ratio);
+                }
+                "--filter" => {
+                    let value = iter.next().ok_or("--filter needs lowpass or highpass")?;
+                    args.filter_mode = Some(value.parse()?);
+                }
+                "--filter-env" => {
+                    let value = iter
+                        .next()
+                        .ok_or("--filter-env needs attack,decay,sustain,release,octaves")?;
+                    args.filter_env = Some(value.parse()?);
+                }
+                "--polyphony" => {
+                    let value = iter.next().ok_or("--polyphony needs a voice count")?;
+                    args.polyphony = value
+                        .parse()
+                        .map_err(|_| format!("bad polyphony {:?}", value))?;
+                }
+                "--sample-rate" => {
+                    let value = iter.next().ok_or("--sample-rate needs a rate in Hz")?;
+                    args.sample_rate = value
+                        .parse()
+                        .ok()
+                        .filter(|&rate| rate > 0)
+                        .ok_or_else(|| format!("bad sample rate {:?}", value))?;
+                }
+                _ => return Err(format!("unknown argument {}", arg)),
             }
-            WaveType::Triangle => {
-                self.state = 2.0
-                    * (2.0
-                        * ((self.num_sample as f32 / period)
-                            - (self.num_sample as f32 / period + 0.5).floor()))
-                    .abs()
-                    - 1.0;
-                self.state
-            }
-        })
+        }
+        Ok(args)
     }
 }
 
-impl Source for Wave {
-    #[inline]
-    fn current_frame_len(&self) -> Option<usize> {
-        None
-    }
+fn main() {
+    let args = match Args::parse() {
+        Ok(args) => args,
+        Err(err) => {
+            println!("Error: {}", err);
+            return;
+        }
+    };
 
-    #[inline]
-    fn channels(&self) -> u16 {
-        1
-    }
+    // before anything that depends on the rate is set up
+    set_sample_rate(args.sample_rate);
 
-    #[inline]
-    fn sample_rate(&self) -> u32 {
-        SAMPLE_RATE as u32
+    if args.list_midi {
+        if let Err(err) = list_midi_ports() {
+            println!("Error listing MIDI ports: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.selftest {
+        match selftest() {
+            Ok(()) => println!("Self-test passed"),
+            Err(err) => {
+                println!("Self-test failed: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
     }
 
-    #[inline]
-    fn total_duration(&self) -> Option<Duration> {
-        None
+    let mut synth = Synth::with_polyphony(args.polyphony);
+    println!("Polyphony: {} voices", synth.polyphony());
+    if let Some(seed) = args.seed {
+        synth.set_seed(seed);
     }
-}
+    // printed so a run can be reproduced with --seed
+    println!("Seed: {}", synth.seed());
+    // restore the last session first, so options given on the command line win
+    if let Some(path) = &args.state {
+        if Path::new(path).exists() {
+            match synth.load_state(path) {
+                Ok(()) => println!("Restored state from {}", path),
+                Err(err) => println!("Ignoring state in {}: {}", path, err),
+            }
+        }
+    }
+    // then the saved sound over it, the one the save button last stored
+    if let Some(path) = &args.patch {
+        if Path::new(path).exists() {
+            match synth.load_patch(path) {
+                Ok(()) => println!("Patch from {}", path),
+                Err(err) => println!("Ignoring patch in {}: {}", path, err),
+            }
+        }
+    }
+    if let Some(path) = &args.sample {
+        match load_sample(path) {
+            // samples play back at their recorded pitch on middle C
+            Ok(pcm) => synth.set_wave(WaveType::Sample { pcm, root: 60 }),
+            Err(err) => println!("Error loading {}: {}", path, err),
+        }
+    }
+    if let Some((a, b)) = &args.wavetables {
+        match load_wavetables(a, b) {
+            // morph starts on the first table, route a source to `morph` to move it
+            Ok((a, b)) => synth.set_wave(WaveType::Wavetable { a, b, morph: 0.0 }),
+            Err(err) => println!("Error loading wavetables: {}", err),
+        }
+    }
+    if let Some(layer) = &args.velocity_layer {
+        for patch in synth.patches.iter_mut() {
+            patch.velocity_layer = Some(layer.clone());
+        }
+    }
+    if args.mono {
+        synth.set_mono(true);
+    }
+    if args.legato {
+        synth.retrigger = false;
+    }
+    if let Some(bpm) = args.tempo_bpm {
+        synth.clock.bpm = bpm;
+    }
+    if let Some(arp) = &args.arp {
+        synth.arp = arp.clone();
+        synth.set_arp(true);
+    }
+    if let Some(glide_ms) = args.glide_ms {
+        synth.glide = true;
+        synth.glide_ms = glide_ms;
+    }
+    if let Some(curve) = args.env_curve {
+        for patch in synth.patches.iter_mut() {
+            patch.adsr.curve = curve;
+        }
+    }
+    if let Some((delay, hold)) = args.env_delay_hold {
+        for patch in synth.patches.iter_mut() {
+            patch.adsr.delay = delay;
+            patch.adsr.hold = hold;
+        }
+    }
+    if let Some(osc2) = &args.osc2 {
+        for patch in synth.patches.iter_mut() {
+            patch.osc2 = osc2.clone();
+        }
+    }
+    if let Some(ratio) = args.ring_ratio {
+        for patch in synth.patches.iter_mut() {
+            patch.ring_ratio = Some(ratio);
+        }
+    }
+    if let Some(mode) = args.filter_mode {
+        for patch in synth.patches.iter_mut() {
+            patch.filter_mode = mode;
+        }
+    }
+    if let Some(filter_env) = args.filter_env {
+        for patch in synth.patches.iter_mut() {
+            patch.filter_env = filter_env;
+        }
+    }
+    if let Some((voices, detune)) = args.unison {
+        synth.unison_voices = voices;
+        if let Some(detune) = detune {
+            synth.unison_detune = detune;
+        }
+    }
+    if let Some(bend_range) = args.bend_range {
+        synth.bend_range = bend_range;
+    }
+    if let Some(ms) = args.bend_smoothing_ms {
+        synth.bend_smoothing_ms = ms;
+    }
+    if let Some(secs) = args.note_timeout_secs {
+        synth.note_timeout_secs = Some(secs);
+    }
+    if let Some(transpose) = args.transpose {
+        synth.set_transpose(transpose);
+    }
+    if let Some(curve) = args.velocity_curve {
+        synth.velocity_curve = curve;
+    }
+    if let Some(mode) = args.trigger_mode {
+        synth.trigger_mode = mode;
+    }
+    if let Some((dest, octaves)) = args.velocity_dest {
+        synth.velocity_dest = dest;
+        if let Some(octaves) = octaves {
+            synth.velocity_cutoff = octaves;
+        }
+    }
+    if let Some((mode, spread)) = args.pan_spread {
+        synth.pan_mode = mode;
+        synth.pan_spread = spread;
+    }
+    if let Some(wave_trims) = args.wave_trims {
+        synth.wave_trims = wave_trims;
+    }
+    if let Some(path) = &args.drums {
+        match load_drum_map(path) {
+            Ok(drum_map) => synth.drum_map = drum_map,
+            Err(err) => println!("Error loading {}: {}", path, err),
+        }
+    }
+    if let Some(drum_channel) = args.drum_channel {
+        synth.drum_channel = drum_channel;
+    }
+    for (lfo, arg) in synth.lfos.iter_mut().zip(args.lfos) {
+        if let Some(arg) = arg {
+            *lfo = arg;
+        }
+    }
+    if let Some(path) = &args.mod_routes {
+        match ModMatrix::load(path) {
+            Ok(mod_matrix) => synth.mod_matrix = mod_matrix,
+            Err(err) => println!("Error loading {}: {}", path, err),
+        }
+    }
+    if let Some(shaper) = args.shaper {
+        synth.shaper = shaper;
+    }
+    if let Some(crusher) = args.crusher {
+        synth.crusher = crusher;
+    }
+    if let Some(chorus) = &args.chorus {
+        synth.chorus = chorus.clone();
+    }
+    if let Some(delay) = &args.delay {
+        synth.delay = delay.clone();
+    }
+    if let Some(compressor) = args.compressor {
+        synth.compressor = compressor;
+    }
+    if let Some(gain_db) = args.master_gain_db {
+        synth.master_gain = 10f32.powf(gain_db / 20.0);
+    }
+    if let Some(limiter) = &args.limiter {
+        synth.limiter = limiter.clone();
+    }
+    let synth = Arc::new(Mutex::new(synth));
 
-#[derive(Debug, Clone, Copy)]
-struct Adsr {
-    attack: usize,
-    decay: usize,
-    sustain: f32,
-    release: usize,
+    let setup = PanelSetup {
+        env_adjust: args.env_adjust,
+        state_path: args.state.clone(),
+        patch_path: args.patch.clone(),
+    };
+    #[cfg(feature = "gpio")]
+    let listeners = if args.keys {
+        Vec::new()
+    } else {
+        let panel = match &args.pins {
+            Some(path) if Path::new(path).exists() => match load_panel(path) {
+                Ok(panel) => {
+                    println!("Panel layout from {}", path);
+                    panel
+                }
+                Err(err) => {
+                    println!("Ignoring panel layout in {}: {}", path, err);
+                    default_panel()
+                }
+            },
+            _ => default_panel(),
+        };
+        start_panel(&synth, panel, args.pots || args.gain_pot, &setup)
+    };
+    // without GPIO the keyboard is the only front panel there is
+    let keys = match (args.keys || cfg!(not(feature = "gpio")))
+        .then(|| KeyboardControl::new(synth.clone(), setup.clone()))
+    {
+        Some(Err(err)) => {
+            println!("Keyboard controls disabled: {}", err);
+            None
+        }
+        keys => keys.and_then(Result::ok),
+    };
+    #[cfg(not(feature = "gpio"))]
+    if args.pots || args.gain_pot {
+        println!("Pots disabled: built without the gpio feature");
+    }
+    #[cfg(feature = "gpio")]
+    let pots = match (args.pots || args.gain_pot)
+        .then(|| AnalogControl::new(synth.clone(), args.pots, args.gain_pot))
+    {
+        Some(Err(err)) => {
+            println!("Pots disabled: {}", err);
+            None
+        }
+        pots => pots.and_then(Result::ok),
+    };
+    match run(synth.clone(), args, keys.as_ref()) {
+        Ok(_) => (),
+        Err(err) => println!("Error: {}", err),
+    }
+    if let Some(keys) = keys {
+        keys.stop();
+    }
+    #[cfg(feature = "gpio")]
+    {
+        for listener in listeners {
+            listener.stop();
+        }
+        if let Some(pots) = pots {
+            pots.stop();
+        }
+    }
+    // nothing can start a note any more, let the sound die away before the output goes
+    lock(&synth).fade_out_all();
+    thread::sleep(Duration::from_millis(SHUTDOWN_FADE_MS));
+    if let Some(path) = &setup.state_path {
+        match lock(&synth).save_state(path) {
+            Ok(()) => println!("State saved to {}", path),
+            Err(err) => println!("Error saving state to {}: {}", path, err),
+        }
+    }
 }
 
-#[derive(Clone, Debug)]
-struct Voice {
-    freq: Arc<Mutex<f32>>,
-    wave_type: WaveType,
-    amp_env: Adsr,
-    sink_idx: usize,
-    releasing: Arc<Mutex<bool>>,
+// What a panel button does when pressed
+#[derive(Debug, Clone)]
+enum PanelAction {
+    SetWave(WaveType),
+    // pick the envelope parameter the up/down buttons move: 0 attack, 1 decay, 2 sustain,
+    // 3 release
+    SetEnvTarget(u8),
+    // move the picked envelope parameter by this many steps, negative is down
+    AdjustEnv(i64),
+    // shift the keyboard by this many semitones, negative is down
+    Transpose(i8),
+    // switch every channel's envelope between linear and exponential
+    ToggleEnvCurve,
+    // latch on, or off again letting go of the latched notes
+    ToggleLatch,
+    // save the sound now rather than on exit, the Pi may just be unplugged
+    SaveState,
+    // step through the velocity curves
+    CycleVelocityCurve,
+    // arpeggiator on or off
+    ToggleArp,
+    // chorus in at its default mix, or out
+    ToggleChorus,
+    // bitcrusher in or out, keeping its settings
+    ToggleCrusher,
+    // waveshaper in or out, keeping its settings
+    ToggleShaper,
+    // every channel's second oscillator on or off, keeping its settings
+    ToggleOsc2,
+    // fade the output out or back in
+    ToggleMute,
 }
 
-const INIT_SINK: Option<Sink> = None;
-const MAX_POLYPHONY: usize = 16;
-static mut SINKS: [Option<Sink>; MAX_POLYPHONY] = [INIT_SINK; MAX_POLYPHONY];
+impl FromStr for PanelAction {
+    type Err = String;
 
-// Safe wrapper to get an immutable reference to a sink
-fn get_sink(sink_idx: usize) -> &'static Sink {
-    unsafe { SINKS[sink_idx].as_ref().unwrap() }
+    fn from_str(s: &str) -> Result<Self, String> {
+        let bad = || format!("bad panel action {:?}", s);
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        match fields[..] {
+            ["wave", wave] => Ok(PanelAction::SetWave(match wave {
+                "sine" => WaveType::Sine,
+                "triangle" => WaveType::Triangle,
+                "square" => WaveType::Square,
+                "saw" => WaveType::Saw,
+                // white noise, CC24 darkens it toward brown
+                "noise" => WaveType::Noise { color: 0.0 },
+                "pulse" => WaveType::Pulse {
+                    width: DEFAULT_PULSE_WIDTH,
+                },
+                _ => return Err(format!("unknown wave {:?}", wave)),
+            })),
+            ["env", target] => Ok(PanelAction::SetEnvTarget(match target {
+                "attack" => 0,
+                "decay" => 1,
+                "sustain" => 2,
+                "release" => 3,
+                _ => return Err(format!("unknown envelope parameter {:?}", target)),
+            })),
+            ["env_up"] => Ok(PanelAction::AdjustEnv(1)),
+            ["env_down"] => Ok(PanelAction::AdjustEnv(-1)),
+            ["env_up", steps] => Ok(PanelAction::AdjustEnv(steps.parse().map_err(|_| bad())?)),
+            ["env_down", steps] => Ok(PanelAction::AdjustEnv(
+                -steps.parse::<i64>().map_err(|_| bad())?,
+            )),
+            ["octave_up"] => Ok(PanelAction::Transpose(12)),
+            ["octave_down"] => Ok(PanelAction::Transpose(-12)),
+            ["transpose", semitones] => Ok(PanelAction::Transpose(
+                semitones.parse().map_err(|_| bad())?,
+            )),
+            ["env_curve"] => Ok(PanelAction::ToggleEnvCurve),
+            ["latch"] => Ok(PanelAction::ToggleLatch),
+            ["save"] => Ok(PanelAction::SaveState),
+            ["velocity_curve"] => Ok(PanelAction::CycleVelocityCurve),
+            ["arp"] => Ok(PanelAction::ToggleArp),
+            ["chorus"] => Ok(PanelAction::ToggleChorus),
+            ["crusher"] => Ok(PanelAction::ToggleCrusher),
+            ["drive"] => Ok(PanelAction::ToggleShaper),
+            ["osc2"] => Ok(PanelAction::ToggleOsc2),
+            ["mute"] => Ok(PanelAction::ToggleMute),
+            _ => Err(bad()),
+        }
+    }
 }
 
-impl Voice {
-    fn new(freq: f32, wave_type: WaveType, amp_env: Adsr, sink_idx: usize) -> Self {
-        Self {
-            freq: Arc::new(Mutex::new(freq)),
-            wave_type,
-            amp_env,
-            sink_idx,
-            releasing: Arc::new(Mutex::new(false)),
-        }
-    }
-
-    fn play(&self) {
-        let wave = Wave::new(*self.freq.lock().unwrap(), self.wave_type);
-
-        let sink = get_sink(self.sink_idx);
-
-        let attack = self.amp_env.attack;
-        let decay = self.amp_env.decay;
-        let sustain = self.amp_env.sustain;
-        let release = self.amp_env.release;
-
-        let mut volume = 0.0f32;
-        let mut num_sample_released = 0usize;
-
-        const SAMPLE_RATE_MS: usize = SAMPLE_RATE / 1000;
-
-        let attack_num_samples = attack * SAMPLE_RATE_MS;
-        let decay_num_samples = decay * SAMPLE_RATE_MS;
-        let release_num_samples = release * SAMPLE_RATE_MS;
-
-        let attack_step = 1.0 / attack_num_samples as f32;
-        let decay_step = (1.0 - sustain) / decay_num_samples as f32;
-        let release_step = sustain / release_num_samples as f32;
-
-        let freq = self.freq.clone();
-        let releasing = self.releasing.clone();
-        sink.append(
-            wave.amplify(volume)
-                .stoppable()
-                .periodic_access(Duration::from_millis(1), move |src| {
-                    if *releasing.lock().unwrap() && num_sample_released == 0 {
-                        num_sample_released = src.inner().inner().num_sample;
-                        dbg!(num_sample_released);
-                    } else if *releasing.lock().unwrap() {
-                        let num_sample = src.inner().inner().num_sample - num_sample_released;
-                        if num_sample < release_num_samples {
-                            volume -= release_step;
-                        } else {
-                            src.stop();
-                            dbg!("stopping!");
-                        }
-                    } else if src.inner().inner().num_sample < attack_num_samples {
-                        volume += attack_step;
-                    } else if (src.inner().inner().num_sample - attack_num_samples)
-                        < decay_num_samples
-                    {
-                        volume -= decay_step;
-                    }
-
-                    src.inner_mut().set_factor(volume)
-                })
-                .periodic_access(Duration::from_nanos(50), move |src| {
-                    // reset the frequency (used for pitch bend)
-                    let target_freq = *freq.lock().unwrap();
-                    let current_freq = &mut src.inner_mut().inner_mut().inner_mut().freq;
-                    if *current_freq != target_freq {
-                        if *current_freq > target_freq {
-                            *current_freq -= 1.0;
-                        } else {
-                            *current_freq += 1.0;
-                        }
-                    }
-                }),
-        );
+// The panel as wired on the original board, used when there is no pin file
+#[cfg(feature = "gpio")]
+fn default_panel() -> HashMap<u8, PanelAction> {
+    HashMap::from([
+        (17, PanelAction::SetWave(WaveType::Sine)),
+        (27, PanelAction::SetWave(WaveType::Triangle)),
+        (22, PanelAction::SetWave(WaveType::Square)),
+        (5, PanelAction::SetWave(WaveType::Saw)),
+        (13, PanelAction::SetWave(WaveType::Noise { color: 0.0 })),
+        (6, PanelAction::SetEnvTarget(0)),
+        (26, PanelAction::SetEnvTarget(1)),
+        (23, PanelAction::SetEnvTarget(2)),
+        (24, PanelAction::SetEnvTarget(3)),
+        (25, PanelAction::AdjustEnv(1)),
+        (16, PanelAction::AdjustEnv(-1)),
+        (4, PanelAction::ToggleEnvCurve),
+        (12, PanelAction::ToggleLatch),
+        (19, PanelAction::SaveState),
+        (20, PanelAction::CycleVelocityCurve),
+        (21, PanelAction::ToggleArp),
+        (18, PanelAction::ToggleChorus),
+        (7, PanelAction::ToggleCrusher),
+        (14, PanelAction::ToggleShaper),
+        (15, PanelAction::ToggleOsc2),
+        // on the I2C pins, which the board leaves free
+        (2, PanelAction::ToggleMute),
+    ])
+}
 
-        sink.play();
+// Load a panel layout: one `<BCM pin> <action>` per line, e.g. `17 wave sine`, `6 env attack`,
+// `25 env_up`, `19 save` or `5 octave_down`. `#` starts a comment. Each pin can only do one
+// thing.
+#[cfg(feature = "gpio")]
+fn load_panel<P: AsRef<Path>>(path: P) -> Result<HashMap<u8, PanelAction>, Box<dyn Error>> {
+    let mut panel = HashMap::new();
+    for line in fs::read_to_string(path)?.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (pin, action) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| format!("bad panel line {:?}", line))?;
+        let pin: u8 = pin.parse()?;
+        if pin > MAX_GPIO_PIN {
+            return Err(format!("pin {} is not on the GPIO header", pin).into());
+        }
+        if panel.insert(pin, action.parse()?).is_some() {
+            return Err(format!("pin {} is mapped more than once", pin).into());
+        }
     }
+    Ok(panel)
+}
 
-    fn stop(&self) {
-        let mut releasing_lock = self.releasing.lock().unwrap();
-        *releasing_lock = true;
+// Carry out a panel action, from a button or from the key standing in for it
+fn panel_action(synth: &mut Synth, action: &PanelAction, setup: &PanelSetup) {
+    match action {
+        PanelAction::SetWave(wave_type) => synth.set_wave(wave_type.clone()),
+        PanelAction::SetEnvTarget(env_type) => *lock(&ENV_TYPE) = *env_type,
+        PanelAction::AdjustEnv(steps) => {
+            let env_type = *lock(&ENV_TYPE);
+            let adjust = setup.env_adjust;
+            // the panel edits every channel's envelope together
+            let mut adsr = synth.patches[0].adsr;
+            match env_type {
+                0 | 1 | 3 => {
+                    let diff = adjust.step_ms as i64 * steps;
+                    let affected = match env_type {
+                        0 => &mut adsr.attack,
+                        1 => &mut adsr.decay,
+                        3 => &mut adsr.release,
+                        _ => unreachable!(),
+                    };
+                    *affected = (*affected as i64 + diff)
+                        .clamp(adjust.min_ms as i64, adjust.max_ms as i64)
+                        as usize;
+                }
+                2 => {
+                    let diff = adjust.sustain_step * *steps as f32;
+                    adsr.sustain = (adsr.sustain + diff).clamp(0.0, 1.0);
+                }
+                _ => {}
+            }
+            synth.set_adsr(adsr);
+        }
+        PanelAction::Transpose(semitones) => {
+            synth.set_transpose(synth.transpose().saturating_add(*semitones));
+            println!("Transpose {:+}", synth.transpose());
+        }
+        PanelAction::ToggleEnvCurve => {
+            let mut adsr = synth.patches[0].adsr;
+            adsr.curve = match adsr.curve {
+                EnvCurve::Linear => EnvCurve::Exponential,
+                EnvCurve::Exponential => EnvCurve::Linear,
+            };
+            synth.set_adsr(adsr);
+            println!("Envelope curve {:?}", adsr.curve);
+        }
+        PanelAction::ToggleLatch => {
+            let latch = !synth.latch();
+            synth.set_latch(latch);
+            println!("Latch {}", if latch { "on" } else { "off" });
+        }
+        PanelAction::SaveState => {
+            match &setup.state_path {
+                Some(path) => match synth.save_state(path) {
+                    Ok(()) => println!("State saved to {}", path),
+                    Err(err) => println!("Error saving state to {}: {}", path, err),
+                },
+                None => println!("No state file to save to (--no-state)"),
+            }
+            match &setup.patch_path {
+                Some(path) => match synth.save_patch(path) {
+                    Ok(()) => println!("Patch saved to {}", path),
+                    Err(err) => println!("Error saving patch to {}: {}", path, err),
+                },
+                None => println!("No patch file to save to (--no-patch)"),
+            }
+        }
+        PanelAction::CycleVelocityCurve => {
+            synth.velocity_curve = synth.velocity_curve.next();
+            println!("Velocity curve {:?}", synth.velocity_curve);
+        }
+        PanelAction::ToggleArp => {
+            let arp = !synth.arp();
+            synth.set_arp(arp);
+            println!("Arpeggiator {}", if arp { "on" } else { "off" });
+        }
+        PanelAction::ToggleChorus => {
+            let on = synth.chorus.mix == 0.0;
+            synth.chorus.mix = if on { DEFAULT_CHORUS_MIX } else { 0.0 };
+            println!("Chorus {}", if on { "on" } else { "off" });
+        }
+        PanelAction::ToggleShaper => {
+            synth.shaper.on = !synth.shaper.on;
+            println!("Drive {}", if synth.shaper.on { "on" } else { "off" });
+        }
+        PanelAction::ToggleOsc2 => {
+            let on = !synth.patches[0].osc2.on;
+            for patch in synth.patches.iter_mut() {
+                patch.osc2.on = on;
+            }
+            println!("Second oscillator {}", if on { "on" } else { "off" });
+        }
+        PanelAction::ToggleCrusher => {
+            synth.crusher.on = !synth.crusher.on;
+            println!("Bitcrusher {}", if synth.crusher.on { "on" } else { "off" });
+        }
+        PanelAction::ToggleMute => {
+            let muted = !synth.muted();
+            synth.set_muted(muted);
+            println!("{}", if muted { "Muted" } else { "Unmuted" });
+        }
     }
 }
 
-static PINS: [u8; 10] = [17, 27, 22, 5, 6, 26, 23, 24, 25, 16];
-
-
-lazy_static! {
-    static ref WAVE_TYPE: Mutex<WaveType> = Mutex::new(WaveType::Triangle);
-    static ref ENV_TYPE: Mutex<u8> = Mutex::new(0);
-    static ref ADSR: Mutex<Adsr> = Mutex::new(Adsr{attack:10, decay:10, sustain:1.0, release:10});
+// Computer keys standing in for the panel buttons with --keys: 1-6 pick the wave, a/d/s/r the
+// envelope parameter and +/- move it, z/x shift the keyboard an octave down/up, c toggles the
+// envelope curve, l the latch, p the arpeggiator, u the second oscillator, o the overdrive, b
+// the bitcrusher, h the chorus and m mutes, v steps through the velocity curves and w saves the
+// state
+fn key_action(key: char) -> Option<PanelAction> {
+    Some(match key {
+        '1' => PanelAction::SetWave(WaveType::Sine),
+        '2' => PanelAction::SetWave(WaveType::Triangle),
+        '3' => PanelAction::SetWave(WaveType::Square),
+        '4' => PanelAction::SetWave(WaveType::Saw),
+        '5' => PanelAction::SetWave(WaveType::Noise { color: 0.0 }),
+        '6' => PanelAction::SetWave(WaveType::Pulse {
+            width: DEFAULT_PULSE_WIDTH,
+        }),
+        'a' => PanelAction::SetEnvTarget(0),
+        'd' => PanelAction::SetEnvTarget(1),
+        's' => PanelAction::SetEnvTarget(2),
+        'r' => PanelAction::SetEnvTarget(3),
+        // = is + without shift
+        '+' | '=' => PanelAction::AdjustEnv(1),
+        '-' => PanelAction::AdjustEnv(-1),
+        'z' => PanelAction::Transpose(-12),
+        'x' => PanelAction::Transpose(12),
+        'c' => PanelAction::ToggleEnvCurve,
+        'l' => PanelAction::ToggleLatch,
+        'p' => PanelAction::ToggleArp,
+        'h' => PanelAction::ToggleChorus,
+        'b' => PanelAction::ToggleCrusher,
+        'o' => PanelAction::ToggleShaper,
+        'u' => PanelAction::ToggleOsc2,
+        'm' => PanelAction::ToggleMute,
+        'v' => PanelAction::CycleVelocityCurve,
+        'w' => PanelAction::SaveState,
+        _ => return None,
+    })
 }
 
-fn main() {
-    for pin in PINS {
-        let _listener = EventListener::new_rising(
+// Listen to every button of the panel, skipping the ones whose pin can't be opened or is
+// taken by the pots' ADC
+#[cfg(feature = "gpio")]
+fn start_panel(
+    synth: &Arc<Mutex<Synth>>,
+    panel: HashMap<u8, PanelAction>,
+    pots: bool,
+    setup: &PanelSetup,
+) -> Vec<EventListener> {
+    let mut listeners = Vec::new();
+    for (pin, action) in panel {
+        if pots && SPI0_PINS.contains(&pin) {
+            println!("Panel button disabled: pin {} is on the pots' SPI bus", pin);
+            continue;
+        }
+        let synth = synth.clone();
+        let setup = setup.clone();
+        let listener = EventListener::new_rising(
             pin,
             move || {
-                match pin {
-                    17 => *WAVE_TYPE.lock().unwrap() = WaveType::Sine,
-                    27 => *WAVE_TYPE.lock().unwrap() = WaveType::Triangle,
-                    22 => *WAVE_TYPE.lock().unwrap() = WaveType::Square,
-                    5 => *WAVE_TYPE.lock().unwrap() = WaveType::Saw,
-                    6 => *ENV_TYPE.lock().unwrap() = 0,
-                    26 => *ENV_TYPE.lock().unwrap() = 1,
-                    23 => *ENV_TYPE.lock().unwrap() = 2,
-                    24 => *ENV_TYPE.lock().unwrap() = 3,
-                    25 | 16 => {
-                        let env_type = *ENV_TYPE.lock().unwrap();
-                        if env_type == 0 || env_type == 1 || env_type ==3{
-                            let diff: i64 = if pin == 25 {10} else {-10};
-                            let mut adsr = *ADSR.lock().unwrap();
-                            let affected = match env_type {
-                                0 => &mut adsr.attack,
-                                1 => &mut adsr.decay,
-                                3 => &mut adsr.release,
-                                _ => unreachable!(),
-                            };
-                            if *affected >= 10 && *affected <= 990 {
-                                *affected = (*affected as i64 + diff) as usize;
-                            }
-                        }
-                    } 
-                    _ => {}
-                };
-                println!("Triggerd {}", pin);
+                panel_action(&mut lock(&synth), &action, &setup);
             },
-            0,
+            BUTTON_DEBOUNCE_MS,
         );
+        // the synth stays playable over MIDI without its front panel
+        match listener {
+            Ok(listener) => listeners.push(listener),
+            Err(err) => println!("Panel button disabled: {}", err),
+        }
     }
-    match run() {
-        Ok(_) => (),
-        Err(err) => println!("Error: {}", err),
-    }
+    listeners
 }
 
-fn midi_note_to_freq(midi_note: u8) -> f32 {
-    2f32.powf((midi_note as f32 - 69.0) / 12.0) * 440.0
+// Reference tone played by --selftest
+const SELFTEST_FREQ: f32 = 440.0;
+const SELFTEST_SECONDS: usize = 1;
+const SELFTEST_LEVEL: f32 = 0.5;
+
+// Play the reference tone straight from an oscillator through a sink on the default output,
+// to check the audio path of an install without any MIDI gear
+fn selftest() -> Result<(), Box<dyn Error>> {
+    println!(
+        "Self-test: playing {} Hz for {} s",
+        SELFTEST_FREQ, SELFTEST_SECONDS
+    );
+    let (_stream, stream_handle, _) = open_stream()?;
+    let sink = Sink::try_new(&stream_handle)?;
+
+    let tone: Vec<f32> = Wave::new(SELFTEST_FREQ, WaveType::Sine)
+        .take(sample_rate() as usize * SELFTEST_SECONDS)
+        .map(|sample| sample * SELFTEST_LEVEL)
+        .collect();
+    if tone.iter().all(|&sample| sample == 0.0) {
+        return Err("oscillator produced silence".into());
+    }
+    sink.append(SamplesBuffer::new(1, sample_rate(), tone));
+    sink.sleep_until_end();
+    Ok(())
 }
 
-fn midi_callback(
-    message: &[u8],
-    playing_notes: Arc<Mutex<HashMap<u8, Voice>>>,
-    sustained_notes: Arc<Mutex<HashSet<u8>>>,
-    stream_handle: Arc<Mutex<OutputStreamHandle>>,
-) {
-    let playing_notes = &mut *playing_notes.lock().unwrap();
-    let sustained_notes = &mut *sustained_notes.lock().unwrap();
-    let stream_handle = &*stream_handle.lock().unwrap();
+// Number of samples rendered per lock of the synth
+const BLOCK_SIZE: usize = 64;
 
-    let status = message[0];
-    let data1 = message[1];
+// Size and refresh rate of the terminal spectrum view
+const SPECTRUM_COLUMNS: usize = 80;
+const SPECTRUM_ROWS: usize = 20;
+const SPECTRUM_REFRESH: Duration = Duration::from_millis(100);
+// Width and refresh rate (~30 Hz) of the level meter, and how long a clip stays lit
+const METER_WIDTH: usize = 50;
+const METER_REFRESH: Duration = Duration::from_millis(33);
+const METER_CLIP_HOLD: usize = 30;
 
-    match status {
-        // note on
-        144..=159 => {
-            if let Some(existing_voice) = playing_notes.get(&data1) {
-                existing_voice.play();
-            } else {
-                let sink_idx = {
-                    let mut found_idx = None;
-                    for i in 0..MAX_POLYPHONY {
-                        if get_sink(i).empty() {
-                            found_idx = Some(i);
-                            break;
-                        }
-                    }
+// How often the audio thread checks that the output is still pulling samples, how long it may
+// stall before the output is reopened, and the backoff between failed reopen attempts
+const AUDIO_WATCHDOG_INTERVAL: Duration = Duration::from_millis(100);
+const AUDIO_STALL_TIMEOUT: Duration = Duration::from_millis(500);
+const AUDIO_RETRY_MIN: Duration = Duration::from_millis(250);
+const AUDIO_RETRY_MAX: Duration = Duration::from_secs(5);
 
-                    if found_idx.is_none() {
-                        for i in 0..MAX_POLYPHONY {
-                            if get_sink(i).is_paused() {
-                                get_sink(i).stop();
-                                unsafe { SINKS[i] = Some(Sink::try_new(&stream_handle).unwrap()) };
-                                found_idx = Some(i);
-                                break;
-                            }
-                        }
-                    }
+// Where the rendered output goes besides the speakers
+#[derive(Clone, Default)]
+struct OutputTaps {
+    // copy of the output for the spectrum view
+    spectrum: Option<Arc<Mutex<SpectrumTap>>>,
+    meter: Option<Arc<Mutex<LevelMeter>>>,
+    // count of rendered blocks, shows the audio watchdog the output is alive
+    rendered: Arc<AtomicUsize>,
+}
 
-                    found_idx
-                };
-                if let Some(sink_idx) = sink_idx {
-                    let freq = midi_note_to_freq(data1);
-                    let note = Voice::new(
-                        freq,
-                        *WAVE_TYPE.lock().unwrap(),
-                        *ADSR.lock().unwrap(),
-                        sink_idx,
-                    );
-                    note.play();
-                    playing_notes.insert(data1, note);
-                } else {
-                    dbg!("max polyphony hit");
-                }
-            }
+// Feeds the synth's mix to rodio, rendering a small block at a time. Stereo (interleaved)
+// unless the output only has one channel.
+struct SynthSource {
+    synth: Arc<Mutex<Synth>>,
+    channels: u16,
+    block: [f32; BLOCK_SIZE],
+    // mono fold-down of a stereo block for the taps
+    mono: [f32; BLOCK_SIZE],
+    pos: usize,
+    taps: OutputTaps,
+}
+
+impl SynthSource {
+    fn new(synth: Arc<Mutex<Synth>>, taps: OutputTaps, channels: u16) -> Self {
+        Self {
+            synth,
+            channels,
+            block: [0.0; BLOCK_SIZE],
+            mono: [0.0; BLOCK_SIZE],
+            pos: BLOCK_SIZE,
+            taps,
         }
-        // note off
-        128..=143 => {
-            let note = playing_notes.get(&data1);
-            if let Some(note) = note {
-                if !sustained_notes.contains(&data1) {
-                    note.stop();
-                    playing_notes.remove(&data1);
+    }
+}
+
+impl Iterator for SynthSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.pos == BLOCK_SIZE {
+            let mono = if self.channels == 2 {
+                lock(&self.synth).render_stereo(&mut self.block);
+                let frames = BLOCK_SIZE / 2;
+                for (sample, frame) in self.mono.iter_mut().zip(self.block.chunks_exact(2)) {
+                    *sample = (frame[0] + frame[1]) * std::f32::consts::FRAC_1_SQRT_2;
                 }
+                &self.mono[..frames]
+            } else {
+                lock(&self.synth).render(&mut self.block);
+                &self.block[..]
+            };
+            self.pos = 0;
+            self.taps.rendered.fetch_add(1, Ordering::Relaxed);
+            // never wait on the display thread, a skipped block only thins the view
+            if let Some(Ok(mut tap)) = self.taps.spectrum.as_ref().map(|tap| tap.try_lock()) {
+                tap.push(mono);
+            }
+            if let Some(Ok(mut meter)) = self.taps.meter.as_ref().map(|meter| meter.try_lock()) {
+                meter.push(mono);
             }
         }
-        // mode change
-        176..=191 => {
-            println!("{:?} (len = {})", message, message.len());
-            // sus
-            if data1 == 64 {
-                let data2 = message[2];
-                match data2 {
-                    127 => {
-                        for (note_midi, note) in playing_notes.iter() {
-                            if !get_sink(note.sink_idx).is_paused() {
-                                sustained_notes.insert(*note_midi);
-                            }
-                        }
-                    }
-                    0 => {
-                        for note_midi in sustained_notes.iter() {
-                            let note = playing_notes.get(note_midi).unwrap();
-                            note.stop();
-                        }
+        let sample = self.block[self.pos];
+        self.pos += 1;
+        Some(sample)
+    }
+}
 
-                        sustained_notes.clear();
-                    }
-                    _ => unreachable!(),
-                }
-            }
+impl Source for SynthSource {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+// Redraw the spectrum of the tapped output until the program exits
+fn spawn_spectrum_view(tap: Arc<Mutex<SpectrumTap>>) {
+    thread::spawn(move || {
+        let mut analyzer = SpectrumAnalyzer::new();
+        loop {
+            thread::sleep(SPECTRUM_REFRESH);
+            let samples = lock(&tap).snapshot();
+            print!(
+                "{}",
+                analyzer.render(&samples, SPECTRUM_COLUMNS, SPECTRUM_ROWS)
+            );
         }
-        // pitch bend
-        224..=239 => {
-            let bend_factor = message[2]; // 0-127 (64 means no bend)
-            for (midi_note, playing_voice) in playing_notes.iter_mut() {
-                *playing_voice.freq.lock().unwrap() =
-                    midi_note_to_freq(*midi_note) + (bend_factor as f32 - 64.0);
+    });
+}
+
+// Redraw the output level meter until the program exits
+fn spawn_level_meter(meter: Arc<Mutex<LevelMeter>>) {
+    thread::spawn(move || {
+        let mut clip_frames = 0;
+        loop {
+            thread::sleep(METER_REFRESH);
+            let level = lock(&meter).take();
+            if level.clipped {
+                clip_frames = METER_CLIP_HOLD;
             }
+            clip_frames = clip_frames.saturating_sub(1);
+            print!(
+                "{}",
+                level.render(METER_WIDTH, level.clipped || clip_frames > 0)
+            );
+            stdout().flush().ok();
         }
-        _ => {
-            println!("{:?} (len = {})", message, message.len());
-        }
+    });
+}
+
+// Print the MIDI input ports with the numbers --midi takes
+fn list_midi_ports() -> Result<(), SynthError> {
+    let midi_in = MidiInput::new("midir listing inputs")
+        .map_err(|err| SynthError::Midi(format!("opening MIDI input: {}", err)))?;
+    let ports = midi_in.ports();
+    if ports.is_empty() {
+        println!("No MIDI input ports");
+    }
+    for (i, port) in ports.iter().enumerate() {
+        let name = midi_in
+            .port_name(port)
+            .map_err(|err| SynthError::Midi(format!("naming MIDI input {}: {}", i, err)))?;
+        println!("{}: {}", i, name);
     }
+    Ok(())
 }
 
-fn run() -> Result<(), Box<dyn Error>> {
-    let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-    for i in 0..MAX_POLYPHONY {
-        unsafe {
-            SINKS[i] = Some(Sink::try_new(&stream_handle).unwrap());
+// Open the MIDI thru port, carrying on without thru if it isn't there
+fn connect_thru(name: &str) -> Option<MidiOutputConnection> {
+    let midi_out = match MidiOutput::new("midir thru output") {
+        Ok(midi_out) => midi_out,
+        Err(err) => {
+            println!("MIDI thru disabled: {}", err);
+            return None;
+        }
+    };
+    let port = midi_out.ports().into_iter().find(|port| {
+        midi_out
+            .port_name(port)
+            .map(|port_name| port_name.contains(name))
+            .unwrap_or(false)
+    });
+    match port {
+        Some(port) => match midi_out.connect(&port, "midir-thru") {
+            Ok(conn) => Some(conn),
+            Err(err) => {
+                println!("MIDI thru disabled: {}", err);
+                None
+            }
+        },
+        None => {
+            println!("MIDI thru disabled: no output port matching {:?}", name);
+            None
         }
     }
+}
 
-    let mut input = String::new();
+// Open the default output device for the engine's sample rate. Fails if the device can't run
+// at that rate. rodio opens the device in its default configuration and converts the synth's
+// rate and channels to it, so nothing is resampled when that already is the engine's rate.
+// Also returns the channel count to play in: 2, or 1 if the device is mono.
+fn open_stream() -> Result<(OutputStream, OutputStreamHandle, u16), SynthError> {
+    let device = cpal::default_host()
+        .default_output_device()
+        .ok_or_else(|| SynthError::Audio("no default output device".to_string()))?;
+    let rate = sample_rate();
+    let supported = device
+        .supported_output_configs()
+        .map_err(|err| SynthError::Audio(format!("querying the default output: {}", err)))?
+        .any(|config| (config.min_sample_rate().0..=config.max_sample_rate().0).contains(&rate));
+    if !supported {
+        return Err(SynthError::Audio(format!(
+            "the default output doesn't support {} Hz",
+            rate
+        )));
+    }
+    let channels = device
+        .default_output_config()
+        .map_err(|err| SynthError::Audio(format!("querying the default output: {}", err)))?
+        .channels()
+        .min(2);
+    let (stream, stream_handle) = OutputStream::try_from_device(&device)
+        .map_err(|err| SynthError::Audio(format!("opening the default output: {}", err)))?;
+    Ok((stream, stream_handle, channels))
+}
 
-    let mut all_midi_in = MidiInput::new("midir reading input")?;
+fn open_output(
+    synth: Arc<Mutex<Synth>>,
+    taps: OutputTaps,
+) -> Result<(OutputStream, Sink), SynthError> {
+    let (stream, stream_handle, channels) = open_stream()?;
+    let sink = Sink::try_new(&stream_handle)
+        .map_err(|err| SynthError::Audio(format!("creating the sink: {}", err)))?;
+    sink.append(SynthSource::new(synth, taps, channels));
+    sink.play();
+    Ok((stream, sink))
+}
+
+// Play the synth from a thread of its own that reopens the output whenever it stops pulling
+// samples, e.g. when a USB DAC drops out. The synth itself lives on, so held notes keep
+// sounding once the output is back. Returns once the first open has succeeded or failed.
+fn spawn_audio(synth: Arc<Mutex<Synth>>, taps: OutputTaps) -> Result<(), SynthError> {
+    let (ready_tx, ready_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut ready = Some(ready_tx);
+        let mut retry = AUDIO_RETRY_MIN;
+        loop {
+            let output = match open_output(synth.clone(), taps.clone()) {
+                Ok(output) => output,
+                Err(err) => {
+                    // nothing to come back to if the very first open fails
+                    if let Some(ready) = ready.take() {
+                        ready.send(Err(err)).ok();
+                        return;
+                    }
+                    println!("Audio reconnect failed: {}, retrying in {:?}", err, retry);
+                    thread::sleep(retry);
+                    retry = (retry * 2).min(AUDIO_RETRY_MAX);
+                    continue;
+                }
+            };
+            match ready.take() {
+                Some(ready) => {
+                    ready.send(Ok(())).ok();
+                }
+                None => println!("Audio output reconnected"),
+            }
+            retry = AUDIO_RETRY_MIN;
+
+            // watch the output until it stops asking for samples
+            let mut last = taps.rendered.load(Ordering::Relaxed);
+            let mut stalled = Duration::ZERO;
+            while stalled < AUDIO_STALL_TIMEOUT {
+                thread::sleep(AUDIO_WATCHDOG_INTERVAL);
+                let rendered = taps.rendered.load(Ordering::Relaxed);
+                if rendered == last {
+                    stalled += AUDIO_WATCHDOG_INTERVAL;
+                } else {
+                    stalled = Duration::ZERO;
+                    last = rendered;
+                }
+            }
+            println!("Audio output stalled, reconnecting");
+            drop(output);
+        }
+    });
+    ready_rx
+        .recv()
+        .unwrap_or_else(|_| Err(SynthError::Audio("audio thread exited".into())))
+}
+
+fn run(
+    synth: Arc<Mutex<Synth>>,
+    args: Args,
+    keys: Option<&KeyboardControl>,
+) -> Result<(), Box<dyn Error>> {
+    let spectrum = args.spectrum.then(|| {
+        let tap = Arc::new(Mutex::new(SpectrumTap::default()));
+        spawn_spectrum_view(tap.clone());
+        tap
+    });
+    let meter = args.meter.then(|| {
+        let meter = Arc::new(Mutex::new(LevelMeter::default()));
+        spawn_level_meter(meter.clone());
+        meter
+    });
+    let taps = OutputTaps {
+        spectrum,
+        meter,
+        ..OutputTaps::default()
+    };
+    spawn_audio(synth.clone(), taps)?;
+
+    let mut all_midi_in = MidiInput::new("midir reading input")
+        .map_err(|err| SynthError::Midi(format!("opening MIDI input: {}", err)))?;
     all_midi_in.ignore(Ignore::None);
 
-    // Get an input port (read from console if multiple are available)
-    let in_ports = all_midi_in.ports();
-    if in_ports.len() == 0 {
-        return Err("no input port found".into());
+    // list the ports once, every connection below is made to one of these
+    let in_ports: Vec<(MidiInputPort, String)> = all_midi_in
+        .ports()
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, port)| {
+            let name = all_midi_in.port_name(&port).unwrap_or_default();
+            let picked = args.ports.is_empty()
+                || args
+                    .ports
+                    .iter()
+                    .any(|wanted| wanted.parse() == Ok(i) || name.contains(wanted.as_str()));
+            picked.then_some((port, name))
+        })
+        .collect();
+    if in_ports.is_empty() && args.play.is_none() {
+        return Err(if args.ports.is_empty() {
+            "no input port found".into()
+        } else {
+            format!("no input port matching {:?} (see --list-midi)", args.ports).into()
+        });
     }
 
-    let stream_handle = Arc::new(Mutex::new(stream_handle));
-    let playing_notes = Arc::new(Mutex::new(HashMap::<u8, Voice>::new()));
-    let sustained_notes = Arc::new(Mutex::new(HashSet::<u8>::new()));
+    let recorder = args
+        .record
+        .as_ref()
+        .map(|_| Arc::new(Mutex::new(MidiRecorder::new())));
+
+    if let Some(path) = args.play {
+        let synth = synth.clone();
+        let looping = args.loop_play;
+        thread::spawn(move || {
+            if let Err(err) = play_midi_file(&synth, &path, looping) {
+                println!("Error playing {}: {}", path, err);
+            }
+        });
+    }
+
+    let thru = args
+        .thru
+        .as_deref()
+        .and_then(connect_thru)
+        .map(|conn| Arc::new(Mutex::new(conn)));
+    let thru_filter = args.thru_filter;
 
     let mut conns = Vec::new();
-    for i in 0..in_ports.len() {
-        let mut midi_in = MidiInput::new(&format!("midir reading input {}", i))?;
+    for (i, (port, port_name)) in in_ports.into_iter().enumerate() {
+        let mut midi_in = MidiInput::new(&format!("midir reading input {}", i))
+            .map_err(|err| SynthError::Midi(format!("opening MIDI input {}: {}", i, err)))?;
         midi_in.ignore(Ignore::None);
 
-        let stream_handle_con = stream_handle.clone();
-        let playing_notes_con = playing_notes.clone();
-        let sustained_notes_con = sustained_notes.clone();
+        let synth_con = synth.clone();
+        let recorder_con = recorder.clone();
+        let thru_con = thru.clone();
 
-        let port = &midi_in.ports()[i];
+        println!("Listening to {}", port_name);
         let conn = midi_in.connect(
-            port,
+            &port,
             &format!("midir-read-input-{}", i),
             move |_, message, _| {
-                midi_callback(
-                    message,
-                    playing_notes_con.clone(),
-                    sustained_notes_con.clone(),
-                    stream_handle_con.clone(),
-                )
+                if let Some(recorder) = &recorder_con {
+                    lock(recorder).record(message);
+                }
+                if let Some(thru) = &thru_con {
+                    if thru_filter.passes(message) {
+                        if let Err(err) = lock(thru).send(message) {
+                            println!("MIDI thru error: {}", err);
+                        }
+                    }
+                }
+                lock(&synth_con).handle_midi(message)
             },
             (),
-        )?;
-        conns.push(conn);
+        );
+        match conn {
+            Ok(conn) => conns.push(conn),
+            // keep going with the ports that do connect
+            Err(err) => println!("Error connecting to {}: {}", port_name, err),
+        }
     }
 
-    stdin().read_line(&mut input)?; // wait for next enter key press
+    match keys {
+        // the keyboard reads the terminal itself, and quits on q or enter
+        Some(keys) => keys.wait(),
+        None => {
+            let mut input = String::new();
+            stdin().read_line(&mut input)?; // wait for next enter key press
+        }
+    }
 
     println!("Closing connection");
+    if let (Some(recorder), Some(path)) = (recorder, args.record) {
+        lock(&recorder).save(&path)?;
+        println!("Recording saved to {}", path);
+    }
     Ok(())
 }
 
+// Runs a callback when a button is pressed. The pin's edge interrupt calls it from rppal's
+// interrupt thread, nothing polls. The button works until the listener is stopped.
+#[cfg(feature = "gpio")]
 struct EventListener {
-    pin: u8,
-    handle: thread::JoinHandle<()>,
-    stop: Arc<Mutex<bool>>,
+    input: InputPin,
 }
 
+#[cfg(feature = "gpio")]
 impl EventListener {
-    fn new_rising<Callback>(pin: u8, callback: Callback, bounce_time: u64) -> Self
+    fn new_rising<Callback>(
+        pin: u8,
+        callback: Callback,
+        bounce_time: u64,
+    ) -> Result<Self, SynthError>
     where
         Callback: Fn() + std::marker::Send + 'static,
     {
-        let stop = Arc::new(Mutex::new(false));
+        let mut input = Gpio::new()
+            .and_then(|gpio| gpio.get(pin))
+            .map_err(|err| SynthError::Gpio(format!("opening pin {}: {}", pin, err)))?
+            .into_input_pulldown();
+        let bounce_time = Duration::from_millis(bounce_time);
+        let mut last_press: Option<Instant> = None;
+        input
+            .set_async_interrupt(Trigger::RisingEdge, move |_| {
+                let now = Instant::now();
+                if last_press.is_some_and(|last| now.duration_since(last) < bounce_time) {
+                    return;
+                }
+                last_press = Some(now);
+                callback();
+            })
+            .map_err(|err| SynthError::Gpio(format!("watching pin {}: {}", pin, err)))?;
+        Ok(Self { input })
+    }
+
+    // Clearing the interrupt joins rppal's interrupt thread, once this returns the callback
+    // has run for the last time
+    fn stop(mut self) {
+        if let Err(err) = self.input.clear_async_interrupt() {
+            println!("Error stopping button: {}", err);
+        }
+    }
+}
+
+// Presses the panel buttons from the computer keyboard, for running without a Pi. The terminal is
+// put in raw mode so single key presses arrive, and a background thread reads them until q,
+// escape, enter or ctrl-c is pressed or the control is stopped.
+struct KeyboardControl {
+    handle: thread::JoinHandle<()>,
+    stop: Arc<AtomicBool>,
+    quit: mpsc::Receiver<()>,
+}
+
+impl KeyboardControl {
+    fn new(synth: Arc<Mutex<Synth>>, setup: PanelSetup) -> Result<Self, SynthError> {
+        enable_raw_mode()?;
+        let stop = Arc::new(AtomicBool::new(false));
         let stop_for_inner = stop.clone();
+        let (quit_tx, quit) = mpsc::channel();
         let handle = thread::spawn(move || {
-            let pin = Gpio::new().unwrap().get(pin).unwrap().into_input_pulldown();
-
-            let mut prev_value = Level::Low;
-            while !*stop_for_inner.lock().unwrap() {
-                let value = pin.read();
-                if value == Level::High && prev_value == Level::Low {
-                    callback();
-                    prev_value = Level::High;
-                    thread::sleep(Duration::from_millis(bounce_time));
-                } else if value == Level::Low && prev_value == Level::High {
-                    prev_value = Level::Low;
+            while !stop_for_inner.load(Ordering::Relaxed) {
+                match event::poll(Duration::from_millis(KEY_POLL_MS)) {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(err) => {
+                        println!("Error reading the keyboard: {}\r", err);
+                        break;
+                    }
+                }
+                let key = match event::read() {
+                    Ok(Event::Key(key)) if key.kind != KeyEventKind::Release => key,
+                    Ok(_) => continue,
+                    Err(err) => {
+                        println!("Error reading the keyboard: {}\r", err);
+                        break;
+                    }
+                };
+                match key.code {
+                    // raw mode turns ctrl-c into a key press, so it quits like q
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => break,
+                    KeyCode::Char('q') | KeyCode::Esc | KeyCode::Enter => break,
+                    KeyCode::Char(c) => {
+                        if let Some(action) = key_action(c) {
+                            panel_action(&mut lock(&synth), &action, &setup);
+                        }
+                    }
+                    _ => {}
                 }
             }
+            let _ = quit_tx.send(());
         });
-        Self { pin, handle, stop }
+        Ok(Self { handle, stop, quit })
     }
 
-    fn stop(&self) {
-        *self.stop.lock().unwrap() = true;
+    // Block until a quit key is pressed
+    fn wait(&self) {
+        let _ = self.quit.recv();
+    }
+
+    fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.handle.join();
+        if let Err(err) = disable_raw_mode() {
+            println!("Error restoring the terminal: {}", err);
+        }
+    }
+}
+
+// MCP3008 inputs the attack, decay, sustain and release pots are wired to
+#[cfg(feature = "gpio")]
+const ADSR_POT_CHANNELS: [u8; 4] = [0, 1, 2, 3];
+// MCP3008 input of the master gain pot
+#[cfg(feature = "gpio")]
+const GAIN_POT_CHANNEL: u8 = 4;
+#[cfg(feature = "gpio")]
+const POT_POLL_MS: u64 = 20;
+// ADC counts a pot has to move before it counts, so jitter doesn't keep rewriting the envelope
+#[cfg(feature = "gpio")]
+const POT_HYSTERESIS: u16 = 4;
+// range of the attack, decay and release pots
+#[cfg(feature = "gpio")]
+const MIN_POT_ENV_MS: f32 = 1.0;
+#[cfg(feature = "gpio")]
+const MAX_POT_ENV_MS: f32 = 2000.0;
+
+// Reads pots on an MCP3008 ADC over SPI: with `envelope` it sets every channel's envelope from
+// them, one pot each for attack, decay, sustain and release, and with `gain` the master gain
+// from one more. A background thread polls the ADC until stopped.
+#[cfg(feature = "gpio")]
+struct AnalogControl {
+    handle: thread::JoinHandle<()>,
+    stop: Arc<AtomicBool>,
+}
+
+#[cfg(feature = "gpio")]
+impl AnalogControl {
+    fn new(synth: Arc<Mutex<Synth>>, envelope: bool, gain: bool) -> Result<Self, SynthError> {
+        let spi = Spi::new(Bus::Spi0, SlaveSelect::Ss0, 1_000_000, Mode::Mode0)
+            .map_err(|err| SynthError::Gpio(format!("opening SPI for the ADC: {}", err)))?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_for_inner = stop.clone();
+        let handle = thread::spawn(move || {
+            // by ADC input
+            let mut last: [Option<u16>; 8] = [None; 8];
+            let mut channels = Vec::new();
+            if envelope {
+                channels.extend(ADSR_POT_CHANNELS);
+            }
+            if gain {
+                channels.push(GAIN_POT_CHANNEL);
+            }
+            while !stop_for_inner.load(Ordering::Relaxed) {
+                let mut changed = [false; 8];
+                for &channel in &channels {
+                    let value = match read_mcp3008(&spi, channel) {
+                        Ok(value) => value,
+                        Err(err) => {
+                            println!("Error reading pot {}: {}", channel, err);
+                            continue;
+                        }
+                    };
+                    let last = &mut last[channel as usize];
+                    if last.is_none_or(|last| last.abs_diff(value) >= POT_HYSTERESIS) {
+                        *last = Some(value);
+                        changed[channel as usize] = true;
+                    }
+                }
+                let pot = |i: u8| last[i as usize].map(|value| value as f32 / 1023.0);
+                if ADSR_POT_CHANNELS.iter().any(|&i| changed[i as usize]) {
+                    let mut synth = lock(&synth);
+                    let mut adsr = synth.patches[0].adsr;
+                    if let Some(attack) = pot(0) {
+                        adsr.attack = pot_to_ms(attack);
+                    }
+                    if let Some(decay) = pot(1) {
+                        adsr.decay = pot_to_ms(decay);
+                    }
+                    if let Some(sustain) = pot(2) {
+                        adsr.sustain = sustain;
+                    }
+                    if let Some(release) = pot(3) {
+                        adsr.release = pot_to_ms(release);
+                    }
+                    synth.set_adsr(adsr);
+                }
+                if changed[GAIN_POT_CHANNEL as usize] {
+                    if let Some(gain) = pot(GAIN_POT_CHANNEL) {
+                        // squared, closer to an even sweep of loudness than linear
+                        lock(&synth).master_gain = gain * gain;
+                    }
+                }
+                thread::sleep(Duration::from_millis(POT_POLL_MS));
+            }
+        });
+        Ok(Self { handle, stop })
     }
 
-    fn wait(self) {
-        self.handle.join().unwrap();
+    fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.handle.join();
     }
 }
+
+// Exponential, so the short times that need the most precision get most of the pot's travel
+#[cfg(feature = "gpio")]
+fn pot_to_ms(value: f32) -> usize {
+    let span = MAX_POT_ENV_MS / MIN_POT_ENV_MS;
+    (MIN_POT_ENV_MS * span.powf(value)).round() as usize
+}
+
+// Single-ended 10-bit conversion of one MCP3008 input (0..=7)
+#[cfg(feature = "gpio")]
+fn read_mcp3008(spi: &Spi, channel: u8) -> rppal::spi::Result<u16> {
+    let mut read = [0u8; 3];
+    spi.transfer(&mut read, &[0x01, (0x08 | channel) << 4, 0x00])?;
+    Ok(((read[1] as u16 & 0x03) << 8) | read[2] as u16)
+}
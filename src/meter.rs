@@ -0,0 +1,78 @@
+// dB range shown by the meter bar
+const METER_FLOOR_DB: f32 = -60.0;
+
+// Peak and RMS of the output since the last reading, fed by the audio thread
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LevelMeter {
+    peak: f32,
+    sum_squares: f32,
+    count: usize,
+    clipped: bool,
+}
+
+// One reading of the LevelMeter
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Level {
+    pub peak: f32,
+    pub rms: f32,
+    // a sample hit full scale
+    pub clipped: bool,
+}
+
+fn to_db(level: f32) -> f32 {
+    20.0 * level.max(1e-9).log10()
+}
+
+impl LevelMeter {
+    pub fn push(&mut self, samples: &[f32]) {
+        for &sample in samples {
+            let level = sample.abs();
+            self.peak = self.peak.max(level);
+            self.sum_squares += sample * sample;
+            self.clipped |= level >= 1.0;
+        }
+        self.count += samples.len();
+    }
+
+    // the level of the window since the last call, and start a new window
+    pub fn take(&mut self) -> Level {
+        let level = Level {
+            peak: self.peak,
+            rms: (self.sum_squares / self.count.max(1) as f32).sqrt(),
+            clipped: self.clipped,
+        };
+        *self = Self::default();
+        level
+    }
+}
+
+impl Level {
+    // One line meter `width` characters wide: `=` up to the RMS, `|` at the peak, starting
+    // with a carriage return so it redraws in place
+    pub fn render(&self, width: usize, clip_lit: bool) -> String {
+        let position = |level: f32| {
+            let fraction = 1.0 - to_db(level) / METER_FLOOR_DB;
+            (fraction.clamp(0.0, 1.0) * width as f32) as usize
+        };
+        let rms = position(self.rms);
+        let peak = position(self.peak);
+
+        let mut bar = String::with_capacity(width);
+        for i in 0..width {
+            bar.push(if i + 1 == peak {
+                '|'
+            } else if i < rms {
+                '='
+            } else {
+                ' '
+            });
+        }
+        format!(
+            "\r[{}] {:6.1} dB peak {:6.1} dB rms {}",
+            bar,
+            to_db(self.peak).max(METER_FLOOR_DB),
+            to_db(self.rms).max(METER_FLOOR_DB),
+            if clip_lit { "CLIP" } else { "    " }
+        )
+    }
+}
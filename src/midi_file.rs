@@ -0,0 +1,174 @@
+use crate::synth::MIDI_CHANNELS;
+use crate::{lock, Synth};
+use midly::{MetaMessage, Smf, Timing, TrackEventKind};
+use std::{
+    error::Error,
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+// Tempo until the file sets one, in microseconds per quarter note (120 bpm)
+const DEFAULT_TEMPO: u32 = 500_000;
+
+enum EventKind {
+    // raw bytes, as they would arrive from a live port
+    Message(Vec<u8>),
+    // microseconds per quarter note
+    Tempo(u32),
+    // a track ends, the file lasts until the last one does, rests at the end included
+    EndOfTrack,
+}
+
+struct Event {
+    tick: u64,
+    kind: EventKind,
+}
+
+// Play a Standard MIDI File through the synth in real time, optionally looping it forever
+pub fn play_midi_file<P: AsRef<Path>>(
+    synth: &Arc<Mutex<Synth>>,
+    path: P,
+    looping: bool,
+) -> Result<(), Box<dyn Error>> {
+    let bytes = std::fs::read(path)?;
+    let smf = Smf::parse(&bytes)?;
+    let events = collect_events(&smf)?;
+    // nothing would take any time, a loop of it would just spin
+    if !events
+        .iter()
+        .any(|event| matches!(event.kind, EventKind::Message(_)))
+    {
+        return Err("the file has no notes or other channel messages".into());
+    }
+    if looping && events.last().is_some_and(|event| event.tick == 0) {
+        return Err("the file takes no time, it can't loop".into());
+    }
+
+    loop {
+        play_events(synth, &events, smf.header.timing);
+        // a note or pedal the file leaves held doesn't carry over into the next pass
+        {
+            let mut synth = lock(synth);
+            for channel in 0..MIDI_CHANNELS as u8 {
+                synth.sustain_pedal(channel, false);
+                synth.all_notes_off(channel);
+            }
+        }
+        if !looping {
+            return Ok(());
+        }
+    }
+}
+
+// Merge all tracks into one list ordered by absolute tick
+fn collect_events(smf: &Smf) -> Result<Vec<Event>, Box<dyn Error>> {
+    let mut events = Vec::new();
+    for track in &smf.tracks {
+        let mut tick = 0u64;
+        for event in track {
+            tick += event.delta.as_int() as u64;
+            match event.kind {
+                TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => events.push(Event {
+                    tick,
+                    kind: EventKind::Tempo(tempo.as_int()),
+                }),
+                TrackEventKind::Meta(MetaMessage::EndOfTrack) => events.push(Event {
+                    tick,
+                    kind: EventKind::EndOfTrack,
+                }),
+                TrackEventKind::Midi { .. } => {
+                    if let Some(live) = event.kind.as_live_event() {
+                        let mut message = Vec::new();
+                        live.write_std(&mut message)?;
+                        events.push(Event {
+                            tick,
+                            kind: EventKind::Message(message),
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    events.sort_by_key(|event| event.tick);
+    Ok(events)
+}
+
+fn seconds_per_tick(timing: Timing, tempo: u32) -> f64 {
+    match timing {
+        Timing::Metrical(ppq) => tempo as f64 / 1_000_000.0 / ppq.as_int().max(1) as f64,
+        Timing::Timecode(fps, subframes) => 1.0 / (fps.as_f32() as f64 * subframes.max(1) as f64),
+    }
+}
+
+fn play_events(synth: &Arc<Mutex<Synth>>, events: &[Event], timing: Timing) {
+    let start = Instant::now();
+    let mut tempo = DEFAULT_TEMPO;
+    let mut last_tick = 0u64;
+    let mut elapsed = 0.0f64;
+
+    for event in events {
+        elapsed += (event.tick - last_tick) as f64 * seconds_per_tick(timing, tempo);
+        last_tick = event.tick;
+
+        // schedule against the start time so sleeping doesn't accumulate drift
+        let due = start + Duration::from_secs_f64(elapsed);
+        if let Some(wait) = due.checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+
+        match &event.kind {
+            EventKind::Tempo(new_tempo) => tempo = *new_tempo,
+            EventKind::Message(message) => lock(synth).handle_midi(message),
+            // only waited for
+            EventKind::EndOfTrack => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::{Format, Header, MidiMessage, TrackEvent};
+
+    #[test]
+    fn keeps_the_rest_before_the_end_of_the_track() {
+        // a quarter note then three beats of rest, at 96 ticks a beat
+        let event = |delta: u32, kind| TrackEvent {
+            delta: delta.into(),
+            kind,
+        };
+        let note = |message| TrackEventKind::Midi {
+            channel: 0.into(),
+            message,
+        };
+        let track = vec![
+            event(
+                0,
+                note(MidiMessage::NoteOn {
+                    key: 60.into(),
+                    vel: 100.into(),
+                }),
+            ),
+            event(
+                96,
+                note(MidiMessage::NoteOff {
+                    key: 60.into(),
+                    vel: 0.into(),
+                }),
+            ),
+            event(288, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+        ];
+        let smf = Smf {
+            header: Header::new(Format::SingleTrack, Timing::Metrical(96.into())),
+            tracks: vec![track],
+        };
+
+        let events = collect_events(&smf).unwrap();
+        let last = events.last().unwrap();
+        assert!(matches!(last.kind, EventKind::EndOfTrack));
+        assert_eq!(last.tick, 384);
+    }
+}
@@ -0,0 +1,146 @@
+use std::{error::Error, fs, path::Path, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModSource {
+    Lfo1,
+    Lfo2,
+    Velocity,
+    Aftertouch,
+    ModWheel,
+    EnvFollower,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModDest {
+    // semitones per unit of the source
+    Pitch,
+    // octaves per unit of the source
+    Cutoff,
+    // fraction of full level per unit of the source
+    Amp,
+    PulseWidth,
+    Pan,
+    // wavetable morph position per unit of the source
+    Morph,
+}
+
+impl FromStr for ModSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "lfo1" => Ok(ModSource::Lfo1),
+            "lfo2" => Ok(ModSource::Lfo2),
+            "velocity" => Ok(ModSource::Velocity),
+            "aftertouch" => Ok(ModSource::Aftertouch),
+            "mod_wheel" => Ok(ModSource::ModWheel),
+            "env_follower" => Ok(ModSource::EnvFollower),
+            _ => Err(format!("unknown modulation source {:?}", s)),
+        }
+    }
+}
+
+impl FromStr for ModDest {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "pitch" => Ok(ModDest::Pitch),
+            "cutoff" => Ok(ModDest::Cutoff),
+            "amp" => Ok(ModDest::Amp),
+            "pulse_width" => Ok(ModDest::PulseWidth),
+            "pan" => Ok(ModDest::Pan),
+            "morph" => Ok(ModDest::Morph),
+            _ => Err(format!("unknown modulation destination {:?}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModRoute {
+    pub source: ModSource,
+    pub dest: ModDest,
+    pub amount: f32,
+}
+
+// Current value of every modulation source as seen by one voice. LFOs are bipolar (-1..1),
+// everything else runs 0..1.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ModSources {
+    pub lfo1: f32,
+    pub lfo2: f32,
+    pub velocity: f32,
+    pub aftertouch: f32,
+    pub mod_wheel: f32,
+    pub env_follower: f32,
+}
+
+impl ModSources {
+    fn get(&self, source: ModSource) -> f32 {
+        match source {
+            ModSource::Lfo1 => self.lfo1,
+            ModSource::Lfo2 => self.lfo2,
+            ModSource::Velocity => self.velocity,
+            ModSource::Aftertouch => self.aftertouch,
+            ModSource::ModWheel => self.mod_wheel,
+            ModSource::EnvFollower => self.env_follower,
+        }
+    }
+}
+
+// Summed modulation for each destination, in the destination's units
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ModOutputs {
+    pub pitch: f32,
+    pub cutoff: f32,
+    pub amp: f32,
+    pub pulse_width: f32,
+    pub pan: f32,
+    pub morph: f32,
+}
+
+// List of (source, destination, amount) routes, evaluated at control rate for every voice
+#[derive(Debug, Default, Clone)]
+pub struct ModMatrix {
+    pub routes: Vec<ModRoute>,
+}
+
+impl ModMatrix {
+    pub fn evaluate(&self, sources: &ModSources) -> ModOutputs {
+        let mut outputs = ModOutputs::default();
+        for route in &self.routes {
+            let value = sources.get(route.source) * route.amount;
+            match route.dest {
+                ModDest::Pitch => outputs.pitch += value,
+                ModDest::Cutoff => outputs.cutoff += value,
+                ModDest::Amp => outputs.amp += value,
+                ModDest::PulseWidth => outputs.pulse_width += value,
+                ModDest::Pan => outputs.pan += value,
+                ModDest::Morph => outputs.morph += value,
+            }
+        }
+        outputs
+    }
+
+    // Load routes from a file with one `<source> <destination> <amount>` per line, e.g.
+    // `mod_wheel pitch 0.5`. `#` starts a comment.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let mut routes = Vec::new();
+        for line in fs::read_to_string(path)?.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Err(format!("bad modulation route {:?}", line).into());
+            }
+            routes.push(ModRoute {
+                source: fields[0].parse()?,
+                dest: fields[1].parse()?,
+                amount: fields[2].parse()?,
+            });
+        }
+        Ok(ModMatrix { routes })
+    }
+}
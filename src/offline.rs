@@ -0,0 +1,61 @@
+use crate::{sample_rate, Synth};
+
+// seed of the synth render_events plays through, so the same events render the same samples
+pub const OFFLINE_SEED: u32 = 1;
+
+// Something played into an offline render
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    NoteOff { channel: u8, note: u8 },
+    // any other message, as raw bytes the way a live port delivers them
+    Midi(Vec<u8>),
+}
+
+impl Event {
+    fn apply(&self, synth: &mut Synth) {
+        match *self {
+            Event::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => synth.note_on(channel, note, velocity),
+            Event::NoteOff { channel, note } => synth.note_off(channel, note),
+            Event::Midi(ref message) => synth.handle_midi(message),
+        }
+    }
+}
+
+impl Synth {
+    // Play `events`, each at its time in seconds from the start, and render `duration_secs` of
+    // the mono mix at the engine's sample rate. No audio device or MIDI port is involved, so
+    // it's for tests and bouncing. Events can come in any order, ones at the same time are
+    // played in the order given, and ones past the end are dropped.
+    pub fn render_events(&mut self, events: &[(f32, Event)], duration_secs: f32) -> Vec<f32> {
+        let to_samples = |secs: f32| (secs.max(0.0) * sample_rate() as f32).round() as usize;
+        let mut out = vec![0.0; to_samples(duration_secs)];
+        let mut events: Vec<&(f32, Event)> = events.iter().collect();
+        events.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let mut pos = 0;
+        for (time, event) in events {
+            let at = to_samples(*time);
+            if at >= out.len() {
+                break;
+            }
+            self.render(&mut out[pos..at.max(pos)]);
+            pos = pos.max(at);
+            event.apply(self);
+        }
+        self.render(&mut out[pos..]);
+        out
+    }
+}
+
+// A default synth with a fixed seed playing `events` for `duration_secs`, see
+// Synth::render_events
+pub fn render_events(events: &[(f32, Event)], duration_secs: f32) -> Vec<f32> {
+    let mut synth = Synth::new();
+    synth.set_seed(OFFLINE_SEED);
+    synth.render_events(events, duration_secs)
+}
@@ -0,0 +1,183 @@
+use crate::envelope::{Adsr, FilterEnv};
+use crate::filter::{FilterMode, DEFAULT_CUTOFF_HZ, DEFAULT_RESONANCE};
+use crate::wave::{WaveType, DEFAULT_PULSE_WIDTH};
+use std::str::FromStr;
+
+// velocity range over which the layers crossfade when no width is given
+pub const DEFAULT_LAYER_WIDTH: u8 = 32;
+
+// One of the built-in waves by name
+fn parse_wave(name: &str) -> Result<WaveType, String> {
+    Ok(match name {
+        "sine" => WaveType::Sine,
+        "square" => WaveType::Square,
+        "pulse" => WaveType::Pulse {
+            width: DEFAULT_PULSE_WIDTH,
+        },
+        "saw" => WaveType::Saw,
+        "triangle" => WaveType::Triangle,
+        "noise" => WaveType::Noise { color: 0.0 },
+        _ => return Err(format!("unknown wave {:?}", name)),
+    })
+}
+
+// A second oscillator the patch crossfades into by note-on velocity: the patch's own wave is
+// the soft layer, `hard` takes over above the split. At the split both layers sound at -6 dB.
+#[derive(Debug, Clone)]
+pub struct VelocityLayer {
+    pub hard: WaveType,
+    // crossover velocity
+    pub split: u8,
+    // velocity range of the crossfade, 0 switches straight from one layer to the other
+    pub width: u8,
+}
+
+impl VelocityLayer {
+    // gain of the hard layer for a note-on velocity, the soft layer gets the rest
+    pub fn hard_gain(&self, velocity: u8) -> f32 {
+        let offset = velocity as f32 - self.split as f32;
+        if self.width == 0 {
+            return if offset > 0.0 {
+                1.0
+            } else if offset < 0.0 {
+                0.0
+            } else {
+                0.5
+            };
+        }
+        (0.5 + offset / self.width as f32).clamp(0.0, 1.0)
+    }
+}
+
+// Parses `<hard wave>,<split>[,<width>]`, e.g. `saw,64` or `square,80,16`
+impl FromStr for VelocityLayer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let bad = || format!("bad velocity layer {:?}", s);
+        let fields: Vec<&str> = s.split(',').map(str::trim).collect();
+        let (hard, split, width) = match fields[..] {
+            [hard, split] => (hard, split, None),
+            [hard, split, width] => (hard, split, Some(width)),
+            _ => return Err(bad()),
+        };
+        Ok(VelocityLayer {
+            hard: parse_wave(hard)?,
+            split: split.parse().map_err(|_| bad())?,
+            width: match width {
+                Some(width) => width.parse().map_err(|_| bad())?,
+                None => DEFAULT_LAYER_WIDTH,
+            },
+        })
+    }
+}
+
+// Second oscillator of every note, mixed with the patch's own wave before the filter. Its
+// pitch follows the note at an interval and detune of its own: a few cents for a fat lead, an
+// octave down for a sub-oscillator. Off by default, keeping its settings for when it is
+// switched on.
+#[derive(Debug, Clone)]
+pub struct Osc2 {
+    pub on: bool,
+    pub wave_type: WaveType,
+    // interval from the note, -12 is an octave down
+    pub semitones: i8,
+    pub detune_cents: f32,
+    // 0 is the first oscillator only, 1 the second only
+    pub mix: f32,
+}
+
+impl Default for Osc2 {
+    // a square sub-oscillator
+    fn default() -> Self {
+        Osc2 {
+            on: false,
+            wave_type: WaveType::Square,
+            semitones: -12,
+            detune_cents: 0.0,
+            mix: 0.5,
+        }
+    }
+}
+
+impl Osc2 {
+    // frequency of the oscillator as a ratio to the note's
+    pub fn ratio(&self) -> f32 {
+        2f32.powf((self.semitones as f32 * 100.0 + self.detune_cents) / 1200.0)
+    }
+}
+
+// Parses `<wave>[,<semitones>[,<detune cents>[,<mix>]]]`, e.g. `square,-12` or `saw,0,7,0.5`,
+// switching the oscillator on
+impl FromStr for Osc2 {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let bad = || format!("bad second oscillator {:?}", s);
+        let mut fields = s.split(',').map(str::trim);
+        let wave_type = parse_wave(fields.next().ok_or_else(bad)?)?;
+        let mut osc2 = Osc2 {
+            on: true,
+            wave_type,
+            semitones: 0,
+            detune_cents: 0.0,
+            mix: 0.5,
+        };
+        if let Some(semitones) = fields.next() {
+            osc2.semitones = semitones.parse().map_err(|_| bad())?;
+        }
+        if let Some(detune) = fields.next() {
+            osc2.detune_cents = detune.parse().map_err(|_| bad())?;
+        }
+        if let Some(mix) = fields.next() {
+            osc2.mix = mix.parse().map_err(|_| bad())?;
+        }
+        if fields.next().is_some() || !(0.0..=1.0).contains(&osc2.mix) {
+            return Err(bad());
+        }
+        Ok(osc2)
+    }
+}
+
+// Sound settings used for the notes of one MIDI channel
+#[derive(Debug, Clone)]
+pub struct Patch {
+    pub wave_type: WaveType,
+    pub adsr: Adsr,
+    // level of the channel's notes, 0..1 (CC7)
+    pub volume: f32,
+    // stereo position of the channel, -1 (left) to 1 (right) (CC10)
+    pub pan: f32,
+    // filter between the oscillator and the amp envelope, cutoff in Hz and resonance as Q.
+    // A high-pass wants the cutoff turned down, at the default it lets almost nothing through.
+    pub filter_mode: FilterMode,
+    pub cutoff: f32,
+    pub resonance: f32,
+    // sweeps the cutoff on every note
+    pub filter_env: FilterEnv,
+    // vowel position (0..4, A E I O U) of the formant filter, None leaves the filter out
+    pub vowel: Option<f32>,
+    // ring modulator frequency as a ratio to the note, None leaves it out
+    pub ring_ratio: Option<f32>,
+    pub osc2: Osc2,
+    pub velocity_layer: Option<VelocityLayer>,
+}
+
+impl Default for Patch {
+    fn default() -> Self {
+        Patch {
+            wave_type: WaveType::Triangle,
+            adsr: Adsr::default(),
+            volume: 1.0,
+            pan: 0.0,
+            filter_mode: FilterMode::LowPass,
+            cutoff: DEFAULT_CUTOFF_HZ,
+            resonance: DEFAULT_RESONANCE,
+            filter_env: FilterEnv::default(),
+            vowel: None,
+            ring_ratio: None,
+            osc2: Osc2::default(),
+            velocity_layer: None,
+        }
+    }
+}
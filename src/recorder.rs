@@ -0,0 +1,64 @@
+use midly::{
+    live::LiveEvent, num::u15, num::u24, num::u28, Format, Header, MetaMessage, Smf, Timing,
+    TrackEvent, TrackEventKind,
+};
+use std::{error::Error, path::Path, time::Instant};
+
+// Resolution of recorded files, in ticks per quarter note
+const PPQ: u16 = 480;
+// Recordings are written at a fixed 120 bpm, in microseconds per quarter note
+const TEMPO: u32 = 500_000;
+
+// Captures incoming MIDI with timestamps and writes it out as a type-0 Standard MIDI File
+#[derive(Debug, Default)]
+pub struct MidiRecorder {
+    start: Option<Instant>,
+    // (seconds since the first message, raw message)
+    events: Vec<(f64, Vec<u8>)>,
+}
+
+impl MidiRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, message: &[u8]) {
+        // time starts at the first message so the file has no leading silence
+        let start = *self.start.get_or_insert_with(Instant::now);
+        self.events
+            .push((start.elapsed().as_secs_f64(), message.to_vec()));
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        let ticks_per_second = PPQ as f64 * 1_000_000.0 / TEMPO as f64;
+
+        let mut track = vec![TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::Tempo(u24::new(TEMPO))),
+        }];
+        let mut last_tick = 0u32;
+        for (time, message) in &self.events {
+            // note-on/off, CCs, pitch bend etc., anything that isn't a channel message is dropped
+            if let Ok(LiveEvent::Midi { channel, message }) = LiveEvent::parse(message) {
+                let tick = (time * ticks_per_second).round() as u32;
+                track.push(TrackEvent {
+                    delta: u28::new(tick - last_tick),
+                    kind: TrackEventKind::Midi { channel, message },
+                });
+                last_tick = tick;
+            }
+        }
+        track.push(TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+        });
+
+        let mut smf = Smf::new(Header::new(
+            Format::SingleTrack,
+            Timing::Metrical(u15::new(PPQ)),
+        ));
+        smf.tracks.push(track);
+        smf.save(path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,34 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+// A seed from the OS entropy std already draws for hash maps, for unseeded runs
+pub(crate) fn entropy_seed() -> u32 {
+    RandomState::new().build_hasher().finish() as u32
+}
+
+// Small xorshift generator; deterministic for a given seed so random features can be reproduced
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct XorShift32 {
+    state: u32,
+}
+
+impl XorShift32 {
+    pub(crate) fn new(seed: u32) -> Self {
+        // xorshift gets stuck at zero
+        Self { state: seed.max(1) }
+    }
+
+    pub(crate) fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    // uniform in [-1, 1)
+    pub(crate) fn next_bipolar(&mut self) -> f32 {
+        (self.next_u32() as f32 / u32::MAX as f32) * 2.0 - 1.0
+    }
+}
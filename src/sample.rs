@@ -0,0 +1,110 @@
+use crate::sample_rate;
+use hound::{SampleFormat, WavReader};
+use std::{collections::HashMap, error::Error, fs, path::Path, sync::Arc};
+
+// Decode a WAV file into mono PCM, along with its sample rate
+fn read_mono<P: AsRef<Path>>(path: P) -> Result<(Vec<f32>, u32), Box<dyn Error>> {
+    let mut reader = WavReader::open(path)?;
+    let spec = reader.spec();
+
+    let interleaved: Vec<f32> = match spec.sample_format {
+        SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+        SampleFormat::Int => {
+            let scale = 1.0 / (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|sample| sample.map(|sample| sample as f32 * scale))
+                .collect::<Result<_, _>>()?
+        }
+    };
+
+    // mono-sum the channels
+    let channels = spec.channels.max(1) as usize;
+    let mono: Vec<f32> = interleaved
+        .chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect();
+
+    Ok((mono, spec.sample_rate))
+}
+
+// Decode a WAV file into mono PCM at the engine's sample rate, for use with WaveType::Sample
+pub fn load_sample<P: AsRef<Path>>(path: P) -> Result<Arc<Vec<f32>>, Box<dyn Error>> {
+    let (mono, file_rate) = read_mono(path)?;
+    Ok(Arc::new(resample(&mono, file_rate, sample_rate())))
+}
+
+// The two tables of a WaveType::Wavetable
+pub type WavetablePair = (Arc<Vec<f32>>, Arc<Vec<f32>>);
+
+// Load two single-cycle WAV files for WaveType::Wavetable. The second table is resampled to
+// the length of the first so the two can be crossfaded sample for sample.
+pub fn load_wavetables<P: AsRef<Path>>(a: P, b: P) -> Result<WavetablePair, Box<dyn Error>> {
+    let (a, _) = read_mono(a)?;
+    let (b, _) = read_mono(b)?;
+    if a.is_empty() || b.is_empty() {
+        return Err("empty wavetable".into());
+    }
+    let b = resize_cycle(&b, a.len());
+    Ok((Arc::new(a), Arc::new(b)))
+}
+
+// Resample one cycle of a waveform to `len` samples, wrapping around at the end
+fn resize_cycle(cycle: &[f32], len: usize) -> Vec<f32> {
+    if cycle.len() == len {
+        return cycle.to_vec();
+    }
+    let step = cycle.len() as f64 / len as f64;
+    (0..len)
+        .map(|i| {
+            let pos = i as f64 * step;
+            let idx = pos as usize;
+            let frac = (pos - idx as f64) as f32;
+            let next = cycle[(idx + 1) % cycle.len()];
+            cycle[idx] + (next - cycle[idx]) * frac
+        })
+        .collect()
+}
+
+// MIDI note to the sample it triggers
+pub type DrumMap = HashMap<u8, Arc<Vec<f32>>>;
+
+// Load a drum map: one `<midi note> <wav file>` pair per line, `#` starts a comment. Relative
+// file names are resolved against the map's directory.
+pub fn load_drum_map<P: AsRef<Path>>(path: P) -> Result<DrumMap, Box<dyn Error>> {
+    let path = path.as_ref();
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+
+    let mut drum_map = HashMap::new();
+    for line in fs::read_to_string(path)?.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (note, file) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| format!("bad drum map line {:?}", line))?;
+        let note: u8 = note.parse()?;
+        drum_map.insert(note, load_sample(dir.join(file.trim()))?);
+    }
+    Ok(drum_map)
+}
+
+// Linear-interpolation resampler, good enough for one-shot samples
+fn resample(input: &[f32], from_rate: u32, to_rate: u32) -> Vec<f32> {
+    if from_rate == to_rate || input.is_empty() {
+        return input.to_vec();
+    }
+
+    let step = from_rate as f64 / to_rate as f64;
+    let len = (input.len() as f64 / step).floor() as usize;
+    (0..len)
+        .map(|i| {
+            let pos = i as f64 * step;
+            let idx = pos as usize;
+            let frac = (pos - idx as f64) as f32;
+            let next = input.get(idx + 1).copied().unwrap_or(input[idx]);
+            input[idx] + (next - input[idx]) * frac
+        })
+        .collect()
+}
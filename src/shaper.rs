@@ -0,0 +1,117 @@
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+// Most the input can be driven into the curve
+pub const MAX_DRIVE: f32 = 20.0;
+pub const DEFAULT_DRIVE: f32 = 3.0;
+
+// Transfer curve of the waveshaper
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ShapeCurve {
+    // tanh, rounds the peaks off gradually: warmth at low drive, overdrive at high
+    Soft,
+    // flat tops above full scale, harsh fuzz
+    Hard,
+    // arctan, softer than tanh near the top and never quite flattens
+    Arctan,
+}
+
+impl ShapeCurve {
+    fn apply(self, x: f32) -> f32 {
+        match self {
+            ShapeCurve::Soft => x.tanh(),
+            ShapeCurve::Hard => x.clamp(-1.0, 1.0),
+            ShapeCurve::Arctan => x.atan() * std::f32::consts::FRAC_2_PI,
+        }
+    }
+}
+
+impl FromStr for ShapeCurve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "soft" | "tanh" => Ok(ShapeCurve::Soft),
+            "hard" | "clip" => Ok(ShapeCurve::Hard),
+            "arctan" | "atan" => Ok(ShapeCurve::Arctan),
+            _ => Err(format!("unknown shaper curve {:?}", s)),
+        }
+    }
+}
+
+// Waveshaping distortion for the master mix. The input is multiplied by the drive and bent by
+// the curve, and the output scaled so a full-scale input still comes out at full scale: more
+// drive thickens the sound rather than just making it louder. Off by default, keeping its
+// settings for when it is switched on.
+#[derive(Debug, Clone, Copy)]
+pub struct Shaper {
+    pub on: bool,
+    pub curve: ShapeCurve,
+    drive: f32,
+}
+
+impl Default for Shaper {
+    fn default() -> Self {
+        let mut shaper = Self::new(ShapeCurve::Soft, DEFAULT_DRIVE);
+        shaper.on = false;
+        shaper
+    }
+}
+
+impl Shaper {
+    pub fn new(curve: ShapeCurve, drive: f32) -> Self {
+        let mut shaper = Self {
+            on: true,
+            curve,
+            drive: 1.0,
+        };
+        shaper.set_drive(drive);
+        shaper
+    }
+
+    // Gain into the curve, clamped to 1..=MAX_DRIVE
+    pub fn set_drive(&mut self, drive: f32) {
+        self.drive = drive.clamp(1.0, MAX_DRIVE);
+    }
+
+    pub fn drive(&self) -> f32 {
+        self.drive
+    }
+
+    pub fn process_stereo(&mut self, left: f32, right: f32) -> (f32, f32) {
+        if !self.on {
+            return (left, right);
+        }
+        let makeup = 1.0 / self.curve.apply(self.drive);
+        (
+            self.curve.apply(left * self.drive) * makeup,
+            self.curve.apply(right * self.drive) * makeup,
+        )
+    }
+}
+
+// Parses `curve[,drive]`, e.g. `soft` or `hard,8`
+impl FromStr for Shaper {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let bad = || format!("bad shaper {:?}", s);
+        let fields: Vec<&str> = s.split(',').map(str::trim).collect();
+        let (curve, drive) = match fields[..] {
+            [curve] => (curve, None),
+            [curve, drive] => (curve, Some(drive)),
+            _ => return Err(bad()),
+        };
+        let drive = match drive {
+            Some(drive) => drive.parse().map_err(|_| bad())?,
+            None => DEFAULT_DRIVE,
+        };
+        if !(1.0..=MAX_DRIVE).contains(&drive) {
+            return Err(format!(
+                "shaper drive goes from 1 to {}, not {}",
+                MAX_DRIVE, drive
+            ));
+        }
+        Ok(Self::new(curve.parse()?, drive))
+    }
+}
@@ -0,0 +1,60 @@
+use crate::sample_rate;
+
+// How long a smoothed parameter takes to get most of the way (63%) to a new setting
+pub const DEFAULT_SMOOTHING_MS: f32 = 10.0;
+
+// A setting that glides to each new value instead of jumping there, so turning a knob or
+// stepping a CC doesn't zipper on held notes. One-pole: each step covers a fixed fraction of
+// what is left, so it's quick to respond and settles without overshoot.
+#[derive(Debug, Clone, Copy)]
+pub struct SmoothedParam {
+    value: f32,
+    target: f32,
+    time_ms: f32,
+}
+
+impl SmoothedParam {
+    pub fn new(value: f32, time_ms: f32) -> Self {
+        Self {
+            value,
+            target: value,
+            time_ms,
+        }
+    }
+
+    // Where to glide to
+    pub fn set(&mut self, target: f32) {
+        self.target = target;
+    }
+
+    // How long it takes to get most of the way to a new setting
+    pub fn set_time(&mut self, time_ms: f32) {
+        self.time_ms = time_ms.max(0.0);
+    }
+
+    pub fn target(&self) -> f32 {
+        self.target
+    }
+
+    // Move on by `samples` toward the target, returning the value there
+    pub fn advance(&mut self, samples: usize) -> f32 {
+        let time_samples = self.time_ms * sample_rate() as f32 / 1000.0;
+        if time_samples <= 0.0 {
+            self.value = self.target;
+        } else {
+            let coefficient = 1.0 - (-(samples as f32) / time_samples).exp();
+            self.value += (self.target - self.value) * coefficient;
+        }
+        self.value
+    }
+
+    pub fn value(&self) -> f32 {
+        self.value
+    }
+}
+
+impl Default for SmoothedParam {
+    fn default() -> Self {
+        Self::new(0.0, DEFAULT_SMOOTHING_MS)
+    }
+}
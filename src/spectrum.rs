@@ -0,0 +1,128 @@
+use crate::sample_rate;
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+use std::sync::Arc;
+
+// FFT length of the spectrum view, ~46 ms of audio
+pub const SPECTRUM_SIZE: usize = 2048;
+// range of the bar chart
+const SPECTRUM_MIN_HZ: f32 = 20.0;
+const SPECTRUM_MAX_HZ: f32 = 20_000.0;
+const SPECTRUM_FLOOR_DB: f32 = -80.0;
+
+// Ring buffer the audio thread copies the mix into, holding the latest SPECTRUM_SIZE samples
+#[derive(Debug, Clone)]
+pub struct SpectrumTap {
+    ring: Vec<f32>,
+    pos: usize,
+}
+
+impl Default for SpectrumTap {
+    fn default() -> Self {
+        Self {
+            ring: vec![0.0; SPECTRUM_SIZE],
+            pos: 0,
+        }
+    }
+}
+
+impl SpectrumTap {
+    pub fn push(&mut self, samples: &[f32]) {
+        for &sample in samples {
+            self.ring[self.pos] = sample;
+            self.pos = (self.pos + 1) % self.ring.len();
+        }
+    }
+
+    // the buffered samples, oldest first
+    pub fn snapshot(&self) -> Vec<f32> {
+        let mut samples = self.ring[self.pos..].to_vec();
+        samples.extend_from_slice(&self.ring[..self.pos]);
+        samples
+    }
+}
+
+// Hann-windowed FFT of a block of the mix, turned into a terminal bar chart
+pub struct SpectrumAnalyzer {
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    buffer: Vec<Complex<f32>>,
+}
+
+impl Default for SpectrumAnalyzer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SpectrumAnalyzer {
+    pub fn new() -> Self {
+        let window = (0..SPECTRUM_SIZE)
+            .map(|i| {
+                let phase = i as f32 / SPECTRUM_SIZE as f32;
+                0.5 - 0.5 * (2.0 * std::f32::consts::PI * phase).cos()
+            })
+            .collect();
+        Self {
+            fft: FftPlanner::new().plan_fft_forward(SPECTRUM_SIZE),
+            window,
+            buffer: vec![Complex::new(0.0, 0.0); SPECTRUM_SIZE],
+        }
+    }
+
+    // Level in dB (full-scale sine is ~0 dB) of each bin up to Nyquist
+    pub fn magnitudes_db(&mut self, samples: &[f32]) -> Vec<f32> {
+        for (i, value) in self.buffer.iter_mut().enumerate() {
+            let sample = samples.get(i).copied().unwrap_or(0.0);
+            *value = Complex::new(sample * self.window[i], 0.0);
+        }
+        self.fft.process(&mut self.buffer);
+
+        // undo the window's loss of level
+        let scale = 2.0 / self.window.iter().sum::<f32>();
+        self.buffer[..SPECTRUM_SIZE / 2]
+            .iter()
+            .map(|bin| 20.0 * (bin.norm() * scale).max(1e-9).log10())
+            .collect()
+    }
+
+    // Bar chart of `samples` with `columns` log-spaced bands and `rows` lines of height,
+    // starting with the ANSI codes to redraw in place
+    pub fn render(&mut self, samples: &[f32], columns: usize, rows: usize) -> String {
+        let magnitudes = self.magnitudes_db(samples);
+        let bin_hz = sample_rate() as f32 / SPECTRUM_SIZE as f32;
+        let span = SPECTRUM_MAX_HZ / SPECTRUM_MIN_HZ;
+
+        // height of each column in rows, from the loudest bin in its band
+        let heights: Vec<usize> = (0..columns)
+            .map(|column| {
+                let low = SPECTRUM_MIN_HZ * span.powf(column as f32 / columns as f32);
+                let high = SPECTRUM_MIN_HZ * span.powf((column + 1) as f32 / columns as f32);
+                let first = (low / bin_hz) as usize;
+                let last = ((high / bin_hz) as usize)
+                    .max(first + 1)
+                    .min(magnitudes.len());
+                let db = magnitudes[first.min(last - 1)..last]
+                    .iter()
+                    .fold(SPECTRUM_FLOOR_DB, |max, &db| max.max(db));
+                let level = 1.0 - db / SPECTRUM_FLOOR_DB;
+                (level.clamp(0.0, 1.0) * rows as f32).round() as usize
+            })
+            .collect();
+
+        let mut out = String::from("\x1b[H\x1b[2J");
+        for row in (0..rows).rev() {
+            for &height in &heights {
+                out.push(if height > row { '█' } else { ' ' });
+            }
+            out.push('\n');
+        }
+        let low_label = format!("{} Hz", SPECTRUM_MIN_HZ);
+        out.push_str(&format!(
+            "{:<width$}{} Hz\n",
+            low_label,
+            SPECTRUM_MAX_HZ,
+            width = columns.saturating_sub(8)
+        ));
+        out
+    }
+}
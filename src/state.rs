@@ -0,0 +1,540 @@
+use crate::arp::{ArpMode, ArpRate};
+use crate::delay::DelaySync;
+use crate::envelope::{Adsr, FilterEnv};
+use crate::filter::FilterMode;
+use crate::lfo::Lfo;
+use crate::patch::Patch;
+use crate::shaper::ShapeCurve;
+use crate::synth::{NotePriority, PanMode, Synth, TriggerMode, VelocityCurve, VelocityDest};
+use crate::wave::WaveType;
+use serde::{Deserialize, Serialize};
+use std::{error::Error, fs, path::Path};
+
+// Bump only when a file of the old layout can't be read as the new one, a field renamed or
+// changing meaning, and files of another version are ignored. Fields that are added are
+// filled in from the synth's defaults and need no bump.
+pub const STATE_VERSION: u32 = 1;
+
+// The oscillators a patch is saved with. Samples and wavetables come from files given at
+// startup, so they are not saved and whatever was loaded is kept.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum SavedWave {
+    Sine,
+    Square,
+    Pulse { width: f32 },
+    Saw,
+    Triangle,
+    Noise { color: f32 },
+}
+
+impl SavedWave {
+    // None for the waves that come from files
+    fn from_wave(wave_type: &WaveType) -> Option<Self> {
+        match *wave_type {
+            WaveType::Sine => Some(SavedWave::Sine),
+            WaveType::Square => Some(SavedWave::Square),
+            WaveType::Pulse { width } => Some(SavedWave::Pulse { width }),
+            WaveType::Saw => Some(SavedWave::Saw),
+            WaveType::Triangle => Some(SavedWave::Triangle),
+            WaveType::Noise { color } => Some(SavedWave::Noise { color }),
+            WaveType::Sample { .. } | WaveType::Wavetable { .. } => None,
+        }
+    }
+
+    fn wave_type(self) -> WaveType {
+        match self {
+            SavedWave::Sine => WaveType::Sine,
+            SavedWave::Square => WaveType::Square,
+            SavedWave::Pulse { width } => WaveType::Pulse { width },
+            SavedWave::Saw => WaveType::Saw,
+            SavedWave::Triangle => WaveType::Triangle,
+            SavedWave::Noise { color } => WaveType::Noise { color },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedOsc2 {
+    pub on: bool,
+    pub wave: Option<SavedWave>,
+    pub semitones: i8,
+    pub detune_cents: f32,
+    pub mix: f32,
+}
+
+// missing fields (settings added since the file was saved) take the default patch's values
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PatchState {
+    // a sample or wavetable is None, which TOML leaves out, and keeps whatever was loaded
+    #[serde(default)]
+    pub wave: Option<SavedWave>,
+    pub adsr: Adsr,
+    pub volume: f32,
+    pub pan: f32,
+    pub filter_mode: FilterMode,
+    pub cutoff: f32,
+    pub resonance: f32,
+    pub filter_env: FilterEnv,
+    pub vowel: Option<f32>,
+    pub ring_ratio: Option<f32>,
+    pub osc2: SavedOsc2,
+}
+
+impl PatchState {
+    fn from_patch(patch: &Patch) -> Self {
+        PatchState {
+            wave: SavedWave::from_wave(&patch.wave_type),
+            adsr: patch.adsr,
+            volume: patch.volume,
+            pan: patch.pan,
+            filter_mode: patch.filter_mode,
+            cutoff: patch.cutoff,
+            resonance: patch.resonance,
+            filter_env: patch.filter_env,
+            vowel: patch.vowel,
+            ring_ratio: patch.ring_ratio,
+            osc2: SavedOsc2 {
+                on: patch.osc2.on,
+                wave: SavedWave::from_wave(&patch.osc2.wave_type),
+                semitones: patch.osc2.semitones,
+                detune_cents: patch.osc2.detune_cents,
+                mix: patch.osc2.mix,
+            },
+        }
+    }
+}
+
+impl Default for PatchState {
+    fn default() -> Self {
+        Self::from_patch(&Patch::default())
+    }
+}
+
+impl PatchState {
+    fn apply(&self, patch: &mut Patch) {
+        if let Some(wave) = self.wave {
+            patch.wave_type = wave.wave_type();
+        }
+        patch.adsr = self.adsr;
+        patch.volume = self.volume;
+        patch.pan = self.pan;
+        patch.filter_mode = self.filter_mode;
+        patch.cutoff = self.cutoff;
+        patch.resonance = self.resonance;
+        patch.filter_env = self.filter_env;
+        patch.vowel = self.vowel;
+        patch.ring_ratio = self.ring_ratio;
+        patch.osc2.on = self.osc2.on;
+        if let Some(wave) = self.osc2.wave {
+            patch.osc2.wave_type = wave.wave_type();
+        }
+        patch.osc2.semitones = self.osc2.semitones;
+        patch.osc2.detune_cents = self.osc2.detune_cents;
+        patch.osc2.mix = self.osc2.mix;
+    }
+}
+
+// A sound as saved to a patch file: the voice settings every channel plays with, the LFOs
+// and the effects. The playing setup (modes, arpeggiator, routing, output) is left to the
+// state file. Missing fields take the values of a fresh synth.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SavedPatch {
+    pub lfos: [Lfo; 2],
+    // (low, mid, high) in dB
+    pub eq_gains_db: (f32, f32, f32),
+    pub shaper: bool,
+    pub shaper_curve: ShapeCurve,
+    pub shaper_drive: f32,
+    pub crusher: bool,
+    pub crusher_bits: u8,
+    pub crusher_downsample: usize,
+    pub chorus_rate_hz: f32,
+    pub chorus_depth_ms: f32,
+    pub chorus_voices: usize,
+    pub chorus_mix: f32,
+    pub delay_ms: f32,
+    pub delay_feedback: f32,
+    pub delay_mix: f32,
+    pub delay_sync: DelaySync,
+    pub delay_ping_pong: bool,
+    // channel 1's, the panel edits every channel together
+    pub voice: PatchState,
+}
+
+impl Default for SavedPatch {
+    fn default() -> Self {
+        Synth::new().patch()
+    }
+}
+
+// Everything about the sound and the playing setup that can change while the synth runs, so
+// a standalone unit comes back up exactly as it was left. Missing fields take the values of a
+// fresh synth.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SynthState {
+    pub version: u32,
+    pub patches: Vec<PatchState>,
+    pub drift_amount: f32,
+    pub mono: bool,
+    pub retrigger: bool,
+    pub glide: bool,
+    pub glide_ms: f32,
+    pub bend_range: f32,
+    pub bend_smoothing_ms: f32,
+    pub note_timeout_secs: Option<f32>,
+    pub unison_voices: usize,
+    pub unison_detune: f32,
+    pub fingered_glide: bool,
+    pub pan_spread: f32,
+    pub pan_mode: PanMode,
+    pub transpose: i8,
+    pub drum_channel: Option<u8>,
+    pub auto_gain: bool,
+    pub auto_gain_law: f32,
+    pub note_priority: NotePriority,
+    pub trigger_mode: TriggerMode,
+    pub velocity_curve: VelocityCurve,
+    pub velocity_dest: VelocityDest,
+    pub velocity_cutoff: f32,
+    pub latch: bool,
+    pub control_rate: f32,
+    pub lfos: [Lfo; 2],
+    pub arp: bool,
+    pub arp_mode: ArpMode,
+    pub arp_rate: ArpRate,
+    pub arp_octaves: u8,
+    // saved as arp_bpm before the LFOs shared the tempo
+    #[serde(alias = "arp_bpm")]
+    pub tempo_bpm: f32,
+    // (low, mid, high) in dB
+    pub eq_gains_db: (f32, f32, f32),
+    pub shaper: bool,
+    pub shaper_curve: ShapeCurve,
+    pub shaper_drive: f32,
+    pub crusher: bool,
+    pub crusher_bits: u8,
+    pub crusher_downsample: usize,
+    pub chorus_rate_hz: f32,
+    pub chorus_depth_ms: f32,
+    pub chorus_voices: usize,
+    pub chorus_mix: f32,
+    pub delay_ms: f32,
+    pub delay_feedback: f32,
+    pub delay_mix: f32,
+    pub delay_sync: DelaySync,
+    pub delay_ping_pong: bool,
+    pub compressor_threshold_db: f32,
+    pub compressor_ratio: f32,
+    pub compressor_attack_ms: f32,
+    pub compressor_release_ms: f32,
+    pub master_gain: f32,
+    pub limiter_ceiling_db: f32,
+    pub limiter_release_ms: f32,
+}
+
+impl Default for SynthState {
+    fn default() -> Self {
+        Synth::new().state()
+    }
+}
+
+impl Synth {
+    pub fn state(&self) -> SynthState {
+        let (compressor_attack_ms, compressor_release_ms) = self.compressor.times();
+        SynthState {
+            version: STATE_VERSION,
+            patches: self.patches.iter().map(PatchState::from_patch).collect(),
+            drift_amount: self.drift_amount,
+            mono: self.mono,
+            retrigger: self.retrigger,
+            glide: self.glide,
+            glide_ms: self.glide_ms,
+            bend_range: self.bend_range,
+            bend_smoothing_ms: self.bend_smoothing_ms,
+            note_timeout_secs: self.note_timeout_secs,
+            unison_voices: self.unison_voices,
+            unison_detune: self.unison_detune,
+            fingered_glide: self.fingered_glide,
+            pan_spread: self.pan_spread,
+            pan_mode: self.pan_mode,
+            transpose: self.transpose(),
+            drum_channel: self.drum_channel,
+            auto_gain: self.auto_gain,
+            auto_gain_law: self.auto_gain_law,
+            note_priority: self.note_priority,
+            trigger_mode: self.trigger_mode,
+            velocity_curve: self.velocity_curve,
+            velocity_dest: self.velocity_dest,
+            velocity_cutoff: self.velocity_cutoff,
+            latch: self.latch(),
+            control_rate: self.control_rate(),
+            lfos: self.lfos,
+            arp: self.arp(),
+            arp_mode: self.arp.mode,
+            arp_rate: self.arp.rate,
+            arp_octaves: self.arp.octaves,
+            tempo_bpm: self.clock.bpm,
+            eq_gains_db: self.eq.gains(),
+            shaper: self.shaper.on,
+            shaper_curve: self.shaper.curve,
+            shaper_drive: self.shaper.drive(),
+            crusher: self.crusher.on,
+            crusher_bits: self.crusher.bits(),
+            crusher_downsample: self.crusher.downsample,
+            chorus_rate_hz: self.chorus.rate_hz,
+            chorus_depth_ms: self.chorus.depth_ms,
+            chorus_voices: self.chorus.voices(),
+            chorus_mix: self.chorus.mix,
+            delay_ms: self.delay.time(),
+            delay_feedback: self.delay.feedback,
+            delay_mix: self.delay.mix,
+            delay_sync: self.delay.sync,
+            delay_ping_pong: self.delay.ping_pong,
+            compressor_threshold_db: self.compressor.threshold_db,
+            compressor_ratio: self.compressor.ratio,
+            compressor_attack_ms,
+            compressor_release_ms,
+            master_gain: self.master_gain,
+            limiter_ceiling_db: self.limiter.ceiling_db(),
+            limiter_release_ms: self.limiter.release(),
+        }
+    }
+
+    pub fn restore_state(&mut self, state: &SynthState) {
+        for (patch, saved) in self.patches.iter_mut().zip(&state.patches) {
+            saved.apply(patch);
+        }
+        self.drift_amount = state.drift_amount;
+        self.set_mono(state.mono);
+        self.retrigger = state.retrigger;
+        self.glide = state.glide;
+        self.glide_ms = state.glide_ms;
+        self.bend_range = state.bend_range;
+        self.bend_smoothing_ms = state.bend_smoothing_ms;
+        self.note_timeout_secs = state.note_timeout_secs;
+        self.unison_voices = state.unison_voices;
+        self.unison_detune = state.unison_detune;
+        self.fingered_glide = state.fingered_glide;
+        self.pan_spread = state.pan_spread;
+        self.pan_mode = state.pan_mode;
+        self.set_transpose(state.transpose);
+        self.drum_channel = state.drum_channel;
+        self.auto_gain = state.auto_gain;
+        self.auto_gain_law = state.auto_gain_law;
+        self.note_priority = state.note_priority;
+        self.trigger_mode = state.trigger_mode;
+        self.velocity_curve = state.velocity_curve;
+        self.velocity_dest = state.velocity_dest;
+        self.velocity_cutoff = state.velocity_cutoff;
+        self.set_latch(state.latch);
+        self.set_control_rate(state.control_rate);
+        self.lfos = state.lfos;
+        self.arp.mode = state.arp_mode;
+        self.arp.rate = state.arp_rate;
+        self.arp.octaves = state.arp_octaves;
+        self.clock.bpm = state.tempo_bpm;
+        self.set_arp(state.arp);
+        let (low, mid, high) = state.eq_gains_db;
+        self.eq.set_low(low);
+        self.eq.set_mid(mid);
+        self.eq.set_high(high);
+        self.shaper.on = state.shaper;
+        self.shaper.curve = state.shaper_curve;
+        self.shaper.set_drive(state.shaper_drive);
+        self.crusher.on = state.crusher;
+        self.crusher.set_bits(state.crusher_bits);
+        self.crusher.downsample = state.crusher_downsample;
+        self.chorus.rate_hz = state.chorus_rate_hz;
+        self.chorus.depth_ms = state.chorus_depth_ms;
+        self.chorus.set_voices(state.chorus_voices);
+        self.chorus.mix = state.chorus_mix;
+        self.delay.set_time(state.delay_ms);
+        self.delay.feedback = state.delay_feedback;
+        self.delay.mix = state.delay_mix;
+        self.delay.sync = state.delay_sync;
+        self.delay.ping_pong = state.delay_ping_pong;
+        self.compressor.threshold_db = state.compressor_threshold_db;
+        self.compressor.ratio = state.compressor_ratio;
+        self.compressor
+            .set_times(state.compressor_attack_ms, state.compressor_release_ms);
+        self.master_gain = state.master_gain;
+        self.limiter.set_ceiling_db(state.limiter_ceiling_db);
+        self.limiter.set_release(state.limiter_release_ms);
+    }
+
+    pub fn patch(&self) -> SavedPatch {
+        SavedPatch {
+            lfos: self.lfos,
+            eq_gains_db: self.eq.gains(),
+            shaper: self.shaper.on,
+            shaper_curve: self.shaper.curve,
+            shaper_drive: self.shaper.drive(),
+            crusher: self.crusher.on,
+            crusher_bits: self.crusher.bits(),
+            crusher_downsample: self.crusher.downsample,
+            chorus_rate_hz: self.chorus.rate_hz,
+            chorus_depth_ms: self.chorus.depth_ms,
+            chorus_voices: self.chorus.voices(),
+            chorus_mix: self.chorus.mix,
+            delay_ms: self.delay.time(),
+            delay_feedback: self.delay.feedback,
+            delay_mix: self.delay.mix,
+            delay_sync: self.delay.sync,
+            delay_ping_pong: self.delay.ping_pong,
+            voice: PatchState::from_patch(&self.patches[0]),
+        }
+    }
+
+    // Play every channel with the saved sound
+    pub fn restore_patch(&mut self, saved: &SavedPatch) {
+        for patch in self.patches.iter_mut() {
+            saved.voice.apply(patch);
+        }
+        self.lfos = saved.lfos;
+        let (low, mid, high) = saved.eq_gains_db;
+        self.eq.set_low(low);
+        self.eq.set_mid(mid);
+        self.eq.set_high(high);
+        self.shaper.on = saved.shaper;
+        self.shaper.curve = saved.shaper_curve;
+        self.shaper.set_drive(saved.shaper_drive);
+        self.crusher.on = saved.crusher;
+        self.crusher.set_bits(saved.crusher_bits);
+        self.crusher.downsample = saved.crusher_downsample;
+        self.chorus.rate_hz = saved.chorus_rate_hz;
+        self.chorus.depth_ms = saved.chorus_depth_ms;
+        self.chorus.set_voices(saved.chorus_voices);
+        self.chorus.mix = saved.chorus_mix;
+        self.delay.set_time(saved.delay_ms);
+        self.delay.feedback = saved.delay_feedback;
+        self.delay.mix = saved.delay_mix;
+        self.delay.sync = saved.delay_sync;
+        self.delay.ping_pong = saved.delay_ping_pong;
+    }
+
+    // Write the sound to a TOML patch file
+    pub fn save_patch<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        fs::write(path, toml::to_string(&self.patch())?)?;
+        Ok(())
+    }
+
+    // Play the sound in a TOML patch file. A file that doesn't parse is an error and leaves
+    // the sound as it was.
+    pub fn load_patch<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Box<dyn Error>> {
+        let saved: SavedPatch = toml::from_str(&fs::read_to_string(path)?)?;
+        self.restore_patch(&saved);
+        Ok(())
+    }
+
+    pub fn save_state<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        fs::write(path, serde_json::to_string_pretty(&self.state())?)?;
+        Ok(())
+    }
+
+    // Restore a saved state. A file from another version (or that doesn't parse) is an error
+    // and leaves the synth as it was, on its defaults.
+    pub fn load_state<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Box<dyn Error>> {
+        let state: SynthState = serde_json::from_str(&fs::read_to_string(path)?)?;
+        if state.version != STATE_VERSION {
+            return Err(format!(
+                "state version {} doesn't match {}",
+                state.version, STATE_VERSION
+            )
+            .into());
+        }
+        self.restore_state(&state);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_added_since_a_file_was_saved_take_their_defaults() {
+        // the layout the first version saved, before arp_bpm became tempo_bpm
+        let saved = r#"{
+            "version": 1,
+            "patches": [{
+                "wave": "Saw",
+                "adsr": {
+                    "attack": 20, "decay": 100, "sustain": 0.5, "release": 300,
+                    "loop_ad": false, "mode": "Adsr"
+                },
+                "vowel": null
+            }],
+            "drift_amount": 3.0,
+            "retrigger": true,
+            "glide": false,
+            "fingered_glide": false,
+            "pan_spread": 0.0,
+            "auto_gain": true,
+            "auto_gain_law": 0.5,
+            "note_priority": "Oldest",
+            "trigger_mode": "Gate",
+            "latch": false,
+            "control_rate": 1000.0,
+            "eq_gains_db": [3.0, 0.0, 0.0],
+            "compressor_threshold_db": -12.0,
+            "compressor_ratio": 4.0,
+            "compressor_attack_ms": 5.0,
+            "compressor_release_ms": 100.0,
+            "arp_bpm": 90.0
+        }"#;
+        let state: SynthState = serde_json::from_str(saved).unwrap();
+        assert_eq!(state.version, STATE_VERSION);
+        let mut synth = Synth::new();
+        synth.restore_state(&state);
+
+        let fresh = Synth::new();
+        let patch = &synth.patches[0];
+        assert!(matches!(patch.wave_type, WaveType::Saw));
+        assert_eq!(patch.adsr.attack, 20);
+        assert_eq!(patch.adsr.hold, 0);
+        assert_eq!(patch.adsr.curve, fresh.patches[0].adsr.curve);
+        assert_eq!(patch.cutoff, fresh.patches[0].cutoff);
+        assert_eq!(synth.drift_amount, 3.0);
+        assert_eq!(synth.trigger_mode, TriggerMode::Gate);
+        assert_eq!(synth.eq.gains().0, 3.0);
+        assert_eq!(synth.clock.bpm, 90.0);
+        assert_eq!(synth.master_gain, fresh.master_gain);
+        assert_eq!(synth.bend_range, fresh.bend_range);
+        assert_eq!(synth.unison_voices, fresh.unison_voices);
+    }
+
+    #[test]
+    fn a_patch_comes_back_from_toml_on_every_channel() {
+        let mut synth = Synth::new();
+        synth.patches[0].wave_type = WaveType::Square;
+        synth.patches[0].adsr.attack = 250;
+        synth.patches[0].cutoff = 1200.0;
+        synth.eq.set_low(-6.0);
+        synth.delay.mix = 0.4;
+        synth.crusher.on = true;
+        let text = toml::to_string(&synth.patch()).unwrap();
+
+        let mut loaded = Synth::new();
+        loaded.restore_patch(&toml::from_str(&text).unwrap());
+        for patch in &loaded.patches {
+            assert!(matches!(patch.wave_type, WaveType::Square));
+            assert_eq!(patch.adsr.attack, 250);
+            assert_eq!(patch.cutoff, 1200.0);
+        }
+        assert_eq!(loaded.eq.gains().0, -6.0);
+        assert_eq!(loaded.delay.mix, 0.4);
+        assert!(loaded.crusher.on);
+    }
+
+    #[test]
+    fn a_partial_patch_keeps_the_rest_fresh() {
+        let saved: SavedPatch = toml::from_str("delay_mix = 0.25\n").unwrap();
+        let mut synth = Synth::new();
+        synth.restore_patch(&saved);
+        assert_eq!(synth.delay.mix, 0.25);
+        assert_eq!(synth.patches[0].cutoff, Synth::new().patches[0].cutoff);
+    }
+}
@@ -0,0 +1,1499 @@
+use crate::arp::Arpeggiator;
+use crate::chorus::{Chorus, MAX_CHORUS_DEPTH_MS};
+use crate::clock::MidiClock;
+use crate::compressor::Compressor;
+use crate::crusher::Crusher;
+use crate::delay::{Delay, MAX_DELAY_FEEDBACK, MAX_DELAY_MS};
+use crate::drums::{gm_drum_kit, render_drum_kit, DrumKit, GM_DRUM_CHANNEL};
+use crate::envelope::Adsr;
+use crate::eq::ThreeBandEq;
+use crate::filter::{DEFAULT_CUTOFF_HZ, DEFAULT_RESONANCE, MAX_RESONANCE, MIN_CUTOFF_HZ};
+use crate::follower::EnvelopeFollower;
+use crate::formant::VOWEL_FORMANTS;
+use crate::lfo::{Lfo, LfoShape, DEFAULT_LFO_RATE_HZ, MAX_LFO_RATE_HZ, MIN_LFO_RATE_HZ};
+use crate::limiter::Limiter;
+use crate::modmatrix::{ModDest, ModMatrix, ModRoute, ModSource, ModSources};
+use crate::patch::Patch;
+use crate::rng::{entropy_seed, XorShift32};
+use crate::sample::DrumMap;
+use crate::shaper::Shaper;
+use crate::smooth::{SmoothedParam, DEFAULT_SMOOTHING_MS};
+use crate::voice::Voice;
+use crate::wave::{WaveTrims, WaveType, DEFAULT_PULSE_WIDTH, MAX_PULSE_WIDTH, MIN_PULSE_WIDTH};
+use crate::{midi_note_to_freq, sample_rate};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+
+pub const DEFAULT_POLYPHONY: usize = 16;
+// upper bound for the runtime polyphony setting
+pub const MAX_POLYPHONY: usize = 64;
+pub const MIDI_CHANNELS: usize = 16;
+// rate envelopes and modulation are evaluated at, in Hz
+pub const DEFAULT_CONTROL_RATE: f32 = 1000.0;
+
+// Which notes survive when every voice is busy and a new note needs one
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum NotePriority {
+    // steal the voice that started first, preferring notes that are already released
+    Oldest,
+    // keep the lowest notes (bass priority)
+    Low,
+    // keep the highest notes (melody priority)
+    High,
+}
+
+// What a note-on does to a key that is still sounding
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TriggerMode {
+    // a fresh voice from zero while the old one releases, every hit of a fast repeat is
+    // articulated
+    Trigger,
+    // keep the envelope going, only a fully released note starts over
+    Gate,
+    // the same voice starts its envelope over, rising from where it is
+    Reuse,
+    // repeats of a key that is still down do nothing, a let-go key is struck afresh
+    Ignore,
+}
+
+impl FromStr for TriggerMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "trigger" | "retrigger" => Ok(TriggerMode::Trigger),
+            "gate" => Ok(TriggerMode::Gate),
+            "reuse" => Ok(TriggerMode::Reuse),
+            "ignore" => Ok(TriggerMode::Ignore),
+            _ => Err(format!("unknown trigger mode {:?}", s)),
+        }
+    }
+}
+
+// How note-on velocity maps to loudness
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum VelocityCurve {
+    // loudness proportional to velocity
+    Linear,
+    // quiet playing comes out louder, for light keyboards
+    Soft,
+    // needs a firmer touch to get loud, for heavy keyboards
+    Hard,
+    // every note at full level, velocity ignored
+    Fixed,
+}
+
+impl VelocityCurve {
+    // amount of a note struck at `velocity`, 0..1
+    pub fn gain(self, velocity: u8) -> f32 {
+        let velocity = velocity.min(127) as f32 / 127.0;
+        match self {
+            VelocityCurve::Linear => velocity,
+            VelocityCurve::Soft => velocity.sqrt(),
+            VelocityCurve::Hard => velocity * velocity,
+            VelocityCurve::Fixed => 1.0,
+        }
+    }
+
+    // The curve after this one, round to the first again
+    pub fn next(self) -> Self {
+        match self {
+            VelocityCurve::Linear => VelocityCurve::Soft,
+            VelocityCurve::Soft => VelocityCurve::Hard,
+            VelocityCurve::Hard => VelocityCurve::Fixed,
+            VelocityCurve::Fixed => VelocityCurve::Linear,
+        }
+    }
+}
+
+impl FromStr for VelocityCurve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "linear" => Ok(VelocityCurve::Linear),
+            "soft" | "logarithmic" | "log" => Ok(VelocityCurve::Soft),
+            "hard" | "exponential" | "exp" => Ok(VelocityCurve::Hard),
+            "fixed" => Ok(VelocityCurve::Fixed),
+            _ => Err(format!("unknown velocity curve {:?}", s)),
+        }
+    }
+}
+
+// What note-on velocity, through the velocity curve, changes
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum VelocityDest {
+    // loudness
+    Amp,
+    // brightness: full velocity plays at the patch's cutoff, softer notes close the filter
+    Cutoff,
+    Both,
+}
+
+impl FromStr for VelocityDest {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "amp" => Ok(VelocityDest::Amp),
+            "cutoff" => Ok(VelocityDest::Cutoff),
+            "both" => Ok(VelocityDest::Both),
+            _ => Err(format!("unknown velocity destination {:?}", s)),
+        }
+    }
+}
+
+// Where in the stereo field each new note is placed, within +-pan_spread of its channel's pan
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PanMode {
+    // every note at the channel's pan
+    Fixed,
+    // a random place for each note, humanizing
+    Random,
+    // by pitch, the lowest MIDI note fully left and the highest fully right like a piano
+    KeyFollow,
+    // notes take turns at places spread across the field, a chord fans out
+    RoundRobin,
+}
+
+// places round-robin notes take in turn, as fractions of the spread
+const ROUND_ROBIN_PANS: [f32; 4] = [-1.0, 1.0, -1.0 / 3.0, 1.0 / 3.0];
+
+impl FromStr for PanMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "fixed" => Ok(PanMode::Fixed),
+            "random" => Ok(PanMode::Random),
+            "key" | "keyfollow" => Ok(PanMode::KeyFollow),
+            "roundrobin" | "rr" => Ok(PanMode::RoundRobin),
+            _ => Err(format!("unknown pan mode {:?}", s)),
+        }
+    }
+}
+
+// A note as (channel, note number), so the same key on two channels are separate voices
+type NoteKey = (u8, u8);
+
+pub const DEFAULT_GLIDE_MS: f32 = 50.0;
+pub const DEFAULT_BEND_RANGE: f32 = 2.0;
+// time a bend takes to follow the wheel, long enough to hide its steps
+pub const DEFAULT_BEND_SMOOTHING_MS: f32 = DEFAULT_SMOOTHING_MS;
+pub const DEFAULT_UNISON_DETUNE: f32 = 15.0;
+// how far the softest note closes the filter when velocity goes to the cutoff
+pub const DEFAULT_VELOCITY_CUTOFF_OCTAVES: f32 = 3.0;
+// longest glide the portamento time CC reaches
+pub const MAX_GLIDE_MS: f32 = 2000.0;
+// furthest the keyboard can be transposed either way, in semitones
+pub const MAX_TRANSPOSE: i8 = 48;
+
+// Fraction of the remaining distance to the target mix gain covered per sample (~20 ms)
+const MIX_GAIN_SMOOTHING: f32 = 0.001;
+
+// Time constant of the mute fade, the output is down 30 dB about 20 ms after muting
+const MUTE_FADE_MS: f32 = 5.0;
+
+// Velocity is scaled by this with the soft pedal (CC67) fully down
+const SOFT_PEDAL_SCALE: f32 = 0.5;
+
+// CCs that set the master EQ band gains, centre (64) is flat
+pub const CC_EQ_LOW: u8 = 20;
+pub const CC_EQ_MID: u8 = 21;
+pub const CC_EQ_HIGH: u8 = 22;
+// CC that sets the formant filter vowel of a channel, turning it inserts the filter
+pub const CC_VOWEL: u8 = 23;
+// CC that sets the color of a channel's noise wave, 0 is white and 127 brown
+pub const CC_NOISE_COLOR: u8 = 24;
+// CC that sets the width of a channel's pulse wave
+pub const CC_PULSE_WIDTH: u8 = 25;
+// CCs for a channel's low-pass filter (sound controllers 5 and 2 in General MIDI)
+pub const CC_CUTOFF: u8 = 74;
+pub const CC_RESONANCE: u8 = 71;
+// CC for how far the filter envelope sweeps the cutoff, 64 is none
+pub const CC_FILTER_ENV_AMOUNT: u8 = 79;
+// filter envelope sweep at either end of its CC, in octaves
+pub const MAX_FILTER_ENV_OCTAVES: f32 = 8.0;
+// CCs for the master delay (effect controls 1 and 2, and effects 4 depth)
+pub const CC_DELAY_TIME: u8 = 12;
+pub const CC_DELAY_FEEDBACK: u8 = 13;
+pub const CC_DELAY_MIX: u8 = 94;
+// CCs for the master chorus (effects 3 depth, and two undefined controllers)
+pub const CC_CHORUS_MIX: u8 = 93;
+pub const CC_CHORUS_RATE: u8 = 26;
+pub const CC_CHORUS_DEPTH: u8 = 27;
+// chorus sweep rate at the top of its CC
+const MAX_CHORUS_RATE_HZ: f32 = 5.0;
+// CCs for the rate and depth of LFO 1 (vibrato rate and depth in General MIDI)
+pub const CC_LFO_RATE: u8 = 76;
+pub const CC_LFO_DEPTH: u8 = 77;
+// vibrato the mod wheel brings in by default, in semitones at full wheel
+const DEFAULT_VIBRATO_SEMITONES: f32 = 0.5;
+// filter opening aftertouch brings in by default, in octaves at full pressure
+const DEFAULT_PRESSURE_OCTAVES: f32 = 2.0;
+// boost/cut at the ends of the EQ CC range
+const EQ_CC_RANGE_DB: f32 = 12.0;
+
+// map a CC value to an EQ gain, 64 is 0 dB
+fn eq_cc_to_db(value: u8) -> f32 {
+    ((value as f32 - 64.0) / 63.0).clamp(-1.0, 1.0) * EQ_CC_RANGE_DB
+}
+
+// The whole instrument: voice allocation, MIDI handling and the mix of all sounding voices
+pub struct Synth {
+    // one patch per MIDI channel
+    pub patches: [Patch; MIDI_CHANNELS],
+    // maximum analog-style detune in cents, 0 means perfectly stable
+    pub drift_amount: f32,
+    // mono mode: one voice per channel, a note played while another is held takes over its
+    // voice (CC126 on, CC127 off)
+    pub mono: bool,
+    // mono mode: whether a new note while another is held restarts the envelope
+    pub retrigger: bool,
+    // portamento: new notes slide in from the previous note of their channel (CC65)
+    pub glide: bool,
+    // how long a glide takes, whatever the interval (CC5)
+    pub glide_ms: f32,
+    // semitones of a full pitch bend, up or down
+    pub bend_range: f32,
+    // how long a bend takes to catch up with the wheel, evening out its steps
+    pub bend_smoothing_ms: f32,
+    // oscillators stacked per note (1 is off) and how far the outer ones are detuned, in cents
+    pub unison_voices: usize,
+    pub unison_detune: f32,
+    // fingered portamento: only glide when the previous note is still held (legato playing)
+    pub fingered_glide: bool,
+    // per-waveform gain so switching waves keeps the level steady
+    pub wave_trims: WaveTrims,
+    // how far new voices are spread either side of their channel's pan, and how they are
+    // placed. 0 keeps all centred.
+    pub pan_spread: f32,
+    pub pan_mode: PanMode,
+    // scale the mix down as more voices sound at once
+    pub auto_gain: bool,
+    pub auto_gain_law: f32,
+    // notes that trigger a one-shot sample instead of the synth voice
+    pub drum_map: DrumMap,
+    // channel whose keys play the drum kit rather than the patch, unmapped keys staying silent
+    pub drum_channel: Option<u8>,
+    // the drum kit's sounds, rendered at the sample rate
+    kit_hits: DrumMap,
+    pub note_priority: NotePriority,
+    pub trigger_mode: TriggerMode,
+    pub velocity_curve: VelocityCurve,
+    pub velocity_dest: VelocityDest,
+    // octaves the cutoff closes by from full velocity down to none, with velocity to cutoff
+    pub velocity_cutoff: f32,
+    // follows the level of the mix, a modulation source for dynamics-driven effects
+    pub follower: EnvelopeFollower,
+    pub mod_matrix: ModMatrix,
+    // the mod matrix's lfo1 and lfo2 sources
+    pub lfos: [Lfo; 2],
+    // pattern and rate of the arpeggiator, see set_arp to turn it on
+    pub arp: Arpeggiator,
+    // tempo of the arpeggiator and synced LFOs, following MIDI clock when it comes in
+    pub clock: MidiClock,
+    // distortion and lo-fi grit on the voices' mix, after their filters and ahead of the
+    // effects so the effects' tails stay clean
+    pub shaper: Shaper,
+    pub crusher: Crusher,
+    // tone shaping on the master mix
+    pub eq: ThreeBandEq,
+    // thickens the EQ'd mix, off until its mix is turned up
+    pub chorus: Chorus,
+    // echoes of the chorused mix
+    pub delay: Delay,
+    // evens out the dynamics of the mix and its echoes
+    pub compressor: Compressor,
+    // overall level after the effects, linear
+    pub master_gain: f32,
+    // a note held longer than this (not by the sustain pedal or latch) is taken for one whose
+    // note-off got lost and is released. None never releases a note by itself.
+    pub note_timeout_secs: Option<f32>,
+    // output faded to silence, everything else carries on underneath
+    muted: bool,
+    // keeps the output from clipping, last in the chain
+    pub limiter: Limiter,
+    // one slot per voice of polyphony
+    voices: Vec<Option<Voice>>,
+    // voices that lost their slot, to voice stealing or a repeat of their key, finishing their
+    // release outside the slots
+    stolen: Vec<Voice>,
+    // held (or sustained) notes and the slot they sound in
+    playing_notes: HashMap<NoteKey, usize>,
+    // sustain pedal (CC64) position per channel
+    pedal_down: [bool; MIDI_CHANNELS],
+    // keys let go while the pedal is down, sounding until it comes up
+    sustained_notes: HashSet<NoteKey>,
+    // latch mode: note-ons toggle notes, note-offs are ignored
+    latch: bool,
+    latched_notes: HashSet<NoteKey>,
+    // arpeggiator on: keys go to it and it plays the notes
+    arp_on: bool,
+    // mono mode: keys held down, most recent last, to fall back to when the top one is let go
+    mono_held: Vec<NoteKey>,
+    // semitones added to every key played, see set_transpose
+    transpose: i8,
+    // keys played while transposed and the notes they sound, so each key-up lets go of the
+    // note its key-down started even if the transpose has changed since
+    transposed_keys: HashMap<NoteKey, u8>,
+    mix_gain: f32,
+    // the channels' cutoff (in octaves, log2 Hz), volume and pan, the LFO depths and the
+    // master gain, gliding toward their settings so knob and CC moves don't zipper
+    smoothed_cutoff: [SmoothedParam; MIDI_CHANNELS],
+    smoothed_volume: [SmoothedParam; MIDI_CHANNELS],
+    smoothed_pan: [SmoothedParam; MIDI_CHANNELS],
+    smoothed_lfo_depth: [SmoothedParam; 2],
+    smoothed_master_gain: SmoothedParam,
+    mute_gain: SmoothedParam,
+    // Every random source is derived from this seed, so with a fixed seed the same input
+    // renders bit-identical output. Current consumers: the per-voice drift walk, the
+    // humanized pan and the arpeggiator's random mode.
+    seed: u32,
+    rng: XorShift32,
+    // counts note-ons, gives every voice its start order
+    note_count: u64,
+    // pitch of the most recent note on each channel, where the next glide starts
+    last_freq: [Option<f32>; MIDI_CHANNELS],
+    // latest controller values per channel, 0..1
+    mod_wheel: [f32; MIDI_CHANNELS],
+    // current pitch bend of each channel, in semitones (so in the log-frequency domain),
+    // gliding toward the wheel's position
+    bend: [SmoothedParam; MIDI_CHANNELS],
+    aftertouch: [f32; MIDI_CHANNELS],
+    soft_pedal: [f32; MIDI_CHANNELS],
+    // samples rendered so far, drives the control-rate updates
+    sample_count: usize,
+    // samples between control ticks
+    control_period: usize,
+}
+
+impl Default for Synth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Synth {
+    pub fn new() -> Self {
+        Self::with_polyphony(DEFAULT_POLYPHONY)
+    }
+
+    // A synth with room for `polyphony` simultaneous voices (clamped to 1..=MAX_POLYPHONY)
+    pub fn with_polyphony(polyphony: usize) -> Self {
+        let polyphony = polyphony.clamp(1, MAX_POLYPHONY);
+        let seed = entropy_seed();
+        Self {
+            patches: std::array::from_fn(|_| Patch::default()),
+            drift_amount: 0.0,
+            retrigger: true,
+            mono: false,
+            glide: false,
+            glide_ms: DEFAULT_GLIDE_MS,
+            bend_range: DEFAULT_BEND_RANGE,
+            bend_smoothing_ms: DEFAULT_BEND_SMOOTHING_MS,
+            unison_voices: 1,
+            unison_detune: DEFAULT_UNISON_DETUNE,
+            fingered_glide: false,
+            wave_trims: WaveTrims::default(),
+            pan_spread: 0.0,
+            pan_mode: PanMode::Random,
+            auto_gain: false,
+            auto_gain_law: 0.5,
+            drum_map: HashMap::new(),
+            drum_channel: Some(GM_DRUM_CHANNEL),
+            kit_hits: render_drum_kit(&gm_drum_kit()),
+            note_priority: NotePriority::Oldest,
+            trigger_mode: TriggerMode::Trigger,
+            velocity_curve: VelocityCurve::Linear,
+            velocity_dest: VelocityDest::Amp,
+            velocity_cutoff: DEFAULT_VELOCITY_CUTOFF_OCTAVES,
+            follower: EnvelopeFollower::default(),
+            // the mod wheel brings in vibrato and pressure opens the filter until other routes
+            // are loaded
+            mod_matrix: ModMatrix {
+                routes: vec![
+                    ModRoute {
+                        source: ModSource::Lfo1,
+                        dest: ModDest::Pitch,
+                        amount: DEFAULT_VIBRATO_SEMITONES,
+                    },
+                    ModRoute {
+                        source: ModSource::Aftertouch,
+                        dest: ModDest::Cutoff,
+                        amount: DEFAULT_PRESSURE_OCTAVES,
+                    },
+                ],
+            },
+            lfos: [
+                Lfo::new(LfoShape::Sine, DEFAULT_LFO_RATE_HZ, 1.0, true),
+                Lfo::default(),
+            ],
+            arp: Arpeggiator::default(),
+            clock: MidiClock::default(),
+            shaper: Shaper::default(),
+            crusher: Crusher::default(),
+            eq: ThreeBandEq::default(),
+            chorus: Chorus::default(),
+            delay: Delay::default(),
+            compressor: Compressor::default(),
+            master_gain: 1.0,
+            muted: false,
+            note_timeout_secs: None,
+            limiter: Limiter::default(),
+            voices: (0..polyphony).map(|_| None).collect(),
+            stolen: Vec::with_capacity(MAX_POLYPHONY),
+            playing_notes: HashMap::new(),
+            pedal_down: [false; MIDI_CHANNELS],
+            sustained_notes: HashSet::new(),
+            latch: false,
+            arp_on: false,
+            latched_notes: HashSet::new(),
+            mono_held: Vec::new(),
+            transpose: 0,
+            transposed_keys: HashMap::new(),
+            mix_gain: 1.0,
+            smoothed_cutoff: [SmoothedParam::new(DEFAULT_CUTOFF_HZ.log2(), DEFAULT_SMOOTHING_MS);
+                MIDI_CHANNELS],
+            smoothed_volume: [SmoothedParam::new(1.0, DEFAULT_SMOOTHING_MS); MIDI_CHANNELS],
+            smoothed_pan: [SmoothedParam::new(0.0, DEFAULT_SMOOTHING_MS); MIDI_CHANNELS],
+            smoothed_lfo_depth: [SmoothedParam::new(1.0, DEFAULT_SMOOTHING_MS); 2],
+            smoothed_master_gain: SmoothedParam::new(1.0, DEFAULT_SMOOTHING_MS),
+            mute_gain: SmoothedParam::new(1.0, MUTE_FADE_MS),
+            seed,
+            rng: XorShift32::new(seed),
+            note_count: 0,
+            last_freq: [None; MIDI_CHANNELS],
+            mod_wheel: [0.0; MIDI_CHANNELS],
+            bend: [SmoothedParam::new(0.0, DEFAULT_BEND_SMOOTHING_MS); MIDI_CHANNELS],
+            aftertouch: [0.0; MIDI_CHANNELS],
+            soft_pedal: [0.0; MIDI_CHANNELS],
+            sample_count: 0,
+            control_period: (sample_rate() as f32 / DEFAULT_CONTROL_RATE) as usize,
+        }
+    }
+
+    pub fn polyphony(&self) -> usize {
+        self.voices.len()
+    }
+
+    pub fn control_rate(&self) -> f32 {
+        sample_rate() as f32 / self.control_period as f32
+    }
+
+    // Set how often (in Hz) envelopes and the mod matrix are evaluated. Every tick costs a
+    // mod matrix evaluation per voice, so a lower rate saves CPU, but envelope segments and
+    // modulation move in coarser steps (at 1 kHz a step is 1 ms, fine for anything but the
+    // snappiest attacks). Rounded to a whole number of samples, at most the sample rate.
+    pub fn set_control_rate(&mut self, hz: f32) {
+        self.control_period = (sample_rate() as f32 / hz.max(1.0)).round().max(1.0) as usize;
+    }
+
+    pub fn seed(&self) -> u32 {
+        self.seed
+    }
+
+    // Restart all randomness from `seed`; set it before playing for a reproducible run
+    pub fn set_seed(&mut self, seed: u32) {
+        self.seed = seed;
+        self.rng = XorShift32::new(seed);
+    }
+
+    // Hand out a different (but reproducible) drift seed to every new voice
+    fn next_drift_seed(&mut self) -> u32 {
+        self.rng.next_u32()
+    }
+
+    // Use the same waveform on every channel
+    pub fn set_wave(&mut self, wave_type: WaveType) {
+        for patch in self.patches.iter_mut() {
+            patch.wave_type = wave_type.clone();
+        }
+    }
+
+    // Use the same amp envelope on every channel
+    pub fn set_adsr(&mut self, adsr: Adsr) {
+        for patch in self.patches.iter_mut() {
+            patch.adsr = adsr;
+        }
+    }
+
+    // Shift every key played from now on by `semitones`, clamped to +-MAX_TRANSPOSE. Keys that
+    // would land outside the MIDI range play the nearest note in it.
+    pub fn set_transpose(&mut self, semitones: i8) {
+        self.transpose = semitones.clamp(-MAX_TRANSPOSE, MAX_TRANSPOSE);
+    }
+
+    pub fn transpose(&self) -> i8 {
+        self.transpose
+    }
+
+    // Sounds for the keys of the drum channel
+    pub fn set_drum_kit(&mut self, kit: &DrumKit) {
+        self.kit_hits = render_drum_kit(kit);
+    }
+
+    fn is_drum_channel(&self, channel: u8) -> bool {
+        self.drum_channel == Some(channel)
+    }
+
+    // The one-shot a key plays instead of a synth voice: its drum-map sample on any channel,
+    // or on the drum channel its sound from the kit
+    fn drum_hit(&self, channel: u8, note: u8) -> Option<&Arc<Vec<f32>>> {
+        self.drum_map.get(&note).or_else(|| {
+            self.kit_hits
+                .get(&note)
+                .filter(|_| self.is_drum_channel(channel))
+        })
+    }
+
+    // The note a key plays, remembering it for the key-up. Drum pads keep their sounds.
+    fn transpose_key(&mut self, channel: u8, note: u8) -> u8 {
+        if self.transpose == 0 || self.is_drum_channel(channel) || self.drum_map.contains_key(&note)
+        {
+            self.transposed_keys.remove(&(channel, note));
+            return note;
+        }
+        let sounding = (note as i16 + self.transpose as i16).clamp(0, 127) as u8;
+        self.transposed_keys.insert((channel, note), sounding);
+        sounding
+    }
+
+    pub fn note_on(&mut self, channel: u8, note: u8, velocity: u8) {
+        let note = self.transpose_key(channel, note);
+        let drum = self.drum_hit(channel, note).is_some();
+        // the drum channel only sounds the keys its kit has
+        if self.is_drum_channel(channel) && !drum {
+            return;
+        }
+        // drum hits still play straight away
+        if self.arp_on && !drum {
+            self.arp.press(channel, note, velocity);
+            return;
+        }
+        let key = (channel, note);
+        // in latch mode a second press of a latched key is what lets it go
+        if self.latch && self.latched_notes.remove(&key) {
+            self.release_key(key);
+            return;
+        }
+        self.start_note(channel, note, velocity);
+        if self.latch && self.playing_notes.contains_key(&key) {
+            self.latched_notes.insert(key);
+        }
+    }
+
+    fn start_note(&mut self, channel: u8, note: u8, velocity: u8) {
+        // the soft pedal plays every new note as if struck more gently
+        let soft = 1.0 - self.soft_pedal[channel as usize] * (1.0 - SOFT_PEDAL_SCALE);
+        let velocity = (velocity as f32 * soft).round() as u8;
+
+        // drums keep ringing over each other whatever the mode
+        if self.mono && self.drum_hit(channel, note).is_none() {
+            let key = (channel, note);
+            self.mono_held.retain(|&held| held != key);
+            // legato: the channel's sounding note hands its voice over
+            let previous = self.mono_held.iter().rev().find(|held| held.0 == channel);
+            let moved = previous
+                .copied()
+                .is_some_and(|previous| self.move_mono_voice(previous, key, self.retrigger));
+            self.mono_held.push(key);
+            if moved {
+                return;
+            }
+        }
+
+        // a repeat of a key that is still sounding (held, sustained or releasing) takes over its
+        // slot
+        let sounds_key = |voice: &Option<Voice>| {
+            voice
+                .as_ref()
+                .is_some_and(|voice| voice.channel == channel && voice.note == note)
+        };
+        let existing = self
+            .playing_notes
+            .get(&(channel, note))
+            .copied()
+            .filter(|&slot| sounds_key(&self.voices[slot]))
+            .or_else(|| self.voices.iter().position(sounds_key));
+        let key = (channel, note);
+        // down rather than only kept sounding by the pedal
+        let held = self.playing_notes.contains_key(&key) && !self.sustained_notes.contains(&key);
+        if let Some(slot) = existing {
+            // controllers that repeat note-ons along with aftertouch don't double the note
+            if self.trigger_mode == TriggerMode::Ignore && held {
+                return;
+            }
+            self.sustained_notes.remove(&key);
+            match self.trigger_mode {
+                // the note picks up again from wherever its envelope is, or starts it over
+                TriggerMode::Gate | TriggerMode::Reuse => {
+                    if let Some(mut voice) = self.voices[slot].take() {
+                        self.set_velocity(&mut voice, velocity);
+                        voice.struck = self.sample_count;
+                        if self.trigger_mode == TriggerMode::Reuse {
+                            voice.restart_envelope();
+                        } else {
+                            voice.resume();
+                        }
+                        self.voices[slot] = Some(voice);
+                    }
+                    self.playing_notes.insert(key, slot);
+                    return;
+                }
+                // the old voice releases on its own and a fresh one starts in its slot, rather
+                // than restarting a voice half way through its release
+                TriggerMode::Trigger | TriggerMode::Ignore => {
+                    self.playing_notes.remove(&key);
+                    if let Some(mut voice) = self.voices[slot].take() {
+                        voice.stop();
+                        self.retire(voice);
+                    }
+                }
+            }
+        }
+
+        // fingered glide needs another note of the channel still down when this one starts
+        let legato = self.playing_notes.keys().any(|key| key.0 == channel);
+        let glide_from = self.last_freq[channel as usize]
+            .filter(|_| self.glide && (legato || !self.fingered_glide));
+
+        let slot = existing
+            .or_else(|| self.voices.iter().position(|voice| voice.is_none()))
+            .or_else(|| self.steal_slot(note));
+        if let Some(slot) = slot {
+            let freq = midi_note_to_freq(note);
+            let drift_seed = self.next_drift_seed();
+            let mut voice = match self.drum_hit(channel, note) {
+                // drum hits play their sample untouched at its own pitch
+                Some(pcm) => Voice::new(
+                    freq,
+                    WaveType::Sample {
+                        pcm: pcm.clone(),
+                        root: note,
+                    },
+                    Adsr::bypass(),
+                    0.0,
+                    drift_seed,
+                ),
+                None => {
+                    let patch = &self.patches[channel as usize];
+                    let mut voice = Voice::new(
+                        freq,
+                        patch.wave_type.clone(),
+                        patch.adsr,
+                        self.drift_amount,
+                        drift_seed,
+                    );
+                    if let Some(layer) = &patch.velocity_layer {
+                        voice.set_layer(layer.hard.clone(), layer.hard_gain(velocity));
+                    }
+                    if patch.osc2.on {
+                        let osc2 = &patch.osc2;
+                        voice.set_osc2(osc2.wave_type.clone(), osc2.ratio(), osc2.mix);
+                    }
+                    // the whole stack sounds in the one slot
+                    voice.set_unison(self.unison_voices, self.unison_detune);
+                    voice
+                }
+            };
+            self.note_count += 1;
+            voice.note = note;
+            voice.started = self.note_count;
+            voice.struck = self.sample_count;
+            voice.set_trims(self.wave_trims);
+            voice.channel = channel;
+            // drum hits are dynamic too, their envelope is bypassed but not their level
+            self.set_velocity(&mut voice, velocity);
+            // drawn whatever the mode and spread so the random sequence doesn't depend on them
+            let random = self.rng.next_bipolar();
+            let place = match self.pan_mode {
+                PanMode::Fixed => 0.0,
+                PanMode::Random => random,
+                PanMode::KeyFollow => note as f32 / 127.0 * 2.0 - 1.0,
+                PanMode::RoundRobin => {
+                    ROUND_ROBIN_PANS[self.note_count as usize % ROUND_ROBIN_PANS.len()]
+                }
+            };
+            voice.pan = (place * self.pan_spread).clamp(-1.0, 1.0);
+            voice.set_vowel(self.patches[channel as usize].vowel);
+            if let Some(from) = glide_from {
+                voice.glide_from(from, self.glide_ms);
+            }
+            self.last_freq[channel as usize] = Some(freq);
+            // start the envelope now rather than on the next control tick
+            self.control_voice(&mut voice);
+            self.voices[slot] = Some(voice);
+            self.playing_notes.insert((channel, note), slot);
+        }
+    }
+
+    // Free up a busy slot for `note` according to the note priority. Returns None when the new
+    // note is itself the one that should give way.
+    fn steal_slot(&mut self, note: u8) -> Option<usize> {
+        let sounding = self
+            .voices
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, voice)| voice.as_ref().map(|voice| (slot, voice)));
+        let slot = match self.note_priority {
+            // notes already released go first, they are on their way out anyway
+            NotePriority::Oldest => {
+                sounding
+                    .min_by_key(|(_, voice)| (!voice.is_releasing(), voice.started))?
+                    .0
+            }
+            NotePriority::Low => {
+                let (slot, highest) = sounding.max_by_key(|(_, voice)| voice.note)?;
+                if note > highest.note {
+                    return None;
+                }
+                slot
+            }
+            NotePriority::High => {
+                let (slot, lowest) = sounding.min_by_key(|(_, voice)| voice.note)?;
+                if note < lowest.note {
+                    return None;
+                }
+                slot
+            }
+        };
+
+        // forget whichever note was using the slot
+        let stolen: Vec<NoteKey> = self
+            .playing_notes
+            .iter()
+            .filter(|(_, &note_slot)| note_slot == slot)
+            .map(|(&key, _)| key)
+            .collect();
+        for key in stolen {
+            self.playing_notes.remove(&key);
+            self.sustained_notes.remove(&key);
+            self.latched_notes.remove(&key);
+        }
+        // let the stolen note fade rather than cut it off with a click
+        if let Some(mut voice) = self.voices[slot].take() {
+            voice.fade_out();
+            self.retire(voice);
+        }
+        Some(slot)
+    }
+
+    // Keep a voice that lost its slot sounding until it finishes
+    fn retire(&mut self, voice: Voice) {
+        if self.stolen.len() == MAX_POLYPHONY {
+            self.stolen.remove(0);
+        }
+        self.stolen.push(voice);
+    }
+
+    // Mono mode: take the voice playing `from` over to `to`, gliding if portamento is on.
+    // Returns false when `from` has no voice (any more).
+    fn move_mono_voice(&mut self, from: NoteKey, to: NoteKey, retrigger: bool) -> bool {
+        let Some(slot) = self.playing_notes.remove(&from) else {
+            return false;
+        };
+        let glide_ms = if self.glide { self.glide_ms } else { 0.0 };
+        let freq = midi_note_to_freq(to.1);
+        if let Some(voice) = &mut self.voices[slot] {
+            voice.note = to.1;
+            voice.struck = self.sample_count;
+            voice.retarget(freq, retrigger);
+            voice.glide(glide_ms);
+        }
+        self.sustained_notes.remove(&from);
+        self.playing_notes.insert(to, slot);
+        self.last_freq[to.0 as usize] = Some(freq);
+        true
+    }
+
+    pub fn note_off(&mut self, channel: u8, note: u8) {
+        let note = self
+            .transposed_keys
+            .remove(&(channel, note))
+            .unwrap_or(note);
+        if self.arp_on && self.arp.release(channel, note) {
+            return;
+        }
+        self.key_up(channel, note);
+    }
+
+    fn key_up(&mut self, channel: u8, note: u8) {
+        let key = (channel, note);
+        // latched notes ignore their key coming up
+        if self.latched_notes.contains(&key) {
+            return;
+        }
+        if self.mono {
+            self.mono_held.retain(|&held| held != key);
+            // letting go of the sounding note returns to the last one still held
+            let previous = self.mono_held.iter().rev().find(|held| held.0 == channel);
+            if let Some(&previous) = previous {
+                if !self.pedal_down[channel as usize] && self.move_mono_voice(key, previous, false)
+                {
+                    return;
+                }
+            }
+        }
+        self.release_key(key);
+    }
+
+    // Let a note go, unless the sustain pedal is down and takes it over
+    fn release_key(&mut self, key: NoteKey) {
+        if self.pedal_down[key.0 as usize] && self.playing_notes.contains_key(&key) {
+            self.sustained_notes.insert(key);
+            return;
+        }
+        self.stop_key(key);
+    }
+
+    // Release the key's voice and forget the key, whatever was holding it
+    fn stop_key(&mut self, key: NoteKey) {
+        self.sustained_notes.remove(&key);
+        if let Some(slot) = self.playing_notes.remove(&key) {
+            if let Some(voice) = &mut self.voices[slot] {
+                voice.stop();
+            }
+        }
+    }
+
+    // Forget keys whose voice has died away (or was taken) underneath them, and release
+    // notes held past the timeout, which most likely lost their note-off
+    fn reap_notes(&mut self) {
+        let now = self.sample_count;
+        let timeout = self
+            .note_timeout_secs
+            .map(|secs| (secs.max(0.0) * sample_rate() as f32) as usize);
+        let mut stuck = Vec::new();
+        self.playing_notes.retain(|key, &mut slot| {
+            let Some(voice) = &self.voices[slot] else {
+                return false;
+            };
+            if (voice.channel, voice.note) != *key {
+                return false;
+            }
+            let held_for = now.wrapping_sub(voice.struck);
+            if timeout.is_some_and(|timeout| held_for > timeout)
+                && !self.sustained_notes.contains(key)
+                && !self.latched_notes.contains(key)
+            {
+                stuck.push(*key);
+            }
+            true
+        });
+        let playing = &self.playing_notes;
+        self.sustained_notes.retain(|key| playing.contains_key(key));
+        self.latched_notes.retain(|key| playing.contains_key(key));
+        for key in stuck {
+            self.mono_held.retain(|&held| held != key);
+            self.stop_key(key);
+        }
+    }
+
+    // Pedal up lets go of the notes whose keys came up while it was down. Keys still held
+    // (or latched) play on.
+    pub fn sustain_pedal(&mut self, channel: u8, down: bool) {
+        self.pedal_down[channel as usize] = down;
+        if !down {
+            let sustained: Vec<NoteKey> = self
+                .sustained_notes
+                .iter()
+                .filter(|key| key.0 == channel)
+                .copied()
+                .collect();
+            for key in sustained {
+                self.stop_key(key);
+            }
+        }
+    }
+
+    // All Notes Off: release every note of the channel, whatever is holding it (sustain
+    // pedal, latch or a key that never came up)
+    pub fn all_notes_off(&mut self, channel: u8) {
+        self.sustained_notes.retain(|key| key.0 != channel);
+        self.latched_notes.retain(|key| key.0 != channel);
+        self.mono_held.retain(|key| key.0 != channel);
+        self.transposed_keys.retain(|key, _| key.0 != channel);
+        let keys: Vec<NoteKey> = self
+            .playing_notes
+            .keys()
+            .filter(|key| key.0 == channel)
+            .copied()
+            .collect();
+        for key in keys {
+            self.stop_key(key);
+        }
+    }
+
+    // All Sound Off: silence the channel at once, releases included, freeing its voices
+    pub fn all_sound_off(&mut self, channel: u8) {
+        self.all_notes_off(channel);
+        for slot in self.voices.iter_mut() {
+            if slot.as_ref().is_some_and(|voice| voice.channel == channel) {
+                *slot = None;
+            }
+        }
+        self.stolen.retain(|voice| voice.channel != channel);
+    }
+
+    // Let go of every note on every channel and fade all voices out quickly, one-shots and
+    // releases included, so the output can be closed without a click or a hanging note
+    pub fn fade_out_all(&mut self) {
+        for channel in 0..MIDI_CHANNELS as u8 {
+            self.all_notes_off(channel);
+        }
+        for voice in self.voices.iter_mut().flatten() {
+            voice.fade_out();
+        }
+        for voice in self.stolen.iter_mut() {
+            voice.fade_out();
+        }
+    }
+
+    pub fn set_mono(&mut self, mono: bool) {
+        self.mono = mono;
+        self.mono_held.clear();
+    }
+
+    // Turn latch mode on or off. Turning it off lets go of every latched note.
+    pub fn set_latch(&mut self, latch: bool) {
+        self.latch = latch;
+        if !latch {
+            self.clear_latch();
+        }
+    }
+
+    pub fn latch(&self) -> bool {
+        self.latch
+    }
+
+    // Fade the output out, or back in. Notes keep playing silently.
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    pub fn muted(&self) -> bool {
+        self.muted
+    }
+
+    // Turn the arpeggiator on or off. Turning it off lets go of its note and the keys it holds.
+    pub fn set_arp(&mut self, on: bool) {
+        self.arp_on = on;
+        if !on {
+            if let Some((channel, note)) = self.arp.clear() {
+                self.key_up(channel, note);
+            }
+        }
+    }
+
+    pub fn arp(&self) -> bool {
+        self.arp_on
+    }
+
+    // Let go of every latched note, the sustain pedal still holds the ones it caught
+    pub fn clear_latch(&mut self) {
+        for key in std::mem::take(&mut self.latched_notes) {
+            self.release_key(key);
+        }
+    }
+
+    // value is the full 14-bit bend, 8192 means no bend. Sounding notes of the channel follow
+    // at the next control tick, new notes start bent.
+    pub fn pitch_bend(&mut self, channel: u8, value: u16) {
+        let amount = (value.min(16383) as f32 - 8192.0) / 8192.0;
+        self.bend[channel as usize].set(amount * self.bend_range);
+    }
+
+    pub fn handle_midi(&mut self, message: &[u8]) {
+        let Some(&status) = message.first() else {
+            return;
+        };
+        // system real-time messages are a single byte
+        match status {
+            // MIDI clock
+            0xF8 => return self.clock.tick(),
+            // start: the arpeggiator and LFOs from the top, in step with the sequencer
+            0xFA => {
+                self.arp.restart();
+                for lfo in self.lfos.iter_mut() {
+                    lfo.restart();
+                }
+                return;
+            }
+            // continue picks up where it stopped, the clock ticks carry on from there
+            0xFB => return,
+            // stop
+            0xFC => return self.clock.stop(),
+            _ => {}
+        }
+        let channel = status & 0x0F;
+        // the other one-byte messages (active sensing, reset, tune request) are ignored
+        let Some(&data1) = message.get(1) else {
+            return;
+        };
+
+        match status {
+            // note on, with velocity 0 a note off
+            144..=159 => {
+                // a message cut short before its second data byte is dropped
+                let Some(&velocity) = message.get(2) else {
+                    return;
+                };
+                if velocity == 0 {
+                    self.note_off(channel, data1);
+                } else {
+                    self.note_on(channel, data1, velocity);
+                }
+            }
+            // note off
+            128..=143 => self.note_off(channel, data1),
+            // mode change
+            176..=191 => {
+                let Some(&data2) = message.get(2) else {
+                    return;
+                };
+                match data1 {
+                    // mod wheel
+                    1 => self.mod_wheel[channel as usize] = data2 as f32 / 127.0,
+                    // pan, 64 is centre
+                    10 => {
+                        let pan = ((data2 as f32 - 64.0) / 63.0).clamp(-1.0, 1.0);
+                        self.patches[channel as usize].pan = pan;
+                    }
+                    // channel volume, squared for the General MIDI loudness curve
+                    7 => self.patches[channel as usize].volume = (data2 as f32 / 127.0).powi(2),
+                    // sustain pedal, down from 64 up
+                    64 => self.sustain_pedal(channel, data2 >= 64),
+                    CC_EQ_LOW => self.eq.set_low(eq_cc_to_db(data2)),
+                    CC_EQ_MID => self.eq.set_mid(eq_cc_to_db(data2)),
+                    CC_EQ_HIGH => self.eq.set_high(eq_cc_to_db(data2)),
+                    // squared for finer control of short (slapback) delays
+                    CC_DELAY_TIME => self
+                        .delay
+                        .set_time((data2 as f32 / 127.0).powi(2) * MAX_DELAY_MS),
+                    CC_DELAY_FEEDBACK => {
+                        self.delay.feedback = data2 as f32 / 127.0 * MAX_DELAY_FEEDBACK
+                    }
+                    CC_DELAY_MIX => self.delay.mix = data2 as f32 / 127.0,
+                    CC_CHORUS_MIX => self.chorus.mix = data2 as f32 / 127.0,
+                    // squared, the slow sweeps are the useful ones
+                    CC_CHORUS_RATE => {
+                        self.chorus.rate_hz = (data2 as f32 / 127.0).powi(2) * MAX_CHORUS_RATE_HZ
+                    }
+                    CC_CHORUS_DEPTH => {
+                        self.chorus.depth_ms = data2 as f32 / 127.0 * MAX_CHORUS_DEPTH_MS
+                    }
+                    // portamento on/off
+                    65 => self.glide = data2 >= 64,
+                    // portamento time, squared for finer control of short glides
+                    5 => self.glide_ms = (data2 as f32 / 127.0).powi(2) * MAX_GLIDE_MS,
+                    // panic: all sound off, all notes off
+                    120 => self.all_sound_off(channel),
+                    123 => self.all_notes_off(channel),
+                    // mono and poly mode
+                    126 => self.set_mono(true),
+                    127 => self.set_mono(false),
+                    // soft pedal, 127 is fully down
+                    67 => self.soft_pedal[channel as usize] = data2 as f32 / 127.0,
+                    CC_NOISE_COLOR => {
+                        if let WaveType::Noise { color } =
+                            &mut self.patches[channel as usize].wave_type
+                        {
+                            *color = data2 as f32 / 127.0;
+                        }
+                    }
+                    CC_PULSE_WIDTH => {
+                        if let WaveType::Pulse { width } =
+                            &mut self.patches[channel as usize].wave_type
+                        {
+                            let span = MAX_PULSE_WIDTH - MIN_PULSE_WIDTH;
+                            *width = MIN_PULSE_WIDTH + span * data2 as f32 / 127.0;
+                        }
+                    }
+                    // frees a synced LFO to run at the rate it is given
+                    CC_LFO_RATE => {
+                        let span = MAX_LFO_RATE_HZ / MIN_LFO_RATE_HZ;
+                        self.lfos[0].rate_hz = MIN_LFO_RATE_HZ * span.powf(data2 as f32 / 127.0);
+                        self.lfos[0].sync_beats = None;
+                    }
+                    CC_LFO_DEPTH => self.lfos[0].depth = data2 as f32 / 127.0,
+                    CC_CUTOFF => {
+                        // exponential, so every step of the knob is the same musical interval
+                        let span = DEFAULT_CUTOFF_HZ / MIN_CUTOFF_HZ;
+                        self.patches[channel as usize].cutoff =
+                            MIN_CUTOFF_HZ * span.powf(data2 as f32 / 127.0);
+                    }
+                    CC_RESONANCE => {
+                        let span = MAX_RESONANCE / DEFAULT_RESONANCE;
+                        self.patches[channel as usize].resonance =
+                            DEFAULT_RESONANCE * span.powf(data2 as f32 / 127.0);
+                    }
+                    CC_FILTER_ENV_AMOUNT => {
+                        let amount = ((data2 as f32 - 64.0) / 63.0).clamp(-1.0, 1.0);
+                        self.patches[channel as usize].filter_env.amount =
+                            amount * MAX_FILTER_ENV_OCTAVES;
+                    }
+                    CC_VOWEL => {
+                        let vowel = data2 as f32 / 127.0 * (VOWEL_FORMANTS.len() - 1) as f32;
+                        self.patches[channel as usize].vowel = Some(vowel);
+                    }
+                    _ => {}
+                }
+            }
+            // program change picks the channel's oscillator
+            192..=207 => {
+                let wave_type = match data1 {
+                    0 => WaveType::Sine,
+                    1 => WaveType::Square,
+                    2 => WaveType::Saw,
+                    3 => WaveType::Triangle,
+                    4 => WaveType::Noise { color: 0.0 },
+                    5 => WaveType::Pulse {
+                        width: DEFAULT_PULSE_WIDTH,
+                    },
+                    _ => return,
+                };
+                self.patches[channel as usize].wave_type = wave_type;
+            }
+            // poly key pressure, on the one note
+            160..=175 => {
+                let Some(&data2) = message.get(2) else {
+                    return;
+                };
+                let note = self
+                    .transposed_keys
+                    .get(&(channel, data1))
+                    .copied()
+                    .unwrap_or(data1);
+                if let Some(&slot) = self.playing_notes.get(&(channel, note)) {
+                    if let Some(voice) = self.voices[slot].as_mut() {
+                        voice.pressure = data2 as f32 / 127.0;
+                    }
+                }
+            }
+            // channel pressure, on every note of the channel
+            208..=223 => self.aftertouch[channel as usize] = data1 as f32 / 127.0,
+            // pitch bend
+            224..=239 => {
+                let Some(&data2) = message.get(2) else {
+                    return;
+                };
+                let value = (data2 as u16) << 7 | data1 as u16;
+                self.pitch_bend(channel, value);
+            }
+            _ => {}
+        }
+    }
+
+    // Apply a note-on velocity to the voice's level and/or cutoff, through the velocity curve
+    fn set_velocity(&self, voice: &mut Voice, velocity: u8) {
+        voice.velocity = velocity as f32 / 127.0;
+        let amount = self.velocity_curve.gain(velocity);
+        let (gain, brightness) = match self.velocity_dest {
+            VelocityDest::Amp => (amount, 1.0),
+            VelocityDest::Cutoff => (1.0, amount),
+            VelocityDest::Both => (amount, amount),
+        };
+        voice.set_velocity_gain(gain);
+        voice.set_velocity_cutoff((brightness - 1.0) * self.velocity_cutoff);
+    }
+
+    // Gain applied to the mix so dense chords don't clip: active_voices^-law when auto-gain
+    // is on (law 0.5 is 1/sqrt(n)), unity otherwise
+    fn target_mix_gain(&self) -> f32 {
+        if !self.auto_gain {
+            return 1.0;
+        }
+        let active = self.voices.iter().filter(|voice| voice.is_some()).count();
+        (active.max(1) as f32).powf(-self.auto_gain_law)
+    }
+
+    // Control-rate update of one voice: envelope, mod matrix outputs and formant vowel
+    fn control_voice(&self, voice: &mut Voice) {
+        let channel = voice.channel as usize;
+        let lfo = |i: usize| {
+            let lfo = &self.lfos[i];
+            let wheel = if lfo.wheel {
+                self.mod_wheel[channel]
+            } else {
+                1.0
+            };
+            lfo.wave() * self.smoothed_lfo_depth[i].value() * wheel
+        };
+        let sources = ModSources {
+            lfo1: lfo(0),
+            lfo2: lfo(1),
+            velocity: voice.velocity,
+            // whichever kind of pressure the controller sends, or the harder of the two
+            aftertouch: self.aftertouch[channel].max(voice.pressure),
+            mod_wheel: self.mod_wheel[channel],
+            env_follower: self.follower.value(),
+        };
+        voice.set_modulation(self.mod_matrix.evaluate(&sources));
+        voice.set_bend(self.bend[channel].value());
+        voice.set_filter_mode(self.patches[channel].filter_mode);
+        voice.set_filter_env(self.patches[channel].filter_env);
+        voice.set_filter(
+            2f32.powf(self.smoothed_cutoff[channel].value()),
+            self.patches[channel].resonance,
+        );
+        voice.set_vowel(self.patches[channel].vowel);
+        voice.set_ring_mod(self.patches[channel].ring_ratio);
+        voice.set_channel_volume(self.smoothed_volume[channel].value());
+        voice.set_channel_pan(self.smoothed_pan[channel].value());
+        match self.patches[channel].wave_type {
+            WaveType::Noise { color } => voice.set_noise_color(color),
+            WaveType::Pulse { width } => voice.set_pulse_width(width),
+            _ => {}
+        }
+        voice.control_tick(self.control_period);
+    }
+
+    fn control_tick(&mut self) {
+        let period = self.control_period;
+        for (channel, patch) in self.patches.iter().enumerate() {
+            // in octaves, so a sweep moves evenly through the range rather than rushing the top
+            self.smoothed_cutoff[channel].set(patch.cutoff.max(1.0).log2());
+            self.smoothed_volume[channel].set(patch.volume);
+            self.smoothed_pan[channel].set(patch.pan);
+            self.bend[channel].set_time(self.bend_smoothing_ms);
+            for param in [
+                &mut self.bend[channel],
+                &mut self.smoothed_cutoff[channel],
+                &mut self.smoothed_volume[channel],
+                &mut self.smoothed_pan[channel],
+            ] {
+                param.advance(period);
+            }
+        }
+        for (lfo, depth) in self.lfos.iter().zip(self.smoothed_lfo_depth.iter_mut()) {
+            depth.set(lfo.depth);
+            depth.advance(period);
+        }
+        self.smoothed_master_gain.set(self.master_gain);
+        self.smoothed_master_gain.advance(period);
+        self.mute_gain.set(if self.muted { 0.0 } else { 1.0 });
+        self.mute_gain.advance(period);
+        self.reap_notes();
+
+        let bpm = self.clock.tempo();
+        self.delay.follow_tempo(bpm);
+        for lfo in self.lfos.iter_mut() {
+            lfo.advance(self.control_period, bpm);
+        }
+        if self.arp_on {
+            let (off, on) = self.arp.advance(self.control_period, bpm, &mut self.rng);
+            if let Some((channel, note)) = off {
+                self.key_up(channel, note);
+            }
+            if let Some((channel, note, velocity)) = on {
+                self.start_note(channel, note, velocity);
+            }
+        }
+
+        // swapped out (without allocating) so the voices can be updated from &self
+        let mut voices = std::mem::take(&mut self.voices);
+        for slot in voices.iter_mut() {
+            if let Some(voice) = slot {
+                self.control_voice(voice);
+                if voice.is_finished() {
+                    *slot = None;
+                }
+            }
+        }
+        self.voices = voices;
+
+        let mut stolen = std::mem::take(&mut self.stolen);
+        for voice in stolen.iter_mut() {
+            self.control_voice(voice);
+        }
+        stolen.retain(|voice| !voice.is_finished());
+        self.stolen = stolen;
+    }
+
+    // Next (left, right) frame of the stereo mix
+    pub fn next_frame(&mut self) -> (f32, f32) {
+        if self.sample_count.is_multiple_of(self.control_period) {
+            self.control_tick();
+        }
+        self.sample_count = self.sample_count.wrapping_add(1);
+
+        let (mut left, mut right) = (0.0, 0.0);
+        let mut mix = |voice: &mut Voice| match voice.next_frame() {
+            Some((voice_left, voice_right)) => {
+                left += voice_left;
+                right += voice_right;
+                true
+            }
+            None => false,
+        };
+        for slot in self.voices.iter_mut() {
+            if let Some(voice) = slot {
+                if !mix(voice) {
+                    *slot = None;
+                }
+            }
+        }
+        self.stolen.retain_mut(mix);
+
+        // ease toward the polyphony-dependent gain so level changes don't jump
+        self.mix_gain += (self.target_mix_gain() - self.mix_gain) * MIX_GAIN_SMOOTHING;
+        let (left, right) = self
+            .shaper
+            .process_stereo(left * self.mix_gain, right * self.mix_gain);
+        let (left, right) = self.crusher.process_stereo(left, right);
+        let (left, right) = self.eq.process_stereo(left, right);
+        let (left, right) = self.chorus.process_stereo(left, right);
+        let (left, right) = self.delay.process_stereo(left, right);
+        let (left, right) = self.compressor.process_stereo(left, right);
+        let master_gain = self.smoothed_master_gain.value() * self.mute_gain.value();
+        let (left, right) = self
+            .limiter
+            .process_stereo(left * master_gain, right * master_gain);
+        self.follower.process(left.abs().max(right.abs()));
+        (left, right)
+    }
+
+    // Next sample of the mix folded down to mono, a centred voice comes out at its full level
+    pub fn next_sample(&mut self) -> f32 {
+        let (left, right) = self.next_frame();
+        (left + right) * std::f32::consts::FRAC_1_SQRT_2
+    }
+
+    // Fill `out` with the next block of the mono mix. Independent of any audio backend and
+    // allocation-free, so it can be used for offline rendering, export and tests.
+    pub fn render(&mut self, out: &mut [f32]) {
+        for sample in out.iter_mut() {
+            *sample = self.next_sample();
+        }
+    }
+
+    // Same as render, for the stereo mix as interleaved left/right frames
+    pub fn render_stereo(&mut self, out: &mut [f32]) {
+        for frame in out.chunks_exact_mut(2) {
+            (frame[0], frame[1]) = self.next_frame();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::EnvStage;
+
+    const NOTE: u8 = 60;
+
+    fn play(synth: &mut Synth, ms: usize) {
+        let mut out = vec![0.0; ms * sample_rate() as usize / 1000];
+        synth.render(&mut out);
+    }
+
+    // voices in the slots sounding NOTE
+    fn sounding(synth: &Synth) -> Vec<&Voice> {
+        synth
+            .voices
+            .iter()
+            .flatten()
+            .filter(|voice| voice.note == NOTE)
+            .collect()
+    }
+
+    #[test]
+    fn trigger_mode_starts_a_fresh_voice_for_a_repeat() {
+        let mut synth = Synth::new();
+        synth.note_on(0, NOTE, 100);
+        play(&mut synth, 30);
+        assert_eq!(sounding(&synth)[0].stage(), EnvStage::Sustain);
+
+        // struck again while still down
+        synth.note_on(0, NOTE, 100);
+        let voices = sounding(&synth);
+        assert_eq!(voices.len(), 1);
+        assert_eq!(voices[0].stage(), EnvStage::Attack);
+        assert!(!voices[0].is_releasing());
+        // and the old one fades out on its own
+        assert_eq!(synth.stolen.len(), 1);
+        assert!(synth.stolen[0].is_releasing());
+    }
+
+    #[test]
+    fn gate_mode_keeps_the_envelope_going_for_a_repeat() {
+        let mut synth = Synth::new();
+        synth.trigger_mode = TriggerMode::Gate;
+        synth.note_on(0, NOTE, 100);
+        play(&mut synth, 30);
+
+        // a repeat while held changes nothing about the envelope
+        synth.note_on(0, NOTE, 100);
+        let voices = sounding(&synth);
+        assert_eq!(voices.len(), 1);
+        assert_eq!(voices[0].stage(), EnvStage::Sustain);
+        assert!(synth.stolen.is_empty());
+
+        // one in the release takes the same voice back to held
+        synth.note_off(0, NOTE);
+        play(&mut synth, 2);
+        assert!(sounding(&synth)[0].is_releasing());
+        synth.note_on(0, NOTE, 100);
+        let voices = sounding(&synth);
+        assert_eq!(voices.len(), 1);
+        assert!(!voices[0].is_releasing());
+        assert!(synth.stolen.is_empty());
+        play(&mut synth, 30);
+        assert_eq!(sounding(&synth)[0].stage(), EnvStage::Sustain);
+    }
+
+    #[test]
+    fn velocity_curves() {
+        let curves = [
+            VelocityCurve::Linear,
+            VelocityCurve::Soft,
+            VelocityCurve::Hard,
+            VelocityCurve::Fixed,
+        ];
+        for curve in curves {
+            // full velocity is full level whatever the curve, and louder never plays quieter
+            assert_eq!(curve.gain(127), 1.0);
+            assert!((1..=127).all(|velocity| curve.gain(velocity) >= curve.gain(velocity - 1)));
+            // out of range velocities count as the top
+            assert_eq!(curve.gain(200), 1.0);
+        }
+        for curve in &curves[..3] {
+            assert_eq!(curve.gain(0), 0.0);
+        }
+        assert_eq!(VelocityCurve::Fixed.gain(1), 1.0);
+
+        // half way up: linear in proportion, soft its square root, hard its square
+        let half = 64.0 / 127.0;
+        assert_eq!(VelocityCurve::Linear.gain(64), half);
+        assert!((VelocityCurve::Soft.gain(64) - f32::sqrt(half)).abs() < 1e-6);
+        assert!((VelocityCurve::Hard.gain(64) - half * half).abs() < 1e-6);
+    }
+
+    #[test]
+    fn note_on_with_velocity_zero_releases_the_note() {
+        let mut synth = Synth::new();
+        synth.handle_midi(&[0x90, NOTE, 100]);
+        assert!(!sounding(&synth)[0].is_releasing());
+        synth.handle_midi(&[0x90, NOTE, 0]);
+        assert!(sounding(&synth)[0].is_releasing());
+    }
+
+    #[test]
+    fn messages_cut_short_are_ignored() {
+        let mut synth = Synth::new();
+        for message in [
+            &[][..],
+            &[0x90],
+            &[0x90, NOTE],
+            &[0xB0, 1],
+            &[0xA0, NOTE],
+            &[0xE0, 0],
+        ] {
+            synth.handle_midi(message);
+        }
+        assert!(sounding(&synth).is_empty());
+    }
+}
@@ -0,0 +1,970 @@
+use crate::envelope::{Adsr, EnvCurve, EnvMode, EnvStage, FilterEnv};
+use crate::filter::{Filter, FilterMode, DEFAULT_CUTOFF_HZ, DEFAULT_RESONANCE};
+use crate::formant::FormantFilter;
+use crate::modmatrix::ModOutputs;
+use crate::rng::XorShift32;
+use crate::sample_rate;
+use crate::wave::{
+    Wave, WaveTrims, WaveType, DEFAULT_PULSE_WIDTH, MAX_PULSE_WIDTH, MIN_PULSE_WIDTH,
+};
+
+// Fraction of the drift range the random walk may move per sample
+const DRIFT_STEP: f32 = 0.0005;
+
+// Mixed into the drift seed for the noise generator
+const NOISE_SEED_SALT: u32 = 0x9e37_79b9;
+
+// Top speed of the oscillator's pitch slew outside of a glide (bends, modulation), one octave
+// in 50 ms
+const SLEW_OCTAVES_PER_SECOND: f32 = 20.0;
+
+// Most oscillators one voice stacks in unison
+pub const MAX_UNISON_VOICES: usize = 7;
+
+// Mixed into the drift seed for the unison oscillators' start phases and noise
+const UNISON_SEED_SALT: u32 = 0x85eb_ca6b;
+
+// Shortest attack, a note that jumps straight to full level starts with a click
+const MIN_ATTACK_MS: usize = 2;
+
+// Release time of a voice that is stolen for a new note, short but long enough not to click
+const STEAL_FADE_MS: usize = 5;
+
+// Length of an envelope stage in samples, rounded rather than truncated so stage times stay
+// accurate at any sample rate
+fn ms_to_samples(ms: usize) -> usize {
+    (ms as f32 * sample_rate() as f32 / 1000.0).round() as usize
+}
+
+// Stage a note starts in, a delay of 0 goes straight to the attack rather than waiting a tick
+fn start_stage(amp_env: &Adsr) -> EnvStage {
+    if amp_env.delay > 0 {
+        EnvStage::Delay
+    } else {
+        EnvStage::Attack
+    }
+}
+
+// Time constants an exponential segment gets per stage time. After five the segment is
+// within 1% of its target and the last step to it is inaudible.
+const EXP_TIME_CONSTANTS: f32 = 5.0;
+
+// Fraction of the remaining distance an exponential segment of `num_samples` covers in one
+// control tick of `period` samples
+fn exp_coefficient(period: usize, num_samples: usize) -> f32 {
+    1.0 - (-(EXP_TIME_CONSTANTS * period as f32) / num_samples.max(1) as f32).exp()
+}
+
+// Equal-power pan law: (left, right) gains for a position from -1 (left) to 1 (right). The
+// sides are at -3 dB in the centre, so a voice keeps its loudness wherever it is placed.
+fn pan_gains(pan: f32) -> (f32, f32) {
+    let angle = (pan.clamp(-1.0, 1.0) + 1.0) * std::f32::consts::FRAC_PI_4;
+    (angle.cos(), angle.sin())
+}
+
+// Slow random walk of a voice's pitch (in cents) to emulate analog oscillator instability
+#[derive(Debug, Clone, Copy)]
+struct Drift {
+    rng: XorShift32,
+    amount: f32,
+    cents: f32,
+}
+
+impl Drift {
+    fn new(amount: f32, seed: u32) -> Self {
+        Self {
+            rng: XorShift32::new(seed),
+            amount,
+            cents: 0.0,
+        }
+    }
+
+    // advance the walk by `samples` in one step and return the frequency ratio to apply on
+    // top of the target. A random walk spreads with the square root of its steps, so it
+    // wanders as fast as one stepped every sample.
+    fn next_ratio(&mut self, samples: usize) -> f32 {
+        if self.amount <= 0.0 {
+            return 1.0;
+        }
+        let step = self.amount * DRIFT_STEP * (samples as f32).sqrt();
+        self.cents += self.rng.next_bipolar() * step;
+        self.cents = self.cents.clamp(-self.amount, self.amount);
+        2f32.powf(self.cents / 1200.0)
+    }
+}
+
+// One of the extra oscillators of a unison stack
+#[derive(Clone, Debug)]
+struct UnisonOsc {
+    wave: Wave,
+    // detune from the voice's pitch
+    ratio: f32,
+    // place in the stack's stereo spread
+    gains: (f32, f32),
+}
+
+// A single sounding note: oscillator, amp envelope and pitch slew. Yields samples until the
+// envelope has finished.
+#[derive(Clone, Debug)]
+pub struct Voice {
+    // the note's own frequency, the oscillator slews toward it with bend and modulation on top
+    pub freq: f32,
+    // MIDI note and note-on order, used to pick a voice to steal
+    pub note: u8,
+    pub started: u64,
+    // the synth's sample count when the key was last struck, for the stuck-note watchdog
+    pub struck: usize,
+    pub channel: u8,
+    // note-on velocity, 0..1
+    pub velocity: f32,
+    // poly key pressure on the note, 0..1
+    pub pressure: f32,
+    // level of the note from its velocity, scales the whole envelope
+    velocity_gain: f32,
+    // volume of the voice's MIDI channel
+    channel_volume: f32,
+    // stereo position, -1 (left) to 1 (right)
+    pub pan: f32,
+    // (left, right) gains of the voice's position after channel pan and modulation
+    pan_gains: (f32, f32),
+    modulation: ModOutputs,
+    mod_pitch_ratio: f32,
+    // pitch bend of the voice's channel as a frequency ratio
+    bend_ratio: f32,
+    // oscillator pitch before unison detune, slews toward the target
+    pitch: f32,
+    // slew of a glide under way, in octaves per sample
+    glide_slew: Option<f32>,
+    filter: Filter,
+    // right side filters of a unison stack spread in stereo, the left uses the main ones
+    filter_right: Filter,
+    formant_right: Option<FormantFilter>,
+    // filter settings before modulation
+    cutoff: f32,
+    resonance: f32,
+    // cutoff offset of the note's velocity, in octaves
+    velocity_cutoff: f32,
+    filter_env: FilterEnv,
+    // where the filter envelope is, 0..1 of its amount
+    filter_env_level: f32,
+    // samples since the filter envelope started, and since it was released from which level
+    filter_env_held: usize,
+    filter_env_release: Option<(usize, f32)>,
+    // pulse width of a pulse wave before modulation
+    pulse_width: f32,
+    formant: Option<FormantFilter>,
+    wave: Wave,
+    // detune and stereo place of the main oscillator in a unison stack
+    wave_ratio: f32,
+    wave_gains: (f32, f32),
+    // unison: the rest of the stack, empty for a single oscillator
+    unison: Vec<UnisonOsc>,
+    unison_detune: f32,
+    // velocity layer: second oscillator and its share of the mix
+    layer: Option<Wave>,
+    layer_gain: f32,
+    // second oscillator, its frequency as a ratio to the pitch and its share of the mix
+    osc2: Option<Wave>,
+    osc2_ratio: f32,
+    osc2_mix: f32,
+    // ring modulation: sine the oscillators are multiplied by, at this ratio to the pitch
+    ring: Option<Wave>,
+    ring_ratio: f32,
+    trims: WaveTrims,
+    amp_env: Adsr,
+    drift: Drift,
+    drift_seed: u32,
+    // where the drift has the pitch as of the last control tick, as a frequency ratio
+    drift_ratio: f32,
+    // envelope level, updated at control rate
+    volume: f32,
+    // gain actually applied, ramped per sample toward the envelope level so it moves smoothly
+    // between control ticks
+    level: f32,
+    level_step: f32,
+    stage: EnvStage,
+    // samples since the current stage began, counted by the voice so it can't wrap mid-note
+    stage_elapsed: usize,
+    release_step: f32,
+    releasing: bool,
+    finished: bool,
+}
+
+impl Voice {
+    pub fn new(
+        freq: f32,
+        wave_type: WaveType,
+        amp_env: Adsr,
+        drift_amount: f32,
+        drift_seed: u32,
+    ) -> Self {
+        Self {
+            freq,
+            note: 0,
+            started: 0,
+            struck: 0,
+            channel: 0,
+            velocity: 1.0,
+            pressure: 0.0,
+            velocity_gain: 1.0,
+            channel_volume: 1.0,
+            pan: 0.0,
+            pan_gains: pan_gains(0.0),
+            modulation: ModOutputs::default(),
+            mod_pitch_ratio: 1.0,
+            bend_ratio: 1.0,
+            pitch: freq,
+            glide_slew: None,
+            filter: Filter::default(),
+            filter_right: Filter::default(),
+            formant_right: None,
+            cutoff: DEFAULT_CUTOFF_HZ,
+            resonance: DEFAULT_RESONANCE,
+            velocity_cutoff: 0.0,
+            filter_env: FilterEnv::default(),
+            filter_env_level: 0.0,
+            filter_env_held: 0,
+            filter_env_release: None,
+            pulse_width: match wave_type {
+                WaveType::Pulse { width } => width,
+                _ => DEFAULT_PULSE_WIDTH,
+            },
+            formant: None,
+            wave: {
+                let mut wave = Wave::new(freq, wave_type);
+                // a stream of its own, not the drift walk's
+                wave.seed_noise(drift_seed ^ NOISE_SEED_SALT);
+                wave
+            },
+            wave_ratio: 1.0,
+            wave_gains: pan_gains(0.0),
+            unison: Vec::new(),
+            unison_detune: 0.0,
+            layer: None,
+            layer_gain: 0.0,
+            osc2: None,
+            osc2_ratio: 1.0,
+            osc2_mix: 0.0,
+            ring: None,
+            ring_ratio: 1.0,
+            trims: WaveTrims::default(),
+            amp_env,
+            drift: Drift::new(drift_amount, drift_seed),
+            drift_seed,
+            drift_ratio: 1.0,
+            volume: 0.0,
+            level: 0.0,
+            level_step: 0.0,
+            stage: start_stage(&amp_env),
+            stage_elapsed: 0,
+            release_step: 0.0,
+            releasing: false,
+            finished: false,
+        }
+    }
+
+    // Move a sounding (mono) voice to a new note. Multi-trigger restarts the envelope from
+    // zero, single-trigger keeps the current envelope state and only changes the pitch.
+    pub fn retarget(&mut self, freq: f32, retrigger: bool) {
+        self.freq = freq;
+        if retrigger {
+            self.volume = 0.0;
+            self.enter(start_stage(&self.amp_env));
+            self.filter_env_held = 0;
+            self.filter_env_release = None;
+        }
+    }
+
+    // Stack `voices` oscillators (1 is a single one) detuned up to `detune_cents` either side of
+    // the pitch and spread evenly from left to right. The stack shares the envelope, filter and
+    // velocity layer, and is mixed to the level of a single oscillator.
+    pub fn set_unison(&mut self, voices: usize, detune_cents: f32) {
+        let voices = voices.clamp(1, MAX_UNISON_VOICES);
+        self.unison_detune = detune_cents;
+        self.unison.clear();
+        self.wave_ratio = 1.0;
+        self.wave_gains = pan_gains(0.0);
+        if voices == 1 {
+            return;
+        }
+        // start phases differ, or the stack begins as one loud oscillator and phases apart
+        let mut rng = XorShift32::new(self.drift_seed ^ UNISON_SEED_SALT);
+        for i in 0..voices {
+            let position = 2.0 * i as f32 / (voices - 1) as f32 - 1.0;
+            let ratio = 2f32.powf(position * detune_cents / 1200.0);
+            let gains = pan_gains(position);
+            // the middle of the stack is the voice's own oscillator
+            if i == voices / 2 {
+                self.wave_ratio = ratio;
+                self.wave_gains = gains;
+                continue;
+            }
+            let mut wave = Wave::new(self.pitch * ratio, self.wave.typ.clone());
+            wave.seed_noise(rng.next_u32());
+            wave.set_phase(rng.next_u32() as f32 / u32::MAX as f32);
+            wave.morph = self.wave.morph;
+            wave.pulse_width = self.wave.pulse_width;
+            self.unison.push(UnisonOsc { wave, ratio, gains });
+        }
+    }
+
+    // Take a releasing note back to held, the envelope carries on from its current level
+    pub fn resume(&mut self) {
+        self.releasing = false;
+        self.filter_env_release = None;
+        if self.stage == EnvStage::Release {
+            // rise back at the attack rate, as if the attack had got this far
+            let attack_num_samples = self.attack_samples();
+            let done = (self.volume * attack_num_samples as f32) as usize;
+            self.stage = EnvStage::Attack;
+            self.stage_elapsed = done;
+        }
+    }
+
+    // Start the envelope over without a jump: the attack rises from the current level, as if
+    // it had got this far
+    pub fn restart_envelope(&mut self) {
+        self.releasing = false;
+        self.filter_env_held = 0;
+        self.filter_env_release = None;
+        let done = (self.volume * self.attack_samples() as f32) as usize;
+        self.stage = EnvStage::Attack;
+        self.stage_elapsed = done;
+    }
+
+    pub fn set_velocity_gain(&mut self, gain: f32) {
+        self.velocity_gain = gain;
+    }
+
+    pub fn set_velocity_cutoff(&mut self, octaves: f32) {
+        self.velocity_cutoff = octaves;
+        self.set_filter(self.cutoff, self.resonance);
+    }
+
+    pub fn set_channel_volume(&mut self, volume: f32) {
+        self.channel_volume = volume;
+    }
+
+    // Place the voice in the stereo field: its own pan, moved by its channel's pan and the pan
+    // modulation
+    pub fn set_channel_pan(&mut self, pan: f32) {
+        let pan = (self.pan + pan + self.modulation.pan).clamp(-1.0, 1.0);
+        self.pan_gains = pan_gains(pan);
+    }
+
+    pub fn pan_gains(&self) -> (f32, f32) {
+        self.pan_gains
+    }
+
+    // Crossfade in a second oscillator, `gain` of it against 1 - gain of the main one. Both
+    // share the pitch and the amp envelope.
+    pub fn set_layer(&mut self, wave_type: WaveType, gain: f32) {
+        self.layer = Some(Wave::new(self.pitch, wave_type));
+        self.layer_gain = gain.clamp(0.0, 1.0);
+    }
+
+    // Mix in a second oscillator at `ratio` times the pitch, `mix` of it against 1 - mix of the
+    // main one. In a unison stack it sounds once, with the middle oscillator.
+    pub fn set_osc2(&mut self, wave_type: WaveType, ratio: f32, mix: f32) {
+        self.osc2 = Some(Wave::new(self.pitch * ratio, wave_type));
+        self.osc2_ratio = ratio;
+        self.osc2_mix = mix.clamp(0.0, 1.0);
+    }
+
+    // Multiply the oscillators by a sine at `ratio` times the pitch, None takes it out. Sum and
+    // difference tones replace the note itself: bells and metal at uneven ratios.
+    pub fn set_ring_mod(&mut self, ratio: Option<f32>) {
+        match ratio {
+            Some(ratio) => {
+                self.ring_ratio = ratio;
+                if self.ring.is_none() {
+                    self.ring = Some(Wave::new(self.pitch * ratio, WaveType::Sine));
+                }
+            }
+            None => self.ring = None,
+        }
+    }
+
+    // Follow a change of the patch's noise color, no effect on other waves
+    pub fn set_noise_color(&mut self, color: f32) {
+        self.wave.set_noise_color(color);
+        for osc in self.unison.iter_mut() {
+            osc.wave.set_noise_color(color);
+        }
+    }
+
+    // Loudness trims for the voice's oscillators
+    pub fn set_trims(&mut self, trims: WaveTrims) {
+        self.trims = trims;
+    }
+
+    // Start the oscillator at `freq` and let it slide to the note's own pitch (portamento)
+    pub fn glide_from(&mut self, freq: f32, glide_ms: f32) {
+        self.pitch = freq;
+        self.glide(glide_ms);
+    }
+
+    // Slide from the current pitch to the target in `glide_ms`, whatever the interval. The
+    // slide is a constant number of octaves per second, so it sounds even all the way. 0 jumps
+    // straight to the target.
+    pub fn glide(&mut self, glide_ms: f32) {
+        let octaves = (self.freq / self.pitch).log2().abs();
+        let samples = glide_ms * sample_rate() as f32 / 1000.0;
+        if samples < 1.0 || !octaves.is_finite() {
+            self.pitch = self.freq;
+            self.glide_slew = None;
+        } else {
+            self.glide_slew = Some(octaves / samples);
+        }
+    }
+
+    pub fn stop(&mut self) {
+        // one-shot and bypassed notes always play out in full
+        if self.amp_env.mode != EnvMode::Adsr {
+            return;
+        }
+        self.releasing = true;
+    }
+
+    // Quick fade to silence from wherever the note is, for a voice that's being stolen. Works
+    // on one-shot and bypassed notes as well.
+    pub fn fade_out(&mut self) {
+        self.amp_env.mode = EnvMode::Adsr;
+        self.amp_env.release = STEAL_FADE_MS;
+        self.releasing = true;
+        // a release that was already under way is entered afresh, at the faster rate, on the
+        // next tick
+        if self.stage == EnvStage::Release {
+            self.stage = EnvStage::Sustain;
+        }
+    }
+
+    // Bend the note by `semitones` from its own pitch
+    pub fn set_bend(&mut self, semitones: f32) {
+        self.bend_ratio = 2f32.powf(semitones / 12.0);
+    }
+
+    // Apply the mod matrix output for this control tick
+    pub fn set_modulation(&mut self, modulation: ModOutputs) {
+        self.modulation = modulation;
+        self.mod_pitch_ratio = 2f32.powf(modulation.pitch / 12.0);
+        self.set_filter(self.cutoff, self.resonance);
+        if let WaveType::Wavetable { morph, .. } = self.wave.typ {
+            self.wave.morph = (morph + modulation.morph).clamp(0.0, 1.0);
+            for osc in self.unison.iter_mut() {
+                osc.wave.morph = self.wave.morph;
+            }
+        }
+        self.set_pulse_width(self.pulse_width);
+    }
+
+    // Follow a change of the patch's pulse width, the modulation is applied on top. No effect
+    // on other waves.
+    pub fn set_pulse_width(&mut self, width: f32) {
+        self.pulse_width = width;
+        let width = (width + self.modulation.pulse_width).clamp(MIN_PULSE_WIDTH, MAX_PULSE_WIDTH);
+        self.wave.pulse_width = width;
+        for osc in self.unison.iter_mut() {
+            osc.wave.pulse_width = width;
+        }
+    }
+
+    // Set the filter, the cutoff modulation, velocity and the filter envelope are applied on top
+    pub fn set_filter(&mut self, cutoff: f32, resonance: f32) {
+        self.cutoff = cutoff;
+        self.resonance = resonance;
+        let octaves = self.modulation.cutoff
+            + self.velocity_cutoff
+            + self.filter_env.amount * self.filter_env_level;
+        let cutoff = cutoff * 2f32.powf(octaves);
+        self.filter.set(cutoff, resonance);
+        self.filter_right.set(cutoff, resonance);
+    }
+
+    pub fn set_filter_mode(&mut self, mode: FilterMode) {
+        self.filter.set_mode(mode);
+        self.filter_right.set_mode(mode);
+    }
+
+    // Follow a change of the patch's filter envelope, a note under way carries on with the new
+    // times from where it is
+    pub fn set_filter_env(&mut self, filter_env: FilterEnv) {
+        self.filter_env = filter_env;
+    }
+
+    // Insert, move or remove the formant filter
+    pub fn set_vowel(&mut self, vowel: Option<f32>) {
+        for formant in [&mut self.formant, &mut self.formant_right] {
+            match (vowel, formant.as_mut()) {
+                (Some(vowel), Some(filter)) => filter.set_vowel(vowel),
+                (Some(vowel), None) => *formant = Some(FormantFilter::new(vowel)),
+                (None, _) => *formant = None,
+            }
+        }
+    }
+
+    pub fn stage(&self) -> EnvStage {
+        self.stage
+    }
+
+    // note-off has been received (or the note was stolen) and the envelope is fading
+    pub fn is_releasing(&self) -> bool {
+        self.releasing
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    // Advance the envelope by one control tick of `period` samples. Called by the synth at its
+    // control rate; a voice used on its own has to be ticked by its owner.
+    pub fn control_tick(&mut self, period: usize) {
+        self.filter_env_tick();
+        self.drift_ratio = self.drift.next_ratio(period);
+        if self.amp_env.mode == EnvMode::Bypass {
+            // drum samples keep their transient, they start at full level
+            self.volume = 1.0;
+            self.level = 1.0;
+            self.level_step = 0.0;
+            return;
+        }
+        // the ramp down to silence has played out
+        if self.stage == EnvStage::Off {
+            self.finished = true;
+            return;
+        }
+
+        let sustain = self.amp_env.sustain;
+        let one_shot = self.amp_env.mode == EnvMode::OneShot;
+        let exponential = self.amp_env.curve == EnvCurve::Exponential;
+
+        let delay_num_samples = ms_to_samples(self.amp_env.delay);
+        let attack_num_samples = self.attack_samples();
+        let hold_num_samples = ms_to_samples(self.amp_env.hold);
+        let decay_num_samples = ms_to_samples(self.amp_env.decay);
+        let release_num_samples = ms_to_samples(self.amp_env.release);
+
+        // note-off cuts any earlier stage short, fading from wherever the envelope is
+        if self.releasing && self.stage < EnvStage::Release {
+            self.enter(EnvStage::Release);
+            self.release_step = self.volume * period as f32 / release_num_samples as f32;
+        }
+
+        // every stage runs for its time and then lands exactly on its target level
+        let elapsed = self.stage_elapsed;
+        match self.stage {
+            EnvStage::Delay => {
+                if elapsed >= delay_num_samples {
+                    self.enter(EnvStage::Attack);
+                }
+            }
+            EnvStage::Attack => {
+                if elapsed >= attack_num_samples {
+                    self.volume = 1.0;
+                    // no hold goes straight on, rather than sitting at the peak for a tick
+                    self.enter(if hold_num_samples > 0 {
+                        EnvStage::Hold
+                    } else {
+                        EnvStage::Decay
+                    });
+                } else if exponential {
+                    let coefficient = exp_coefficient(period, attack_num_samples);
+                    self.volume += (1.0 - self.volume) * coefficient;
+                } else {
+                    let step = period as f32 / attack_num_samples as f32;
+                    self.volume = (self.volume + step).min(1.0);
+                }
+            }
+            EnvStage::Hold => {
+                if elapsed >= hold_num_samples {
+                    self.enter(EnvStage::Decay);
+                }
+            }
+            EnvStage::Decay => {
+                // one-shot envelopes decay all the way to silence
+                let target = if one_shot { 0.0 } else { sustain };
+                if elapsed >= decay_num_samples {
+                    self.volume = target;
+                    if one_shot {
+                        self.enter(EnvStage::Off);
+                    } else if self.amp_env.loop_ad {
+                        // jump back to the attack instead of holding sustain
+                        self.volume = 0.0;
+                        self.enter(EnvStage::Attack);
+                    } else {
+                        self.enter(EnvStage::Sustain);
+                    }
+                } else if exponential {
+                    let coefficient = exp_coefficient(period, decay_num_samples);
+                    self.volume += (target - self.volume) * coefficient;
+                } else {
+                    let step = (1.0 - target) * period as f32 / decay_num_samples as f32;
+                    self.volume = (self.volume - step).max(target);
+                }
+            }
+            // follows the patch, sustain may change while the note is held
+            EnvStage::Sustain => self.volume = sustain,
+            EnvStage::Release => {
+                if elapsed >= release_num_samples {
+                    self.volume = 0.0;
+                    self.enter(EnvStage::Off);
+                } else if exponential {
+                    self.volume -= self.volume * exp_coefficient(period, release_num_samples);
+                } else {
+                    self.volume = (self.volume - self.release_step).max(0.0);
+                }
+            }
+            EnvStage::Off => {}
+        }
+        self.level_step = (self.volume - self.level) / period.max(1) as f32;
+    }
+
+    // Move the filter envelope to where it is now and the cutoff with it
+    fn filter_env_tick(&mut self) {
+        if self.releasing && self.filter_env_release.is_none() {
+            self.filter_env_release = Some((0, self.filter_env_level));
+        }
+        let env = self.filter_env;
+        self.filter_env_level = match self.filter_env_release {
+            Some((released, from)) => {
+                let release = ms_to_samples(env.release).max(1);
+                from * (1.0 - released as f32 / release as f32).max(0.0)
+            }
+            None => {
+                let held = self.filter_env_held;
+                let attack = ms_to_samples(env.attack);
+                let decay = ms_to_samples(env.decay);
+                if held < attack {
+                    held as f32 / attack as f32
+                } else if held < attack + decay {
+                    1.0 - (1.0 - env.sustain) * (held - attack) as f32 / decay as f32
+                } else {
+                    env.sustain
+                }
+            }
+        };
+        self.set_filter(self.cutoff, self.resonance);
+    }
+
+    fn attack_samples(&self) -> usize {
+        ms_to_samples(self.amp_env.attack.max(MIN_ATTACK_MS))
+    }
+
+    fn enter(&mut self, stage: EnvStage) {
+        self.stage = stage;
+        self.stage_elapsed = 0;
+    }
+}
+
+impl Voice {
+    // Next (left, right) frame of the voice, placed in the stereo field
+    pub fn next_frame(&mut self) -> Option<(f32, f32)> {
+        let (left, right) = self.next_pair()?;
+        Some((left * self.pan_gains.0, right * self.pan_gains.1))
+    }
+
+    // Next frame before the voice's own pan. Both sides are the same unless a unison stack
+    // spreads them apart.
+    fn next_pair(&mut self) -> Option<(f32, f32)> {
+        if self.finished {
+            return None;
+        }
+        // saturating, a note held for days just stays in its last stage
+        self.stage_elapsed = self.stage_elapsed.saturating_add(1);
+        self.filter_env_held = self.filter_env_held.saturating_add(1);
+        if let Some((released, _)) = self.filter_env_release.as_mut() {
+            *released = released.saturating_add(1);
+        }
+
+        // slew the oscillator toward the target frequency, with analog drift on top. Most of
+        // the time it's already there and there's nothing to work out.
+        let target_freq = self.freq * self.mod_pitch_ratio * self.bend_ratio * self.drift_ratio;
+        if target_freq != self.pitch {
+            // the slew runs at a constant rate in octaves, so every interval moves at the same
+            // speed
+            let octaves = (target_freq / self.pitch).log2();
+            let slew = self
+                .glide_slew
+                .unwrap_or(SLEW_OCTAVES_PER_SECOND / sample_rate() as f32);
+            if !octaves.is_finite() || octaves.abs() <= slew {
+                self.pitch = target_freq;
+                self.glide_slew = None;
+            } else {
+                self.pitch *= 2f32.powf(slew.copysign(octaves));
+            }
+        }
+        self.wave.freq = self.pitch * self.wave_ratio;
+
+        let layer_sample = match &mut self.layer {
+            Some(layer) => {
+                layer.freq = self.wave.freq;
+                layer.next().unwrap_or(0.0) * self.trims.get(&layer.typ)
+            }
+            None => 0.0,
+        };
+
+        let Some(mut sample) = self.wave.next() else {
+            // a sample ran out, the voice is done regardless of the envelope
+            self.finished = true;
+            return None;
+        };
+        let trim = self.trims.get(&self.wave.typ);
+        sample *= trim;
+        if self.layer.is_some() {
+            sample += (layer_sample - sample) * self.layer_gain;
+        }
+        if let Some(osc2) = &mut self.osc2 {
+            osc2.freq = self.pitch * self.osc2_ratio;
+            let osc2_sample = osc2.next().unwrap_or(0.0) * self.trims.get(&osc2.typ);
+            sample += (osc2_sample - sample) * self.osc2_mix;
+        }
+        let ring = match &mut self.ring {
+            Some(ring) => {
+                ring.freq = self.pitch * self.ring_ratio;
+                ring.next().unwrap_or(0.0)
+            }
+            None => 1.0,
+        };
+        sample *= ring;
+        let amp_mod = (1.0 + self.modulation.amp).max(0.0);
+        // the ramp lands on the envelope level at the next tick
+        self.level = (self.level + self.level_step).clamp(0.0, 1.0);
+        let gain = self.level * self.velocity_gain * self.channel_volume * amp_mod;
+
+        if self.unison.is_empty() {
+            sample = self.filter.process(sample);
+            if let Some(formant) = &mut self.formant {
+                sample = formant.process(sample);
+            }
+            return Some((sample * gain, sample * gain));
+        }
+
+        let (mut left, mut right) = (sample * self.wave_gains.0, sample * self.wave_gains.1);
+        for osc in self.unison.iter_mut() {
+            osc.wave.freq = self.pitch * osc.ratio;
+            let sample = osc.wave.next().unwrap_or(0.0) * trim * ring;
+            left += sample * osc.gains.0;
+            right += sample * osc.gains.1;
+        }
+        // the detuned oscillators add up in power, and one centred oscillator comes out at
+        // -3 dB a side: scale back to the level of a single oscillator
+        let scale = std::f32::consts::SQRT_2 / ((self.unison.len() + 1) as f32).sqrt();
+        left = self.filter.process(left * scale);
+        right = self.filter_right.process(right * scale);
+        if let (Some(formant), Some(formant_right)) = (&mut self.formant, &mut self.formant_right) {
+            left = formant.process(left);
+            right = formant_right.process(right);
+        }
+        Some((left * gain, right * gain))
+    }
+}
+
+// The voice on its own yields mono samples, a unison stack folded to the centre
+impl Iterator for Voice {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        self.next_pair().map(|(left, right)| (left + right) * 0.5)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // samples between envelope ticks, 1 ms like the synth's default control rate
+    const PERIOD: usize = 44;
+
+    fn voice(amp_env: Adsr) -> Voice {
+        Voice::new(440.0, WaveType::Sine, amp_env, 0.0, 1)
+    }
+
+    // Play the voice the way the synth does, an envelope tick every PERIOD samples, and return
+    // the gain it applied to each sample
+    fn levels(voice: &mut Voice, samples: usize) -> Vec<f32> {
+        (0..samples)
+            .map(|n| {
+                if n % PERIOD == 0 {
+                    voice.control_tick(PERIOD);
+                }
+                voice.next_frame();
+                voice.level
+            })
+            .collect()
+    }
+
+    #[test]
+    fn settles_on_sustain_after_attack_and_decay() {
+        let mut voice = voice(Adsr {
+            attack: 10,
+            decay: 20,
+            sustain: 0.6,
+            ..Adsr::default()
+        });
+        // attack and decay, and a tick for the decay to land
+        levels(&mut voice, ms_to_samples(30) + 2 * PERIOD);
+        assert_eq!(voice.stage, EnvStage::Sustain);
+        assert_eq!(voice.volume, 0.6);
+        // the gain ramp lands on the envelope level a tick later
+        levels(&mut voice, PERIOD);
+        let held = levels(&mut voice, ms_to_samples(100));
+        assert!(held.iter().all(|&level| (level - 0.6).abs() < 1e-4));
+    }
+
+    #[test]
+    fn attack_takes_its_time() {
+        for attack in [5, 50, 300] {
+            let mut note = voice(Adsr {
+                attack,
+                sustain: 0.5,
+                ..Adsr::default()
+            });
+            let gains = levels(&mut note, ms_to_samples(attack + 50));
+            let peak = gains.iter().position(|&level| level >= 0.999).unwrap();
+            // the envelope gets there on the first tick after the attack time, and the gain
+            // ramp follows it over the next tick, crossing 0.999 a touch before the top
+            let expected = ms_to_samples(attack);
+            assert!(
+                peak + PERIOD >= expected && peak <= expected + 2 * PERIOD,
+                "{} ms attack peaked after {} samples",
+                attack,
+                peak
+            );
+            // and rose steadily rather than jumping there
+            assert!(gains[..=peak]
+                .windows(2)
+                .all(|pair| pair[1] >= pair[0] && pair[1] - pair[0] < 0.5 / PERIOD as f32));
+        }
+    }
+
+    #[test]
+    fn dahdsr_stages_take_their_times() {
+        let amp_env = Adsr {
+            delay: 20,
+            attack: 10,
+            hold: 30,
+            decay: 40,
+            sustain: 0.5,
+            ..Adsr::default()
+        };
+        let mut voice = voice(amp_env);
+        let mut stages = Vec::new();
+        let gains: Vec<f32> = (0..ms_to_samples(150))
+            .map(|n| {
+                if n % PERIOD == 0 {
+                    voice.control_tick(PERIOD);
+                }
+                stages.push(voice.stage);
+                voice.next_frame();
+                voice.level
+            })
+            .collect();
+        let span = |stage| {
+            let start = stages.iter().position(|&s| s == stage).unwrap();
+            let len = stages[start..].iter().take_while(|&&s| s == stage).count();
+            start..start + len
+        };
+
+        // each stage moves on at the first tick after its time is up
+        for (stage, ms) in [
+            (EnvStage::Delay, amp_env.delay),
+            (EnvStage::Attack, amp_env.attack),
+            (EnvStage::Hold, amp_env.hold),
+            (EnvStage::Decay, amp_env.decay),
+        ] {
+            let len = span(stage).len();
+            let expected = ms_to_samples(ms);
+            assert!(
+                len >= expected && len < expected + PERIOD,
+                "{:?} lasted {} samples",
+                stage,
+                len
+            );
+        }
+        assert!(gains[span(EnvStage::Delay)]
+            .iter()
+            .all(|&level| level == 0.0));
+        // full level through the hold once the gain ramp has caught up
+        let hold = span(EnvStage::Hold);
+        assert!(gains[hold.start + PERIOD..hold.end]
+            .iter()
+            .all(|&level| (level - 1.0).abs() < 1e-4));
+        assert_eq!(voice.stage, EnvStage::Sustain);
+    }
+
+    #[test]
+    fn release_in_the_attack_fades_from_where_it_got_to() {
+        for curve in [EnvCurve::Linear, EnvCurve::Exponential] {
+            let mut voice = voice(Adsr {
+                attack: 100,
+                release: 50,
+                curve,
+                ..Adsr::default()
+            });
+            let attack = levels(&mut voice, ms_to_samples(40));
+            voice.stop();
+            let release = levels(&mut voice, ms_to_samples(60));
+
+            // no step at note-off, the gain carries on smoothly from the last attack sample.
+            // It may ease up for the tick it lags the envelope by, but no higher than one
+            // more tick of attack would have taken it.
+            let reached = *attack.last().unwrap();
+            assert!(
+                reached > 0.3 && reached < 0.99,
+                "{:?} attack got to {}",
+                curve,
+                reached
+            );
+            let mut gains = vec![reached];
+            gains.extend_from_slice(&release);
+            assert!(
+                gains
+                    .windows(2)
+                    .all(|pair| (pair[1] - pair[0]).abs() < 0.01),
+                "{:?} release jumped",
+                curve
+            );
+            let tick_of_attack = PERIOD as f32 / ms_to_samples(100) as f32;
+            assert!(release
+                .iter()
+                .all(|&level| level <= reached + tick_of_attack));
+            assert!(release.last().unwrap().abs() < 1e-6);
+            assert!(voice.is_finished());
+        }
+    }
+
+    #[test]
+    fn linear_and_exponential_curves() {
+        let trajectory = |curve| {
+            let mut voice = voice(Adsr {
+                attack: 100,
+                decay: 100,
+                sustain: 0.2,
+                curve,
+                ..Adsr::default()
+            });
+            levels(&mut voice, ms_to_samples(200))
+        };
+        let linear = trajectory(EnvCurve::Linear);
+        let exponential = trajectory(EnvCurve::Exponential);
+        let mid_attack = ms_to_samples(50);
+        let mid_decay = ms_to_samples(150);
+
+        // linear segments are straight lines, halfway through is halfway there
+        assert!((linear[mid_attack] - 0.5).abs() < 0.03);
+        assert!((linear[mid_decay] - 0.6).abs() < 0.03);
+        assert!(linear[PERIOD..ms_to_samples(90)]
+            .windows(3)
+            .all(|w| ((w[2] - w[1]) - (w[1] - w[0])).abs() < 1e-4));
+        // exponential ones cover most of the distance early and ease into their target, 2.5
+        // time constants in for the halfway point
+        let covered = 1.0 - (-EXP_TIME_CONSTANTS / 2.0).exp();
+        assert!((exponential[mid_attack] - covered).abs() < 0.03);
+        assert!((exponential[mid_decay] - (1.0 - 0.8 * covered)).abs() < 0.03);
+        // both still take the stage time to get to the top
+        for gains in [&linear, &exponential] {
+            let peak = gains.iter().position(|&level| level >= 0.999).unwrap();
+            assert!(peak + PERIOD >= ms_to_samples(100) && peak <= ms_to_samples(100) + 2 * PERIOD);
+        }
+    }
+}
@@ -0,0 +1,351 @@
+use crate::rng::XorShift32;
+use crate::{midi_note_to_freq, sample_rate};
+use lazy_static::lazy_static;
+use std::f32::consts::PI;
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub enum WaveType {
+    Sine,
+    Square,
+    // square with the high part `width` of the cycle, MIN_PULSE_WIDTH..MAX_PULSE_WIDTH
+    Pulse {
+        width: f32,
+    },
+    Saw,
+    Triangle,
+    // decoded PCM at the engine's sample rate, played at its original pitch on the root note
+    Sample {
+        pcm: Arc<Vec<f32>>,
+        root: u8,
+    },
+    // two single-cycle tables of the same length, crossfaded by morph (0 is a, 1 is b)
+    Wavetable {
+        a: Arc<Vec<f32>>,
+        b: Arc<Vec<f32>>,
+        morph: f32,
+    },
+    // noise whose color runs from white (0) to brown (1), darker in between
+    Noise {
+        color: f32,
+    },
+}
+
+pub const DEFAULT_PULSE_WIDTH: f32 = 0.5;
+// narrower pulses thin out to nothing
+pub const MIN_PULSE_WIDTH: f32 = 0.05;
+pub const MAX_PULSE_WIDTH: f32 = 0.95;
+
+// Entries in one cycle of the sine table
+const SINE_TABLE_SIZE: usize = 4096;
+
+lazy_static! {
+    // one sine cycle shared by all voices, with the first entry repeated at the end so
+    // interpolation never has to wrap
+    static ref SINE_TABLE: Vec<f32> = (0..=SINE_TABLE_SIZE)
+        .map(|i| (2.0 * PI * i as f32 / SINE_TABLE_SIZE as f32).sin())
+        .collect();
+}
+
+// Linearly interpolated sine of `phase` (0..1), cheaper than calling sin() every sample
+fn table_sine(phase: f32) -> f32 {
+    let pos = phase.rem_euclid(1.0) * SINE_TABLE_SIZE as f32;
+    let idx = (pos as usize).min(SINE_TABLE_SIZE - 1);
+    let frac = pos - idx as f32;
+    SINE_TABLE[idx] + (SINE_TABLE[idx + 1] - SINE_TABLE[idx]) * frac
+}
+
+// Pole of the noise color filter at color 1, puts the brown noise corner at ~35 Hz
+const NOISE_MAX_POLE: f32 = 0.995;
+
+// RMS of the noise at every color. Filtered noise peaks at four times its RMS and more, so it
+// sits well below the other waves to stay inside full scale.
+const NOISE_RMS: f32 = 0.25;
+
+// Gain per waveform so switching waves keeps the loudness steady. The defaults even out the
+// RMS of the basic waves (sine 0.707, square 1, saw and triangle 0.577) at the level of the
+// quietest, so no wave peaks above full scale. Samples and wavetables play untrimmed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WaveTrims {
+    pub sine: f32,
+    pub square: f32,
+    pub saw: f32,
+    pub triangle: f32,
+    pub sample: f32,
+    pub wavetable: f32,
+    pub noise: f32,
+}
+
+impl Default for WaveTrims {
+    fn default() -> Self {
+        let quietest = 1.0 / 3f32.sqrt();
+        WaveTrims {
+            sine: quietest / std::f32::consts::FRAC_1_SQRT_2,
+            square: quietest,
+            saw: 1.0,
+            triangle: 1.0,
+            sample: 1.0,
+            wavetable: 1.0,
+            // noise is already at NOISE_RMS, lower than the rest so its peaks fit
+            noise: 1.0,
+        }
+    }
+}
+
+impl WaveTrims {
+    pub fn get(&self, typ: &WaveType) -> f32 {
+        match typ {
+            WaveType::Sine => self.sine,
+            // a pulse is a square with its edge moved, it gets the same trim
+            WaveType::Square | WaveType::Pulse { .. } => self.square,
+            WaveType::Saw => self.saw,
+            WaveType::Triangle => self.triangle,
+            WaveType::Sample { .. } => self.sample,
+            WaveType::Wavetable { .. } => self.wavetable,
+            WaveType::Noise { .. } => self.noise,
+        }
+    }
+}
+
+// Parses overrides of the defaults as `<wave>=<gain>` pairs, e.g. `sine=0.8,square=0.6`
+impl FromStr for WaveTrims {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut trims = WaveTrims::default();
+        for pair in s.split(',') {
+            let bad = || format!("bad wave trim {:?}", pair);
+            let (wave, gain) = pair.split_once('=').ok_or_else(bad)?;
+            let gain: f32 = gain.trim().parse().map_err(|_| bad())?;
+            let trim = match wave.trim() {
+                "sine" => &mut trims.sine,
+                "square" => &mut trims.square,
+                "saw" => &mut trims.saw,
+                "triangle" => &mut trims.triangle,
+                "sample" => &mut trims.sample,
+                "wavetable" => &mut trims.wavetable,
+                "noise" => &mut trims.noise,
+                _ => return Err(bad()),
+            };
+            *trim = gain;
+        }
+        Ok(trims)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Wave {
+    pub freq: f32,
+    // position within the current cycle, in [0, 1)
+    phase: f32,
+    // read position into a sample, in samples
+    position: f64,
+    pub(crate) typ: WaveType,
+    // current wavetable morph, the patch setting plus modulation
+    pub(crate) morph: f32,
+    // current pulse width, the patch setting plus modulation
+    pub(crate) pulse_width: f32,
+    state: f32,
+    // white noise source and the one-pole filter that colors it
+    noise: XorShift32,
+    noise_pole: f32,
+    noise_gain: f32,
+}
+
+impl Wave {
+    pub fn new(freq: f32, typ: WaveType) -> Wave {
+        let morph = match typ {
+            WaveType::Wavetable { morph, .. } => morph,
+            _ => 0.0,
+        };
+        let pulse_width = match typ {
+            WaveType::Pulse { width } => width.clamp(MIN_PULSE_WIDTH, MAX_PULSE_WIDTH),
+            _ => DEFAULT_PULSE_WIDTH,
+        };
+        let color = match typ {
+            WaveType::Noise { color } => color,
+            _ => 0.0,
+        };
+        let mut wave = Wave {
+            freq,
+            typ,
+            morph,
+            pulse_width,
+            phase: 0.0,
+            position: 0.0,
+            state: 0.0,
+            noise: XorShift32::new(1),
+            noise_pole: 0.0,
+            noise_gain: 1.0,
+        };
+        wave.set_noise_color(color);
+        wave
+    }
+
+    // Jump to `phase` (0..1) in the cycle
+    pub(crate) fn set_phase(&mut self, phase: f32) {
+        self.phase = phase.rem_euclid(1.0);
+    }
+
+    // Restart the noise generator from `seed`
+    pub(crate) fn seed_noise(&mut self, seed: u32) {
+        self.noise = XorShift32::new(seed);
+    }
+
+    // Tilt the noise spectrum: 0 is white, 1 is brown. A one-pole lowpass does the tilt, so
+    // the colors in between are white noise with its top rolled off at -6 dB/octave, never a
+    // true -3 dB/octave pink. Makeup gain keeps the level at NOISE_RMS throughout.
+    pub(crate) fn set_noise_color(&mut self, color: f32) {
+        let pole = color.clamp(0.0, 1.0) * NOISE_MAX_POLE;
+        self.noise_pole = pole;
+        // the filter takes the RMS down by sqrt((1 - pole) / (1 + pole)), from the white
+        // noise's 1 / sqrt(3)
+        self.noise_gain = NOISE_RMS * 3f32.sqrt() * ((1.0 + pole) / (1.0 - pole)).sqrt();
+    }
+}
+
+// Linearly interpolated read of a single-cycle table at `phase` (0..1)
+fn read_cycle(table: &[f32], phase: f32) -> f32 {
+    let pos = phase * table.len() as f32;
+    let idx = pos as usize % table.len();
+    let next = table[(idx + 1) % table.len()];
+    table[idx] + (next - table[idx]) * pos.fract()
+}
+
+// PolyBLEP residual for a unit step at phase 0, `dt` is the phase increment per sample.
+// Subtracting it from a waveform's jumps rounds them off over two samples, which removes most
+// of the aliasing of the naive saw and square.
+fn poly_blep(phase: f32, dt: f32) -> f32 {
+    if phase < dt {
+        let x = phase / dt;
+        2.0 * x - x * x - 1.0
+    } else if phase > 1.0 - dt {
+        let x = (phase - 1.0) / dt;
+        x * x + 2.0 * x + 1.0
+    } else {
+        0.0
+    }
+}
+
+impl Iterator for Wave {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let dt = self.freq / sample_rate() as f32;
+        // bounded phase keeps full precision and stays continuous however long the note is held
+        self.phase = (self.phase + dt).fract();
+        let phase = self.phase;
+        let dt = dt.abs().min(0.5);
+
+        Some(match &self.typ {
+            WaveType::Sine => table_sine(phase),
+            // band-limited: the naive shapes alias badly on high notes
+            WaveType::Saw => {
+                // the saw wraps from 1 to -1 half way through the cycle
+                let t = (phase + 0.5).fract();
+                self.state = 2.0 * t - 1.0 - poly_blep(t, dt);
+                self.state
+            }
+            WaveType::Square => {
+                let naive = if phase <= 0.5 { 1f32 } else { -1f32 };
+                naive + poly_blep(phase, dt) - poly_blep((phase + 0.5).fract(), dt)
+            }
+            WaveType::Pulse { .. } => {
+                let width = self.pulse_width;
+                // phase since the falling edge at `width`, worked out on the same side of the
+                // edge as the naive pulse so rounding can't put the two out of step
+                let (naive, since_fall) = if phase < width {
+                    (1f32, phase + 1.0 - width)
+                } else {
+                    (-1f32, phase - width)
+                };
+                // the offset of an uneven pulse is taken out so it stays centred as the width
+                // moves
+                naive + poly_blep(phase, dt) - poly_blep(since_fall, dt) - (2.0 * width - 1.0)
+            }
+            WaveType::Triangle => {
+                self.state = 2.0 * (2.0 * (phase - (phase + 0.5).floor())).abs() - 1.0;
+                self.state
+            }
+            WaveType::Sample { pcm, root } => {
+                // the sample is over once the read position runs off the end
+                let idx = self.position as usize;
+                let current = *pcm.get(idx)?;
+                let next = pcm.get(idx + 1).copied().unwrap_or(0.0);
+                let frac = (self.position - idx as f64) as f32;
+                self.position += (self.freq / midi_note_to_freq(*root)) as f64;
+                current + (next - current) * frac
+            }
+            WaveType::Wavetable { a, b, .. } => {
+                let from = read_cycle(a, phase);
+                let to = read_cycle(b, phase);
+                from + (to - from) * self.morph
+            }
+            WaveType::Noise { .. } => {
+                let white = self.noise.next_bipolar();
+                self.state = self.noise_pole * self.state + (1.0 - self.noise_pole) * white;
+                // the level keeps the peaks inside already, the clamp only catches a stray one
+                (self.state * self.noise_gain).clamp(-1.0, 1.0)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phase_stays_continuous_through_a_long_hold() {
+        let rate = sample_rate();
+        let freq = 440.0;
+        let mut wave = Wave::new(freq, WaveType::Sine);
+        // ten minutes in, a sample count times the frequency would long since have run out
+        // of f32 precision
+        for _ in 0..10 * 60 * rate {
+            wave.next();
+            assert!((0.0..1.0).contains(&wave.phase));
+        }
+        let last_second: Vec<f32> = wave.by_ref().take(rate as usize).collect();
+
+        // no steps bigger than the sine's own slope, so no clicks or phase jumps
+        let max_step = 2.0 * PI * freq / rate as f32 * 1.01;
+        assert!(last_second
+            .windows(2)
+            .all(|pair| (pair[1] - pair[0]).abs() <= max_step));
+        // and still in tune, a rising zero crossing per cycle
+        let cycles = last_second
+            .windows(2)
+            .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+            .count();
+        assert!((cycles as f32 - freq).abs() <= 1.0, "{} cycles", cycles);
+    }
+
+    #[test]
+    fn noise_stays_inside_full_scale_at_every_color() {
+        for color in [0.0, 0.5, 0.9, 1.0] {
+            let samples: Vec<f32> = Wave::new(440.0, WaveType::Noise { color })
+                .take(10 * sample_rate() as usize)
+                .collect();
+            // a stray peak may hit the clamp, but no more than a few in a million
+            let clipped = samples.iter().filter(|s| s.abs() >= 1.0).count();
+            assert!(
+                clipped as f32 / (samples.len() as f32) < 1e-4,
+                "{} of {} samples clipped at color {}",
+                clipped,
+                samples.len(),
+                color
+            );
+            // and the level holds steady as the color changes
+            let rms = (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt();
+            assert!(
+                (rms - NOISE_RMS).abs() < 0.1 * NOISE_RMS,
+                "RMS {} at color {}",
+                rms,
+                color
+            );
+        }
+    }
+}